@@ -17,7 +17,9 @@
 extern crate irc;
 extern crate rand;
 
-use irc::conn::{Conn, Line, Event, IRCCmd, IRCCode, IRCAction};
+use irc::conn::{Conn, Line, Event, IRCCmd};
+use irc::conn::message::{Message, UnknownCommand, Reply, Join, ExtendedJoin, Privmsg, Notice,
+                         Action};
 
 use std::str;
 use rand::Rng;
@@ -37,67 +39,59 @@ fn handler(conn: &mut Conn, event: Event) {
     match event {
         irc::conn::Connected => println!("Connected"),
         irc::conn::Disconnected => println!("Disconnected"),
+        irc::conn::Lag(_) => (),
+        irc::conn::Reconnecting(attempt, delay) => {
+            println!("Reconnecting in {}ms (attempt {})", delay, attempt)
+        }
+        irc::conn::UnparseableLine(line, err) => {
+            println!("ERROR: Unparseable line ({}): {}", err, str::from_utf8_lossy(line.as_slice()))
+        }
         irc::conn::LineReceived(line) => {
-            match line {
-                Line{command: IRCCode(1), ..} => {
+            let msg = match Message::from_line(&line) {
+                Ok(msg) => msg,
+                Err(UnknownCommand) => return,
+                Err(err) => {
+                    println!("ERROR: Invalid line received ({}): {}", err, line_desc(&line));
+                    return;
+                }
+            };
+            let prefix = line.prefix();
+            let src = match prefix {
+                Some(ref prefix) => prefix.name(),
+                None => match msg {
+                    Join(..) | ExtendedJoin(..) | Privmsg(..) | Notice(..) | Action(..) => {
+                        println!("ERROR: Line has no prefix: {}", line_desc(&line));
+                        return;
+                    }
+                    _ => bytes!("")
+                }
+            };
+            match msg {
+                Reply(1, _) => {
                     println!("Logged in");
                     // we've logged in
-                    conn.join(bytes!("##rustirclib"), [])
+                    conn.join(bytes!("##rustirclib"), []).unwrap()
                 }
-                Line{command: IRCCmd(cmd), args, prefix: prefix } => match cmd.as_slice() {
-                    "JOIN" if prefix.is_some() => {
-                        let prefix = prefix.unwrap();
-                        if prefix.nick() != conn.me().nick() {
-                            return;
-                        }
-                        if args.is_empty() {
-                            let line = Line{command: IRCCmd("JOIN".into_maybe_owned()),
-                                            args: args, prefix: Some(prefix)};
-                            println!("ERROR: Invalid JOIN message received: {}", line_desc(&line));
-                            return;
-                        }
-                        let chan = args.move_iter().next().unwrap();
-                        conn.privmsg(chan.as_slice(), bytes!("Hello"));
-                        let chan = str::from_utf8_lossy(chan.as_slice());
-                        println!("JOINED: {}", chan);
-                    }
-                    "PRIVMSG" | "NOTICE" => {
-                        let (src, dst, msg) = match prefix {
-                            Some(_) if args.len() == 2 => {
-                                let mut args = args;
-                                let (dst, msg) = (args.swap_remove(0).unwrap(),
-                                                  args.move_iter().next().unwrap());
-                                (prefix.as_ref().unwrap().nick(), dst, msg)
-                            }
-                            _ => {
-                                print!("ERROR: Unexpected {} line: ", cmd);
-                                let line = Line{command: IRCCmd(cmd), args: args, prefix: prefix};
-                                println!("{}", line_desc(&line));
-                                return;
-                            }
-                        };
-                        let dsts = str::from_utf8_lossy(dst.as_slice());
-                        let srcs = str::from_utf8_lossy(src.as_slice());
-                        let msgs = str::from_utf8_lossy(msg.as_slice());
-                        println!("<-- {}({}) {}: {}", cmd, dsts, srcs, msgs);
-                        handle_privmsg(conn, msg.as_slice(), src, dst.as_slice())
+                Join(chans, _) => {
+                    for chan in chans.iter() {
+                        handle_join(conn, src, chan.as_slice());
                     }
-                    _ => ()
-                },
-                Line{command: IRCAction(dst), args, prefix } => {
-                    let (src, msg) = match prefix {
-                        Some(_) if args.len() == 1 => {
-                            let msg = args.move_iter().next().unwrap();
-                            (prefix.as_ref().unwrap().nick(), msg)
-                        }
-                        _ => {
-                            let line = Line{command: IRCAction(dst), args: args, prefix: prefix};
-                            println!("ERROR: Unexpected ACTION line: {}", line_desc(&line));
-                            return;
-                        }
+                }
+                ExtendedJoin(chan, _, _) => handle_join(conn, src, chan.as_slice()),
+                Privmsg(ref dst, ref msg) | Notice(ref dst, ref msg) => {
+                    let cmd = match *line.command() {
+                        IRCCmd(ref cmd) => cmd.as_slice(),
+                        _ => "PRIVMSG"
                     };
+                    let dsts = str::from_utf8_lossy(dst.as_slice());
+                    let srcs = str::from_utf8_lossy(src);
+                    let msgs = str::from_utf8_lossy(msg.as_slice());
+                    println!("<-- {}({}) {}: {}", cmd, dsts, srcs, msgs);
+                    handle_privmsg(conn, msg.as_slice(), src, dst.as_slice())
+                }
+                Action(dst, msg) => {
                     let dst = str::from_utf8_lossy(dst.as_slice());
-                    let src = str::from_utf8_lossy(src.as_slice());
+                    let src = str::from_utf8_lossy(src);
                     let msg = str::from_utf8_lossy(msg.as_slice());
                     println!("<-- PRIVMSG({}) {} {}", dst, src, msg);
                 }
@@ -107,6 +101,15 @@ fn handler(conn: &mut Conn, event: Event) {
     }
 }
 
+fn handle_join(conn: &mut Conn, src: &[u8], chan: &[u8]) {
+    if src != conn.me().nick() {
+        return;
+    }
+    conn.privmsg(chan, bytes!("Hello")).unwrap();
+    let chan = str::from_utf8_lossy(chan);
+    println!("JOINED: {}", chan);
+}
+
 fn handle_privmsg(conn: &mut Conn, msg: &[u8], src: &[u8], dst: &[u8]) {
     enum MsgType<'a> {
         DirectedMessage,
@@ -134,7 +137,13 @@ fn handle_privmsg(conn: &mut Conn, msg: &[u8], src: &[u8], dst: &[u8]) {
         DirectedMessage => {
             let reply = if dst == conn.me().nick() { src } else { dst };
             let msg = Vec::from_slice(src).append(bytes!(": Hello"));
-            conn.privmsg(reply, msg.as_slice());
+            match conn.privmsg(reply, msg.as_slice()) {
+                Ok(()) => (),
+                Err(err) => {
+                    println!("ERROR: Can't reply: {}", err);
+                    return;
+                }
+            }
             let src = str::from_utf8(conn.me().nick()).unwrap_or("(invalid utf8)");
             let reply = str::from_utf8(reply).unwrap_or("(invalid utf8)");
             let msg = String::from_utf8(msg).unwrap_or_else(|_| "(invalid utf8)".to_strbuf());
@@ -142,7 +151,7 @@ fn handle_privmsg(conn: &mut Conn, msg: &[u8], src: &[u8], dst: &[u8]) {
         }
         CommandMessage(cmd) if cmd == bytes!("quit") => {
             println!("Quitting...");
-            conn.quit([]);
+            conn.quit([]).unwrap();
         }
         _ => ()
     }
@@ -0,0 +1,400 @@
+//! Case-insensitive comparison of nicknames and channel names
+
+use std::mem;
+use collections::HashMap;
+use collections::hashmap;
+
+/// A rule for deciding which nicknames and channel names are equivalent,
+/// as advertised by the server's CASEMAPPING token.
+#[deriving(Eq,Clone,Show)]
+pub enum CaseMapping {
+    /// Only A-Z and a-z are equivalent
+    Ascii,
+    /// As Ascii, and `[]\^` are the uppercase forms of `{}|~`.
+    /// This is the default when the server doesn't say otherwise.
+    Rfc1459,
+    /// As Rfc1459, but without `^` and `~`
+    StrictRfc1459
+}
+
+impl CaseMapping {
+    /// Returns the CaseMapping with the given CASEMAPPING name, if it's known
+    pub fn from_name(name: &str) -> Option<CaseMapping> {
+        match name.to_ascii_lower().as_slice() {
+            "ascii" => Some(Ascii),
+            "rfc1459" => Some(Rfc1459),
+            "strict-rfc1459" => Some(StrictRfc1459),
+            _ => None
+        }
+    }
+
+    /// Returns the CASEMAPPING name
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Ascii => "ascii",
+            Rfc1459 => "rfc1459",
+            StrictRfc1459 => "strict-rfc1459"
+        }
+    }
+
+    /// Returns the lowercase form of a byte
+    pub fn to_lower(&self, b: u8) -> u8 {
+        match b as char {
+            'A'..'Z' => b + 32,
+            '[' | ']' | '\\' if *self != Ascii => b + 32,
+            '^' if *self == Rfc1459 => b + 32,
+            _ => b
+        }
+    }
+
+    /// Returns the lowercase form of a name
+    pub fn fold(&self, name: &[u8]) -> Vec<u8> {
+        name.iter().map(|&b| self.to_lower(b)).collect()
+    }
+
+    /// Returns `true` if the two names are equivalent
+    pub fn equiv(&self, a: &[u8], b: &[u8]) -> bool {
+        a.len() == b.len() && a.iter().zip(b.iter()).all(|(&a, &b)| {
+            self.to_lower(a) == self.to_lower(b)
+        })
+    }
+
+    /// Returns `true` if `name` matches the wildcard `mask`.
+    ///
+    /// In the mask, `*` matches any number of characters and `?` matches exactly
+    /// one. A backslash makes the following character match literally, e.g. `\*`.
+    pub fn matches(&self, mask: &[u8], name: &[u8]) -> bool {
+        let mut pattern = Vec::with_capacity(mask.len());
+        let mut iter = mask.iter();
+        loop {
+            match iter.next() {
+                None => break,
+                Some(&b) if b == '*' as u8 => pattern.push(AnyRun),
+                Some(&b) if b == '?' as u8 => pattern.push(AnyOne),
+                Some(&b) if b == '\\' as u8 => match iter.next() {
+                    Some(&b) => pattern.push(Byte(self.to_lower(b))),
+                    None => pattern.push(Byte(b))
+                },
+                Some(&b) => pattern.push(Byte(self.to_lower(b)))
+            }
+        }
+
+        // greedy match, backtracking to the most recent * on a mismatch
+        let (mut p, mut n) = (0u, 0u);
+        let mut star: Option<(uint, uint)> = None;
+        while n < name.len() {
+            let matched = match pattern.as_slice().get(p) {
+                Some(&AnyRun) => {
+                    star = Some((p, n));
+                    p += 1;
+                    continue;
+                }
+                Some(&AnyOne) => true,
+                Some(&Byte(b)) => b == self.to_lower(name[n]),
+                None => false
+            };
+            if matched {
+                p += 1;
+                n += 1;
+            } else {
+                match star {
+                    Some((sp, sn)) => {
+                        star = Some((sp, sn + 1));
+                        p = sp + 1;
+                        n = sn + 1;
+                    }
+                    None => return false
+                }
+            }
+        }
+        pattern.slice_from(p).iter().all(|t| *t == AnyRun)
+    }
+}
+
+/// A piece of a wildcard mask
+#[deriving(Eq)]
+enum MaskToken {
+    AnyRun,
+    AnyOne,
+    Byte(u8)
+}
+
+/// A map keyed by nickname or channel name, where equivalent names are the same key.
+/// Keys keep the spelling they were most recently inserted with.
+#[deriving(Clone)]
+pub struct CaseMap<V> {
+    casemap: CaseMapping,
+    map: HashMap<Vec<u8>, Entry<V>>
+}
+
+#[deriving(Clone)]
+struct Entry<V> {
+    name: Vec<u8>,
+    value: V
+}
+
+impl<V> CaseMap<V> {
+    /// Returns an empty CaseMap using the given casemapping
+    pub fn new(casemap: CaseMapping) -> CaseMap<V> {
+        CaseMap { casemap: casemap, map: HashMap::new() }
+    }
+
+    /// Returns the casemapping in use
+    pub fn casemapping(&self) -> CaseMapping {
+        self.casemap
+    }
+
+    /// Returns the number of entries
+    pub fn len(&self) -> uint {
+        self.map.len()
+    }
+
+    /// Returns `true` if the map is empty
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns the value for `key`, if any
+    pub fn find<'a>(&'a self, key: &[u8]) -> Option<&'a V> {
+        self.map.find(&self.casemap.fold(key)).map(|e| &e.value)
+    }
+
+    /// Returns a mutable reference to the value for `key`, if any
+    pub fn find_mut<'a>(&'a mut self, key: &[u8]) -> Option<&'a mut V> {
+        let key = self.casemap.fold(key);
+        self.map.find_mut(&key).map(|e| &mut e.value)
+    }
+
+    /// Returns `true` if the map has a value for `key`
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.map.contains_key(&self.casemap.fold(key))
+    }
+
+    /// Inserts a value, replacing any existing value for an equivalent key.
+    /// Returns `true` if the key is new.
+    pub fn insert(&mut self, key: &[u8], value: V) -> bool {
+        let entry = Entry { name: Vec::from_slice(key), value: value };
+        self.map.insert(self.casemap.fold(key), entry)
+    }
+
+    /// Removes and returns the value for `key`, if any
+    pub fn pop(&mut self, key: &[u8]) -> Option<V> {
+        self.map.pop(&self.casemap.fold(key)).map(|e| e.value)
+    }
+
+    /// Moves the value for `old` to `new`, e.g. after a nick change.
+    /// Any existing value for `new` is replaced. Returns `true` if `old` was present.
+    pub fn rename(&mut self, old: &[u8], new: &[u8]) -> bool {
+        match self.pop(old) {
+            None => false,
+            Some(v) => {
+                self.insert(new, v);
+                true
+            }
+        }
+    }
+
+    /// Removes every entry
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// Keeps only the entries for which `f` returns `true`.
+    /// `f` is given each name and may update its value.
+    pub fn retain(&mut self, f: |&[u8], &mut V| -> bool) {
+        let mut removed = Vec::new();
+        for (key, entry) in self.map.mut_iter() {
+            if !f(entry.name.as_slice(), &mut entry.value) {
+                removed.push(key.clone());
+            }
+        }
+        for key in removed.iter() {
+            self.map.remove(key);
+        }
+    }
+
+    /// Changes the casemapping, merging any keys that become equivalent
+    pub fn set_casemapping(&mut self, casemap: CaseMapping) {
+        if casemap == self.casemap {
+            return;
+        }
+        self.casemap = casemap;
+        let old = mem::replace(&mut self.map, HashMap::new());
+        for (_, entry) in old.move_iter() {
+            self.map.insert(casemap.fold(entry.name.as_slice()), entry);
+        }
+    }
+
+    /// Returns an iterator over the entries, as the names they were inserted with
+    pub fn iter<'a>(&'a self) -> Entries<'a, V> {
+        Entries { iter: self.map.iter() }
+    }
+}
+
+/// An iterator over the entries of a CaseMap
+pub struct Entries<'a, V> {
+    iter: hashmap::Entries<'a, Vec<u8>, Entry<V>>
+}
+
+impl<'a, V> Iterator<(&'a [u8], &'a V)> for Entries<'a, V> {
+    fn next(&mut self) -> Option<(&'a [u8], &'a V)> {
+        self.iter.next().map(|(_, e)| (e.name.as_slice(), &e.value))
+    }
+}
+
+/// A set of nicknames or channel names, where equivalent names are the same entry
+#[deriving(Clone)]
+pub struct CaseSet {
+    map: CaseMap<()>
+}
+
+impl CaseSet {
+    /// Returns an empty CaseSet using the given casemapping
+    pub fn new(casemap: CaseMapping) -> CaseSet {
+        CaseSet { map: CaseMap::new(casemap) }
+    }
+
+    /// Returns the number of names
+    pub fn len(&self) -> uint {
+        self.map.len()
+    }
+
+    /// Returns `true` if the set is empty
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    /// Returns `true` if the set contains `name`
+    pub fn contains(&self, name: &[u8]) -> bool {
+        self.map.contains_key(name)
+    }
+
+    /// Adds a name. Returns `true` if it wasn't already present.
+    pub fn insert(&mut self, name: &[u8]) -> bool {
+        self.map.insert(name, ())
+    }
+
+    /// Removes a name. Returns `true` if it was present.
+    pub fn remove(&mut self, name: &[u8]) -> bool {
+        self.map.pop(name).is_some()
+    }
+
+    /// Removes every name
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// Changes the casemapping, merging any names that become equivalent
+    pub fn set_casemapping(&mut self, casemap: CaseMapping) {
+        self.map.set_casemapping(casemap);
+    }
+
+    /// Returns an iterator over the names, as they were inserted
+    pub fn iter<'a>(&'a self) -> Names<'a> {
+        Names { iter: self.map.iter() }
+    }
+}
+
+/// An iterator over the names in a CaseSet
+pub struct Names<'a> {
+    iter: Entries<'a, ()>
+}
+
+impl<'a> Iterator<&'a [u8]> for Names<'a> {
+    fn next(&mut self) -> Option<&'a [u8]> {
+        self.iter.next().map(|(name, _)| name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CaseMapping, CaseMap, CaseSet, Ascii, Rfc1459, StrictRfc1459};
+
+    macro_rules! b(
+        ($args:tt) => (
+            { static b: &'static [u8] = bytes!($args); b }
+        )
+    )
+
+    #[test]
+    fn test_casemapping() {
+        assert_eq!(CaseMapping::from_name("RFC1459"), Some(Rfc1459));
+        assert_eq!(CaseMapping::from_name("strict-rfc1459"), Some(StrictRfc1459));
+        assert_eq!(CaseMapping::from_name("rfc7613"), None);
+
+        assert_eq!(Ascii.fold(b!("Foo[]\\^")).as_slice(), b!("foo[]\\^"));
+        assert_eq!(Rfc1459.fold(b!("Foo[]\\^")).as_slice(), b!("foo{}|~"));
+        assert_eq!(StrictRfc1459.fold(b!("Foo[]\\^")).as_slice(), b!("foo{}|^"));
+
+        assert!(Rfc1459.equiv(b!("[Bob]"), b!("{bob}")));
+        assert!(!Ascii.equiv(b!("[Bob]"), b!("{bob}")));
+        assert!(!Rfc1459.equiv(b!("bob"), b!("bobby")));
+    }
+
+    #[test]
+    fn test_matches() {
+        assert!(Rfc1459.matches(b!("*"), b!("")));
+        assert!(Rfc1459.matches(b!("*"), b!("nick!user@host")));
+        assert!(Rfc1459.matches(b!("*!*@*.example.com"), b!("bob!b@irc.Example.COM")));
+        assert!(!Rfc1459.matches(b!("*!*@*.example.com"), b!("bob!b@example.com")));
+        assert!(Rfc1459.matches(b!("b?b!*@*"), b!("BOB!b@host")));
+        assert!(!Rfc1459.matches(b!("b?b!*@*"), b!("bob!b")));
+        assert!(Rfc1459.matches(b!("[bob]*"), b!("{BOB}!b@host")));
+        assert!(!Ascii.matches(b!("[bob]*"), b!("{BOB}!b@host")));
+        assert!(Rfc1459.matches(b!("*a*b*c"), b!("xxaxxbxxbxc")));
+        assert!(!Rfc1459.matches(b!("*a*b*c"), b!("xxaxxbxxbx")));
+        assert!(Rfc1459.matches(b!("a**"), b!("a")));
+        assert!(!Rfc1459.matches(b!("a?"), b!("a")));
+
+        // escaped wildcards only match themselves
+        assert!(Rfc1459.matches(b!("what\\?"), b!("what?")));
+        assert!(!Rfc1459.matches(b!("what\\?"), b!("whats")));
+        assert!(Rfc1459.matches(b!("\\**"), b!("*star")));
+        assert!(!Rfc1459.matches(b!("\\**"), b!("star")));
+        assert!(Rfc1459.matches(b!("back\\\\slash"), b!("back\\slash")));
+    }
+
+    #[test]
+    fn test_casemap() {
+        let mut map = CaseMap::new(Rfc1459);
+        assert!(map.insert(b!("Alice"), 1));
+        assert!(map.insert(b!("[Bob]"), 2));
+        assert!(!map.insert(b!("ALICE"), 3));
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.find(b!("alice")), Some(&3));
+        assert_eq!(map.find(b!("{bob}")), Some(&2));
+
+        assert!(map.rename(b!("{BOB}"), b!("Robert")));
+        assert!(!map.contains_key(b!("[bob]")));
+        *map.find_mut(b!("robert")).unwrap() += 1;
+        assert_eq!(map.pop(b!("ROBERT")), Some(3));
+        assert_eq!(map.pop(b!("robert")), None);
+
+        // the inserted spelling is preserved
+        let entries: Vec<(&[u8], &int)> = map.iter().collect();
+        assert_eq!(entries, vec!((b!("ALICE"), &3)));
+
+        map.insert(b!("carol"), 5);
+        map.retain(|name, v| {
+            *v += 1;
+            name != b!("ALICE")
+        });
+        assert_eq!(map.len(), 1);
+        assert_eq!(map.find(b!("Carol")), Some(&6));
+
+        map.set_casemapping(Ascii);
+        map.insert(b!("{bob}"), 4);
+        assert!(!map.contains_key(b!("[bob]")));
+    }
+
+    #[test]
+    fn test_caseset() {
+        let mut set = CaseSet::new(Rfc1459);
+        assert!(set.insert(b!("#Rust")));
+        assert!(!set.insert(b!("#rust")));
+        assert!(set.contains(b!("#RUST")));
+        assert_eq!(set.iter().collect::<Vec<&[u8]>>(), vec!(b!("#Rust")));
+        assert!(set.remove(b!("#rUST")));
+        assert!(set.is_empty());
+    }
+}
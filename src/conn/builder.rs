@@ -0,0 +1,308 @@
+//! Validated construction of outgoing lines
+
+use std::{char, cmp, uint};
+use conn::{Command, IRCCmd, IRCCode, IRCAction, IRCCTCP, IRCCTCPReply};
+
+/// Errors from building a line
+#[deriving(Eq,Clone,Show)]
+pub enum BuildError {
+    /// The command name is empty or has a character that isn't allowed.
+    /// IRC commands must be alphanumeric and numerics must be at most 3 digits.
+    /// CTCP commands can't contain spaces, \x01, CR, LF or NUL.
+    InvalidCommandName,
+    /// The CTCP destination is empty, contains a space, or starts with ':'
+    InvalidTarget,
+    /// The param at the given index contains CR, LF or NUL, or \x01 for a CTCP param
+    IllegalByte(uint),
+    /// The param at the given index isn't the last, or was added with `middle()`,
+    /// but is empty, contains a space, or starts with ':'
+    InvalidMiddleParam(uint),
+    /// The command, destination and relayed prefix leave no room for any text.
+    /// Only returned by `Conn::send_text()`.
+    TooLong
+}
+
+/// A builder for lines to send to the server.
+///
+/// Params are given without any ':' prefix. The final param gets one automatically
+/// when it needs it. Any input that can't be encoded on a single line is rejected
+/// by `build()`, so user-supplied text can't inject extra commands.
+///
+/// For IRCAction, IRCCTCP and IRCCTCPReply commands, the params are joined with spaces
+/// to form the CTCP message.
+#[deriving(Clone)]
+pub struct LineBuilder {
+    command: Command,
+    args: Vec<Vec<u8>>,
+    // indices of params that can't be sent as a trailing param
+    middle: Vec<uint>
+}
+
+impl LineBuilder {
+    /// Starts a line with the given command
+    pub fn new(command: Command) -> LineBuilder {
+        LineBuilder {
+            command: command,
+            args: Vec::new(),
+            middle: Vec::new()
+        }
+    }
+
+    /// Starts a line with a named command, such as "PRIVMSG"
+    pub fn cmd(name: &'static str) -> LineBuilder {
+        LineBuilder::new(IRCCmd(name.into_maybe_owned()))
+    }
+
+    /// Adds a param
+    pub fn arg(mut self, arg: &[u8]) -> LineBuilder {
+        self.args.push(Vec::from_slice(arg));
+        self
+    }
+
+    /// Adds a param that must be valid as a middle param even if it's the last one,
+    /// such as a channel or nick. It can't be empty, contain a space or start with ':'.
+    pub fn middle(mut self, arg: &[u8]) -> LineBuilder {
+        self.middle.push(self.args.len());
+        self.args.push(Vec::from_slice(arg));
+        self
+    }
+
+    /// Adds several params
+    pub fn args<V: Vector<u8>>(mut self, args: &[V]) -> LineBuilder {
+        for arg in args.iter() {
+            self.args.push(Vec::from_slice(arg.as_slice()));
+        }
+        self
+    }
+
+    /// Returns the command
+    pub fn command<'a>(&'a self) -> &'a Command {
+        &self.command
+    }
+
+    /// Returns the params added so far
+    pub fn params<'a>(&'a self) -> &'a [Vec<u8>] {
+        self.args.as_slice()
+    }
+
+    /// Validates the line and returns its raw form, without the trailing CRLF
+    pub fn build(&self) -> Result<Vec<u8>, BuildError> {
+        let is_ctcp = self.command.is_ctcp();
+        for (i, arg) in self.args.iter().enumerate() {
+            if arg.iter().any(|&b| is_illegal(b) || (is_ctcp && b == 0x1)) {
+                return Err(IllegalByte(i));
+            }
+        }
+        let mut res = Vec::new();
+        match self.command {
+            IRCCmd(ref cmd) => {
+                let cmd = cmd.as_slice().as_bytes();
+                let valid = |&b: &u8| b < 0x80 && char::is_alphanumeric(b as char);
+                if cmd.is_empty() || !cmd.iter().all(valid) {
+                    return Err(InvalidCommandName);
+                }
+                res.push_all(cmd);
+            }
+            IRCCode(code) => {
+                if code > 999 {
+                    return Err(InvalidCommandName);
+                }
+                uint::to_str_bytes(code, 10, |v| {
+                    for _ in range(v.len(), 3) {
+                        res.push('0' as u8);
+                    }
+                    res.push_all(v);
+                });
+            }
+            IRCAction(ref dst) => {
+                try!(ctcp_start(&mut res, bytes!("PRIVMSG"), dst.as_slice(), bytes!("ACTION")));
+            }
+            IRCCTCP(ref cmd, ref dst) => {
+                try!(ctcp_start(&mut res, bytes!("PRIVMSG"), dst.as_slice(), cmd.as_slice()));
+            }
+            IRCCTCPReply(ref cmd, ref dst) => {
+                try!(ctcp_start(&mut res, bytes!("NOTICE"), dst.as_slice(), cmd.as_slice()));
+            }
+        }
+        if is_ctcp {
+            for arg in self.args.iter() {
+                res.push(' ' as u8);
+                res.push_all(arg.as_slice());
+            }
+            res.push(0x1);
+            return Ok(res);
+        }
+        for (i, arg) in self.args.iter().enumerate() {
+            res.push(' ' as u8);
+            if i + 1 < self.args.len() || self.middle.contains(&i) {
+                if !is_middle(arg.as_slice()) {
+                    return Err(InvalidMiddleParam(i));
+                }
+            } else if !is_middle(arg.as_slice()) {
+                res.push(':' as u8);
+            }
+            res.push_all(arg.as_slice());
+        }
+        Ok(res)
+    }
+}
+
+/// Splits text into chunks of at most `max` bytes, for sending as several messages.
+///
+/// Chunks end at a space where possible, and that space is dropped. Otherwise they
+/// end on a UTF-8 character boundary. Empty text gives a single empty chunk.
+pub fn split_text<'a>(mut text: &'a [u8], max: uint) -> Vec<&'a [u8]> {
+    let max = cmp::max(max, 1);
+    let is_continuation = |b: u8| b & 0xC0 == 0x80;
+    let mut chunks = Vec::new();
+    while text.len() > max {
+        // back up to the start of the character, unless this isn't UTF-8
+        let mut end = max;
+        while end > 0 && max - end < 3 && is_continuation(text[end]) {
+            end -= 1;
+        }
+        if end == 0 || is_continuation(text[end]) {
+            end = max;
+        }
+        if text[end] == ' ' as u8 {
+            chunks.push(text.slice_to(end));
+            text = text.slice_from(end+1);
+            continue;
+        }
+        match text.slice_to(end).rposition_elem(&(' ' as u8)) {
+            Some(idx) if idx > 0 => {
+                chunks.push(text.slice_to(idx));
+                text = text.slice_from(idx+1);
+            }
+            _ => {
+                chunks.push(text.slice_to(end));
+                text = text.slice_from(end);
+            }
+        }
+    }
+    if !text.is_empty() || chunks.is_empty() {
+        chunks.push(text);
+    }
+    chunks
+}
+
+fn is_illegal(b: u8) -> bool {
+    b == '\r' as u8 || b == '\n' as u8 || b == 0
+}
+
+/// Returns true if the param can be sent without a ':' prefix
+fn is_middle(arg: &[u8]) -> bool {
+    !arg.is_empty() && arg[0] != ':' as u8 && !arg.contains(&(' ' as u8))
+}
+
+fn ctcp_start(res: &mut Vec<u8>, cmd: &[u8], dst: &[u8], ctcp: &[u8]) -> Result<(), BuildError> {
+    if !is_middle(dst) || dst.iter().any(|&b| is_illegal(b)) {
+        return Err(InvalidTarget);
+    }
+    if ctcp.is_empty() || ctcp.iter().any(|&b| is_illegal(b) || b == ' ' as u8 || b == 0x1) {
+        return Err(InvalidCommandName);
+    }
+    res.push_all(cmd);
+    res.push(' ' as u8);
+    res.push_all(dst);
+    res.push_all(bytes!(" :\x01"));
+    res.push_all(ctcp);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LineBuilder, InvalidCommandName, InvalidTarget, IllegalByte, InvalidMiddleParam,
+                split_text};
+    use conn::{IRCCode, IRCAction, IRCCTCP};
+
+    macro_rules! b(
+        ($args:tt) => (
+            { static b: &'static [u8] = bytes!($args); b }
+        )
+    )
+
+    #[test]
+    fn test_build() {
+        let line = LineBuilder::cmd("PRIVMSG").arg(b!("#rust")).arg(b!("hello there"));
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("PRIVMSG #rust :hello there"))));
+
+        let line = LineBuilder::cmd("PRIVMSG").args([b!("#rust"), b!("hi")]);
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("PRIVMSG #rust hi"))));
+
+        // the trailing param gets a colon whenever it needs one
+        let line = LineBuilder::cmd("TOPIC").arg(b!("#rust")).arg(b!(""));
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("TOPIC #rust :"))));
+        let line = LineBuilder::cmd("PRIVMSG").arg(b!("#rust")).arg(b!(":)"));
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("PRIVMSG #rust ::)"))));
+
+        let line = LineBuilder::cmd("QUIT");
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("QUIT"))));
+
+        let line = LineBuilder::new(IRCCode(5)).arg(b!("me"));
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("005 me"))));
+
+        let line = LineBuilder::new(IRCAction(Vec::from_slice(b!("#rust")))).arg(b!("waves"));
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("PRIVMSG #rust :\x01ACTION waves\x01"))));
+
+        let line = LineBuilder::new(IRCCTCP(Vec::from_slice(b!("PING")),
+                                            Vec::from_slice(b!("bob"))))
+                               .args([b!("123"), b!("456")]);
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("PRIVMSG bob :\x01PING 123 456\x01"))));
+    }
+
+    #[test]
+    fn test_errors() {
+        let line = LineBuilder::cmd("PRIVMSG").arg(b!("#rust")).arg(b!("hi\r\nQUIT :pwned"));
+        assert_eq!(line.build(), Err(IllegalByte(1)));
+        let line = LineBuilder::cmd("PRIVMSG").arg(b!("#rust")).arg(b!("nul\x00"));
+        assert_eq!(line.build(), Err(IllegalByte(1)));
+        let line = LineBuilder::cmd("PRIVMSG").arg(b!("#rust\n")).arg(b!("hi"));
+        assert_eq!(line.build(), Err(IllegalByte(0)));
+
+        let line = LineBuilder::cmd("PRIVMSG").arg(b!("#rust #irc")).arg(b!("hi"));
+        assert_eq!(line.build(), Err(InvalidMiddleParam(0)));
+        let line = LineBuilder::cmd("KICK").arg(b!("#rust")).arg(b!(":bob")).arg(b!("bye"));
+        assert_eq!(line.build(), Err(InvalidMiddleParam(1)));
+        let line = LineBuilder::cmd("PRIVMSG").arg(b!("")).arg(b!("hi"));
+        assert_eq!(line.build(), Err(InvalidMiddleParam(0)));
+        // params added with middle() are never escaped, even when last
+        let line = LineBuilder::cmd("NICK").middle(b!("bad nick"));
+        assert_eq!(line.build(), Err(InvalidMiddleParam(0)));
+        let line = LineBuilder::cmd("JOIN").middle(b!("#a")).middle(b!(":key"));
+        assert_eq!(line.build(), Err(InvalidMiddleParam(1)));
+        let line = LineBuilder::cmd("PART").middle(b!(""));
+        assert_eq!(line.build(), Err(InvalidMiddleParam(0)));
+        let line = LineBuilder::cmd("JOIN").middle(b!("#a,#b")).middle(b!("k1,k2"));
+        assert_eq!(line.build(), Ok(Vec::from_slice(b!("JOIN #a,#b k1,k2"))));
+
+        assert_eq!(LineBuilder::cmd("").build(), Err(InvalidCommandName));
+        assert_eq!(LineBuilder::cmd("PRIV MSG").build(), Err(InvalidCommandName));
+        assert_eq!(LineBuilder::new(IRCCode(1000)).build(), Err(InvalidCommandName));
+
+        let line = LineBuilder::new(IRCAction(Vec::from_slice(b!("#a #b")))).arg(b!("waves"));
+        assert_eq!(line.build(), Err(InvalidTarget));
+        let line = LineBuilder::new(IRCAction(Vec::from_slice(b!("#rust")))).arg(b!("\x01x"));
+        assert_eq!(line.build(), Err(IllegalByte(0)));
+    }
+
+    #[test]
+    fn test_split_text() {
+        assert_eq!(split_text(b!("hello world"), 20), vec!(b!("hello world")));
+        assert_eq!(split_text(b!(""), 20), vec!(b!("")));
+
+        // word boundaries
+        assert_eq!(split_text(b!("the quick brown fox"), 10),
+                   vec!(b!("the quick"), b!("brown fox")));
+        assert_eq!(split_text(b!("the quick brown fox"), 9),
+                   vec!(b!("the quick"), b!("brown fox")));
+        assert_eq!(split_text(b!("aaaa bbbb "), 4), vec!(b!("aaaa"), b!("bbbb")));
+
+        // no spaces, so split anywhere
+        assert_eq!(split_text(b!("abcdefgh"), 3), vec!(b!("abc"), b!("def"), b!("gh")));
+
+        // but never in the middle of a character
+        assert_eq!(split_text(b!("héllo"), 2), vec!(b!("h"), b!("é"), b!("ll"), b!("o")));
+        assert_eq!(split_text(b!("日本語"), 4), vec!(b!("日"), b!("本"), b!("語")));
+    }
+}
@@ -1,22 +1,61 @@
 //! Built-in IRC message handlers
 
 use conn::{IRCCode, IRCCmd, Conn, Line};
+use conn::numeric;
 
 pub fn handle_line(conn: &mut Conn, line: &Line) {
     if !conn.logged_in {
-        match line.command {
-            IRCCode(001) => handshake::RPL_WELCOME(conn, line),
-            IRCCode(433) => handshake::ERR_NICKNAMEINUSE(conn, line),
-            IRCCode(432) => handshake::ERR_ERRONEUSNICKNAME(conn, line),
-            IRCCode(436) => handshake::ERR_NICKCOLLISION(conn, line),
-            IRCCode(437) => handshake::ERR_UNAVAILRESOURCE(conn, line),
+        match *line.command() {
+            IRCCode(numeric::RPL_WELCOME) => handshake::RPL_WELCOME(conn, line),
+            IRCCode(numeric::RPL_BOUNCE) => handshake::RPL_BOUNCE(conn, line),
+            IRCCode(numeric::ERR_NICKNAMEINUSE) => handshake::ERR_NICKNAMEINUSE(conn, line),
+            IRCCode(numeric::ERR_ERRONEUSNICKNAME) => handshake::ERR_ERRONEUSNICKNAME(conn, line),
+            IRCCode(numeric::ERR_NICKCOLLISION) => handshake::ERR_NICKCOLLISION(conn, line),
+            IRCCode(numeric::ERR_UNAVAILRESOURCE) => handshake::ERR_UNAVAILRESOURCE(conn, line),
             IRCCmd(ref s) if "PING" == s.as_slice() => normal::PING(conn, line),
+            IRCCmd(ref s) if "PONG" == s.as_slice() => normal::PONG(conn, line),
+            IRCCmd(ref s) if "CAP" == s.as_slice() => cap::CAP(conn, line),
+            IRCCmd(ref s) if "AUTHENTICATE" == s.as_slice() => sasl::AUTHENTICATE(conn, line),
+            IRCCode(numeric::RPL_LOGGEDIN) => sasl::RPL_LOGGEDIN(conn, line),
+            IRCCode(numeric::RPL_LOGGEDOUT) => sasl::RPL_LOGGEDOUT(conn, line),
+            IRCCode(numeric::RPL_SASLSUCCESS) | IRCCode(numeric::ERR_SASLALREADY) => {
+                sasl::RPL_SASLSUCCESS(conn, line)
+            }
+            IRCCode(numeric::ERR_SASLFAIL) | IRCCode(numeric::ERR_SASLTOOLONG) |
+            IRCCode(numeric::ERR_SASLABORTED) => sasl::ERR_SASLFAIL(conn, line),
+            IRCCode(numeric::RPL_SASLMECHS) => sasl::RPL_SASLMECHS(conn, line),
+            IRCCode(numeric::RPL_HOSTHIDDEN) => normal::RPL_HOSTHIDDEN(conn, line),
             _ => ()
         }
     } else {
-        match line.command {
+        match *line.command() {
             IRCCmd(ref s) if "PING" == s.as_slice() => normal::PING(conn, line),
+            IRCCmd(ref s) if "PONG" == s.as_slice() => normal::PONG(conn, line),
             IRCCmd(ref s) if "NICK" == s.as_slice() => normal::NICK(conn, line),
+            IRCCode(numeric::RPL_ISUPPORT) => normal::RPL_ISUPPORT(conn, line),
+            IRCCode(numeric::RPL_USERHOST) => normal::RPL_USERHOST(conn, line),
+            IRCCode(numeric::RPL_HOSTHIDDEN) => normal::RPL_HOSTHIDDEN(conn, line),
+            IRCCmd(ref s) if "JOIN" == s.as_slice() => channels::JOIN(conn, line),
+            IRCCmd(ref s) if "PART" == s.as_slice() => channels::PART(conn, line),
+            IRCCmd(ref s) if "KICK" == s.as_slice() => channels::KICK(conn, line),
+            IRCCmd(ref s) if "QUIT" == s.as_slice() => channels::QUIT(conn, line),
+            IRCCmd(ref s) if "MODE" == s.as_slice() => channels::MODE(conn, line),
+            IRCCmd(ref s) if "TOPIC" == s.as_slice() => channels::TOPIC(conn, line),
+            IRCCode(numeric::RPL_UMODEIS) => channels::RPL_UMODEIS(conn, line),
+            IRCCode(numeric::RPL_CHANNELMODEIS) => channels::RPL_CHANNELMODEIS(conn, line),
+            IRCCode(numeric::RPL_NOTOPIC) => channels::RPL_NOTOPIC(conn, line),
+            IRCCode(numeric::RPL_TOPIC) => channels::RPL_TOPIC(conn, line),
+            IRCCode(numeric::RPL_TOPICWHOTIME) => channels::RPL_TOPICWHOTIME(conn, line),
+            IRCCode(numeric::RPL_NAMREPLY) => channels::RPL_NAMREPLY(conn, line),
+            IRCCode(numeric::RPL_ENDOFNAMES) => channels::RPL_ENDOFNAMES(conn, line),
+            IRCCmd(ref s) if "ACCOUNT" == s.as_slice() => users::ACCOUNT(conn, line),
+            IRCCmd(ref s) if "AWAY" == s.as_slice() => users::AWAY(conn, line),
+            IRCCmd(ref s) if "CHGHOST" == s.as_slice() => users::CHGHOST(conn, line),
+            IRCCode(numeric::RPL_WHOREPLY) => users::RPL_WHOREPLY(conn, line),
+            IRCCode(numeric::RPL_WHOSPCRPL) => users::RPL_WHOSPCRPL(conn, line),
+            IRCCmd(ref s) if "CAP" == s.as_slice() => cap::CAP(conn, line),
+            IRCCode(numeric::RPL_LOGGEDIN) => sasl::RPL_LOGGEDIN(conn, line),
+            IRCCode(numeric::RPL_LOGGEDOUT) => sasl::RPL_LOGGEDOUT(conn, line),
             _ => ()
         }
     }
@@ -24,21 +63,77 @@ pub fn handle_line(conn: &mut Conn, line: &Line) {
 
 mod handshake {
     use conn::{Conn, Line};
+    use conn::builder::LineBuilder;
+    use conn::sasl;
+    use std::{mem, str};
 
     // 001
     pub fn RPL_WELCOME(conn: &mut Conn, line: &Line) {
+        if conn.sasl.is_some() && !conn.sasl_done {
+            // the server registered us without ever offering SASL
+            super::sasl::fail(conn, sasl::Unsupported);
+            return;
+        }
         conn.logged_in = true;
-        if !line.args.is_empty() {
-            conn.user = conn.user.with_nick(line.args.get(0).as_slice());
+        // the server may have skipped CAP negotiation entirely
+        conn.cap_negotiating = false;
+        if !line.args().is_empty() {
+            conn.user = conn.user.with_nick(line.args().get(0));
+        }
+        // find out the user@host the server shows for us
+        let nick = Vec::from_slice(conn.user.nick());
+        let _ = conn.send(LineBuilder::cmd("USERHOST").middle(nick.as_slice()));
+
+        if conn.reconnecting {
+            conn.reconnecting = false;
+            // we may have registered with a fallback nick while our old connection
+            // was still holding the one we want
+            if conn.user.nick() != conn.wanted_nick.as_slice() {
+                let nick = conn.wanted_nick.clone();
+                // wanted_nick was already sent once, so it can be encoded
+                let _ = conn.send_nick(nick.as_slice());
+            }
+        }
+        let rejoin = mem::replace(&mut conn.rejoin, Vec::new());
+        for chan in rejoin.iter() {
+            let key = conn.chan_keys.iter().find(|&&(ref c, _)| c == chan)
+                                    .map(|&(_, ref k)| k.clone());
+            let res = match key {
+                None => conn.join(chan.as_slice(), []),
+                Some(key) => conn.join(chan.as_slice(), key.as_slice())
+            };
+            match res {
+                Err(err) => {
+                    let chan = str::from_utf8_lossy(chan.as_slice());
+                    info!("[DEBUG] Can't rejoin {}: {}", chan, err);
+                }
+                Ok(()) => ()
+            }
+        }
+    }
+
+    // 010
+    pub fn RPL_BOUNCE(conn: &mut Conn, line: &Line) {
+        // :server 010 nick host port :info
+        if line.args().len() < 3 {
+            return;
+        }
+        let host = str::from_utf8(line.args().get(1));
+        let port = str::from_utf8(line.args().get(2)).and_then(from_str::<u16>);
+        match (host, port) {
+            (Some(host), Some(port)) if !host.is_empty() => {
+                conn.redirect = Some((host.to_owned(), port));
+            }
+            _ => ()
         }
     }
 
     // 433
     pub fn ERR_NICKNAMEINUSE(conn: &mut Conn, line: &Line) {
-        if !line.args.is_empty() {
-            let nick = line.args.get(0).as_slice();
-            if nick == conn.user.nick() {
-                conn.set_nick(Vec::from_slice(nick).append(['_' as u8]).as_slice());
+        if !line.args().is_empty() {
+            let nick = line.args().get(0);
+            if conn.is_me(nick) {
+                let _ = conn.send_nick(Vec::from_slice(nick).append(['_' as u8]).as_slice());
                 return;
             }
         }
@@ -63,8 +158,8 @@ mod handshake {
 
     fn bad_nick(conn: &mut Conn, line: &Line) {
         let mut nick;
-        if !line.args.is_empty() {
-            nick = line.args.get(0).clone();
+        if !line.args().is_empty() {
+            nick = Vec::from_slice(line.args().get(0));
         } else {
             nick = Vec::from_slice(conn.user.nick());
         }
@@ -77,33 +172,1086 @@ mod handshake {
                 break;
             }
         }
-        if modified {
-            conn.set_nick(nick.as_slice());
-        } else {
-            conn.quit([]);
+        // give up if there's nothing left to change, or the server sent us a nick
+        // we can't send back
+        if !modified || conn.send_nick(nick.as_slice()).is_err() {
+            let _ = conn.quit([]);
         }
     }
 }
 
 mod normal {
-    use conn::{IRCCmd, Conn, Line};
+    use conn::{IRCCmd, Conn, Line, Lag};
+    use UserPrefixRef;
+    use time;
 
     pub fn PING(conn: &mut Conn, line: &Line) {
-        conn.send_command(IRCCmd("PONG".into_maybe_owned()), line.args.as_slice(), false);
+        let args = line.args().to_vec();
+        conn.send_command(IRCCmd("PONG".into_maybe_owned()), args.as_slice(), false);
+    }
+
+    pub fn PONG(conn: &mut Conn, line: &Line) {
+        // :server PONG server :token
+        let sent = match (conn.ping_sent.as_ref(), line.args().last()) {
+            (Some(&(ref token, sent)), Some(arg)) if token.as_slice() == arg => sent,
+            _ => return
+        };
+        let lag = (time::precise_time_ns() - sent) / 1000000;
+        conn.ping_sent = None;
+        conn.lag = Some(lag);
+        conn.events.push(Lag(lag));
+    }
+
+    // 005
+    pub fn RPL_ISUPPORT(conn: &mut Conn, line: &Line) {
+        // :server 005 me TOKEN[=value] -TOKEN ... :are supported by this server
+        if line.args().len() < 3 {
+            return;
+        }
+        let args = line.args();
+        conn.support.parse_tokens(args.slice(1, args.len()-1).to_vec().as_slice());
+        let casemap = conn.support.casemapping();
+        conn.users.set_casemapping(casemap);
+        for chan in conn.channels.mut_iter() {
+            chan.set_casemapping(casemap);
+        }
+    }
+
+    // 302
+    pub fn RPL_USERHOST(conn: &mut Conn, line: &Line) {
+        // :server 302 me :nick[*]=[+-]user@host ...
+        if line.args().len() < 2 {
+            return;
+        }
+        for reply in line.args().last().unwrap().split(|&b| b == ' ' as u8) {
+            let idx = match reply.position_elem(&('=' as u8)) {
+                Some(idx) => idx,
+                None => continue
+            };
+            // * marks an IRC operator, + or - whether they're away
+            let nick = reply.slice_to(idx);
+            let nick = if nick.ends_with(bytes!("*")) { nick.slice_to(nick.len()-1) } else { nick };
+            if !conn.is_me(nick) {
+                continue;
+            }
+            let mask = reply.slice_from(idx+1);
+            let mask = match mask.head() {
+                Some(&b) if b == '+' as u8 || b == '-' as u8 => mask.slice_from(1),
+                _ => mask
+            };
+            match mask.position_elem(&('@' as u8)) {
+                Some(at) => conn.set_my_host(mask.slice_to(at), mask.slice_from(at+1)),
+                None => ()
+            }
+        }
+    }
+
+    // 396
+    pub fn RPL_HOSTHIDDEN(conn: &mut Conn, line: &Line) {
+        // :server 396 me [user@]host :is now your displayed host
+        if line.args().len() < 2 {
+            return;
+        }
+        let host = line.args().get(1);
+        match host.position_elem(&('@' as u8)) {
+            Some(at) => conn.set_my_host(host.slice_to(at), host.slice_from(at+1)),
+            None => {
+                let user = match conn.user.user() {
+                    Some(user) => Vec::from_slice(user),
+                    None => return
+                };
+                conn.set_my_host(user.as_slice(), host);
+            }
+        }
     }
 
     pub fn NICK(conn: &mut Conn, line: &Line) {
-        if line.args.is_empty() {
+        if line.args().is_empty() {
             // where's my arg?
             return;
         }
-        match line.prefix {
-            Some(ref user) => {
-                if user.nick() == conn.user.nick() {
-                    conn.user = conn.user.with_nick(line.args.get(0).as_slice());
+        let prefix = line.prefix();
+        match prefix {
+            Some(UserPrefixRef(ref user)) => {
+                let new = line.args().get(0);
+                if conn.is_me(user.nick()) {
+                    conn.user = conn.user.with_nick(new);
+                }
+                for chan in conn.channels.mut_iter() {
+                    chan.rename_member(user.nick(), new);
                 }
+                conn.users.rename(user.nick(), new);
             }
+            _ => ()
+        }
+    }
+}
+
+mod channels {
+    use conn::{IRCCmd, Conn, Line, LowPriority};
+    use UserPrefixRef;
+    use conn::state::{Channel, parse_names};
+    use conn::modes::{Modes, ModeChange, ChannelModes, UserModes};
+    use conn::modes::{parse_channel_modes, parse_user_modes};
+    use super::users::WhoxToken;
+    use std::str;
+    use time;
+
+    pub fn JOIN(conn: &mut Conn, line: &Line) {
+        // :nick!user@host JOIN #channel [account :realname]
+        let prefix = line.prefix();
+        let user = match prefix {
+            Some(UserPrefixRef(ref user)) if !line.args().is_empty() => user,
+            _ => return
+        };
+        let name = line.args().get(0);
+        if conn.is_me(user.nick()) {
+            // our own JOIN shows the hostmask everyone else sees
+            match (user.user(), user.host()) {
+                (Some(u), Some(h)) => conn.set_my_host(u, h),
+                _ => ()
+            }
+        }
+        if conn.is_me(user.nick()) && conn.channel(name).is_none() {
+            conn.channels.push(Channel::new(name, conn.support.casemapping()));
+            // fill in the user registry for everyone already in the channel
+            let who = IRCCmd("WHO".into_maybe_owned());
+            if conn.support.token("WHOX").is_some() {
+                // token, channel, user, host, nick, flags, account, realname
+                let fields = Vec::from_slice(bytes!("%tcuhnfar,")).append(WhoxToken);
+                conn.send_command_with_priority(who, [name, fields.as_slice()], false,
+                                                LowPriority);
+            } else {
+                conn.send_command_with_priority(who, [name], false, LowPriority);
+            }
+        }
+        match channel_mut(&mut conn.channels, name) {
+            None => return,
+            Some(chan) => chan.add_member(user.nick(), [], conn.support.prefixes())
+        }
+        let info = conn.users.join(&user.to_user(), name);
+        if line.args().len() >= 3 {
+            // extended-join
+            info.set_account(Some(line.args().get(1)));
+            info.set_realname(line.args().get(2));
+        }
+    }
+
+    pub fn PART(conn: &mut Conn, line: &Line) {
+        // :nick!user@host PART #channel[,#channel] :reason
+        let prefix = line.prefix();
+        let nick = match prefix {
+            Some(UserPrefixRef(ref user)) if !line.args().is_empty() => user.nick(),
+            _ => return
+        };
+        for name in line.args().get(0).split(|&b| b == ',' as u8) {
+            leave(conn, name, nick);
+        }
+    }
+
+    pub fn KICK(conn: &mut Conn, line: &Line) {
+        // :src KICK #channel nick :reason
+        if line.args().len() < 2 {
+            return;
+        }
+        leave(conn, line.args().get(0), line.args().get(1));
+    }
+
+    pub fn QUIT(conn: &mut Conn, line: &Line) {
+        // :nick!user@host QUIT :reason
+        let prefix = line.prefix();
+        let nick = match prefix {
+            Some(UserPrefixRef(ref user)) => user.nick(),
+            _ => return
+        };
+        for chan in conn.channels.mut_iter() {
+            chan.remove_member(nick);
+        }
+        conn.users.remove(nick);
+    }
+
+    pub fn MODE(conn: &mut Conn, line: &Line) {
+        // :src MODE target modes [args...]
+        match Modes::from_line(line, &conn.support) {
+            Some(ChannelModes(name, changes)) => {
+                match channel_mut(&mut conn.channels, name.as_slice()) {
+                    None => (),
+                    Some(chan) => chan.apply_modes(changes.as_slice(), &conn.support)
+                }
+            }
+            Some(UserModes(ref nick, ref changes)) if conn.is_me(nick.as_slice()) => {
+                apply_user_modes(conn, changes.as_slice());
+            }
+            _ => ()
+        }
+    }
+
+    // 221
+    pub fn RPL_UMODEIS(conn: &mut Conn, line: &Line) {
+        // :server 221 me modes
+        if line.args().len() < 2 {
+            return;
+        }
+        let args = line.args().slice_from(2).to_vec();
+        let changes = parse_user_modes(line.args().get(1), args.as_slice());
+        conn.umodes.clear();
+        apply_user_modes(conn, changes.as_slice());
+    }
+
+    fn apply_user_modes(conn: &mut Conn, changes: &[ModeChange]) {
+        for change in changes.iter() {
+            conn.umodes.retain(|&m| m != change.mode);
+            if change.set {
+                conn.umodes.push(change.mode);
+            }
+        }
+    }
+
+    pub fn TOPIC(conn: &mut Conn, line: &Line) {
+        // :nick!user@host TOPIC #channel :topic
+        if line.args().len() < 2 {
+            return;
+        }
+        let now = time::get_time().sec as u64;
+        let text = line.args().get(1);
+        match channel_mut(&mut conn.channels, line.args().get(0)) {
+            None => (),
+            Some(chan) => {
+                chan.set_topic(if text.is_empty() { None } else { Some(text) });
+                match line.prefix_raw() {
+                    None => (),
+                    Some(prefix) => chan.set_topic_info(prefix, Some(now))
+                }
+            }
+        }
+    }
+
+    // 324
+    pub fn RPL_CHANNELMODEIS(conn: &mut Conn, line: &Line) {
+        // :server 324 me #channel modes [args...]
+        if line.args().len() < 3 {
+            return;
+        }
+        let args = line.args().slice_from(3).to_vec();
+        let changes = parse_channel_modes(line.args().get(2), args.as_slice(), &conn.support);
+        match channel_mut(&mut conn.channels, line.args().get(1)) {
+            None => (),
+            Some(chan) => {
+                chan.clear_modes();
+                chan.apply_modes(changes.as_slice(), &conn.support)
+            }
+        }
+    }
+
+    // 331
+    pub fn RPL_NOTOPIC(conn: &mut Conn, line: &Line) {
+        // :server 331 me #channel :No topic is set
+        if line.args().len() < 2 {
+            return;
+        }
+        match channel_mut(&mut conn.channels, line.args().get(1)) {
+            None => (),
+            Some(chan) => chan.set_topic(None)
+        }
+    }
+
+    // 332
+    pub fn RPL_TOPIC(conn: &mut Conn, line: &Line) {
+        // :server 332 me #channel :topic
+        if line.args().len() < 3 {
+            return;
+        }
+        match channel_mut(&mut conn.channels, line.args().get(1)) {
+            None => (),
+            Some(chan) => chan.set_topic(Some(line.args().get(2)))
+        }
+    }
+
+    // 333
+    pub fn RPL_TOPICWHOTIME(conn: &mut Conn, line: &Line) {
+        // :server 333 me #channel setter time
+        if line.args().len() < 4 {
+            return;
+        }
+        let time = str::from_utf8(line.args().get(3)).and_then(from_str::<u64>);
+        match channel_mut(&mut conn.channels, line.args().get(1)) {
+            None => (),
+            Some(chan) => chan.set_topic_info(line.args().get(2), time)
+        }
+    }
+
+    // 353
+    pub fn RPL_NAMREPLY(conn: &mut Conn, line: &Line) {
+        // :server 353 me = #channel :names
+        if line.args().len() < 4 {
+            return;
+        }
+        let name = line.args().get(2);
+        let names = parse_names(line.args().get(3), conn.support.prefixes());
+        match channel_mut(&mut conn.channels, name) {
+            None => return,
+            Some(chan) => chan.add_names(names.as_slice(), conn.support.prefixes())
+        }
+        for &(ref user, _) in names.iter() {
+            conn.users.join(user, name);
+        }
+    }
+
+    // 366
+    pub fn RPL_ENDOFNAMES(conn: &mut Conn, line: &Line) {
+        // :server 366 me #channel :End of NAMES list
+        if line.args().len() < 2 {
+            return;
+        }
+        match channel_mut(&mut conn.channels, line.args().get(1)) {
+            None => (),
+            Some(chan) => {
+                chan.end_names();
+                // a refreshed list may have dropped users we saw leave without noticing
+                conn.users.sync_channel(chan);
+            }
+        }
+    }
+
+    /// Removes `nick` from the channel, or the channel itself if `nick` is us
+    fn leave(conn: &mut Conn, name: &[u8], nick: &[u8]) {
+        if conn.is_me(nick) {
+            conn.channels.retain(|c| !c.is_named(name));
+            conn.users.part_all(name);
+            // don't use an old key if we join again later
+            let casemap = conn.support.casemapping();
+            conn.chan_keys.retain(|&(ref c, _)| !casemap.equiv(c.as_slice(), name));
+        } else {
+            match channel_mut(&mut conn.channels, name) {
+                None => (),
+                Some(chan) => { chan.remove_member(nick); }
+            }
+            conn.users.part(nick, name);
+        }
+    }
+
+    fn channel_mut<'a>(channels: &'a mut Vec<Channel>, name: &[u8]) -> Option<&'a mut Channel> {
+        channels.mut_iter().find(|c| c.is_named(name))
+    }
+}
+
+mod users {
+    use conn::{Conn, Line};
+    use UserPrefixRef;
+
+    /// The token that marks replies to the WHOX queries we send on joining a channel
+    pub static WhoxToken: &'static [u8] = bytes!("152");
+
+    pub fn ACCOUNT(conn: &mut Conn, line: &Line) {
+        // :nick!user@host ACCOUNT accountname
+        match (line.prefix(), line.args().head()) {
+            (Some(UserPrefixRef(ref user)), Some(acct)) => match conn.users.get_mut(user.nick()) {
+                None => (),
+                Some(info) => info.set_account(Some(acct.as_slice()))
+            },
+            _ => ()
+        }
+    }
+
+    pub fn AWAY(conn: &mut Conn, line: &Line) {
+        // :nick!user@host AWAY [:message]
+        let prefix = line.prefix();
+        let user = match prefix {
+            Some(UserPrefixRef(ref user)) => user,
+            _ => return
+        };
+        match conn.users.get_mut(user.nick()) {
+            None => (),
+            Some(info) => info.set_away(line.args().head().map(|m| m.as_slice()))
+        }
+    }
+
+    pub fn CHGHOST(conn: &mut Conn, line: &Line) {
+        // :nick!user@host CHGHOST newuser newhost
+        let prefix = line.prefix();
+        let user = match prefix {
+            Some(UserPrefixRef(ref user)) if line.args().len() >= 2 => user,
+            _ => return
+        };
+        let (new_user, new_host) = (line.args().get(0), line.args().get(1));
+        if conn.is_me(user.nick()) {
+            conn.set_my_host(new_user, new_host);
+        }
+        match conn.users.get_mut(user.nick()) {
+            None => (),
+            Some(info) => info.set_host(new_user, new_host)
+        }
+    }
+
+    // 352
+    pub fn RPL_WHOREPLY(conn: &mut Conn, line: &Line) {
+        // :server 352 me #channel user host server nick flags :hopcount realname
+        if line.args().len() < 8 {
+            return;
+        }
+        let args = line.args();
+        let realname = args.get(7);
+        let realname = match realname.iter().position(|&b| b == ' ' as u8) {
+            Some(idx) => realname.slice_from(idx+1),
+            None => realname.slice_from(realname.len())
+        };
+        update(conn, args.get(5), args.get(2), args.get(3), args.get(6), None, realname);
+    }
+
+    // 354
+    pub fn RPL_WHOSPCRPL(conn: &mut Conn, line: &Line) {
+        // only our own queries have a known field layout:
+        // :server 354 me token #channel user host nick flags account :realname
+        if line.args().len() < 9 || line.args().get(1) != WhoxToken {
+            return;
+        }
+        let args = line.args();
+        update(conn, args.get(5), args.get(3), args.get(4), args.get(6), Some(args.get(7)),
+               args.get(8));
+    }
+
+    /// Updates a known user from a WHO reply. Unknown users are ignored, since we
+    /// only track users that share a channel with us.
+    fn update(conn: &mut Conn, nick: &[u8], user: &[u8], host: &[u8], flags: &[u8],
+              account: Option<&[u8]>, realname: &[u8]) {
+        if conn.is_me(nick) {
+            conn.set_my_host(user, host);
+        }
+        let info = match conn.users.get_mut(nick) {
+            Some(info) => info,
+            None => return
+        };
+        info.set_host(user, host);
+        info.set_realname(realname);
+        match account {
+            // WHOX uses 0 for users that aren't logged in
+            Some(acct) => info.set_account(if acct == bytes!("0") { None } else { Some(acct) }),
             None => ()
         }
+        // H is here, G is gone
+        match flags.head() {
+            Some(&b) if b == 'G' as u8 => if !info.is_away() { info.set_away(Some(bytes!(""))) },
+            Some(&b) if b == 'H' as u8 => info.set_away(None),
+            _ => ()
+        }
+    }
+}
+
+mod cap {
+    use conn::{IRCCmd, Conn, Line};
+    use conn::sasl;
+    use std::str;
+
+    pub fn CAP(conn: &mut Conn, line: &Line) {
+        // :server CAP nick subcmd [*] :caps
+        if line.args().len() < 3 {
+            return;
+        }
+        let (more, list) = if line.args().len() > 3 && line.args().get(2) == bytes!("*") {
+            (true, line.args().get(3))
+        } else {
+            (false, line.args().get(2))
+        };
+        let list = str::from_utf8_lossy(list);
+        let caps: Vec<&str> = list.as_slice().words().collect();
+        match str::from_utf8(line.args().get(1)) {
+            Some("LS") => LS(conn, caps.as_slice(), more),
+            Some("ACK") => ACK(conn, caps.as_slice(), more),
+            Some("NAK") => NAK(conn, more),
+            Some("NEW") => NEW(conn, caps.as_slice()),
+            Some("DEL") => DEL(conn, caps.as_slice()),
+            _ => ()
+        }
+    }
+
+    fn LS(conn: &mut Conn, caps: &[&str], more: bool) {
+        add_available(conn, caps);
+        if more {
+            return;
+        }
+        if !request_wanted(conn) {
+            end(conn);
+        }
+    }
+
+    fn ACK(conn: &mut Conn, caps: &[&str], more: bool) {
+        for &cap in caps.iter() {
+            if cap.starts_with("-") {
+                let cap = cap.slice_from(1);
+                conn.caps_enabled.retain(|c| cap != c.as_slice());
+            } else if !conn.has_cap(cap) {
+                conn.caps_enabled.push(cap.to_owned());
+            }
+        }
+        if conn.cap_negotiating && conn.has_cap("sasl") {
+            super::sasl::start(conn);
+        }
+        if !more {
+            finish_request(conn);
+        }
+    }
+
+    fn NAK(conn: &mut Conn, more: bool) {
+        if !more {
+            finish_request(conn);
+        }
+    }
+
+    fn NEW(conn: &mut Conn, caps: &[&str]) {
+        add_available(conn, caps);
+        request_wanted(conn);
+    }
+
+    fn DEL(conn: &mut Conn, caps: &[&str]) {
+        for &cap in caps.iter() {
+            conn.caps_available.retain(|&(ref c, _)| cap != c.as_slice());
+            conn.caps_enabled.retain(|c| cap != c.as_slice());
+        }
+    }
+
+    fn add_available(conn: &mut Conn, caps: &[&str]) {
+        for &cap in caps.iter() {
+            let (name, value) = match cap.find('=') {
+                None => (cap, None),
+                Some(idx) => (cap.slice_to(idx), Some(cap.slice_from(idx+1).to_owned()))
+            };
+            conn.caps_available.retain(|&(ref c, _)| name != c.as_slice());
+            conn.caps_available.push((name.to_owned(), value));
+        }
+    }
+
+    /// Sends a CAP REQ for every requested capability that is available but not enabled.
+    /// Returns `true` if a request was sent.
+    fn request_wanted(conn: &mut Conn) -> bool {
+        let mut req = String::new();
+        for &cap in conn.caps_requested.iter() {
+            let available = conn.caps_available.iter().any(|&(ref c, _)| cap == c.as_slice());
+            if !available || conn.has_cap(cap) {
+                continue;
+            }
+            if !req.is_empty() {
+                req.push_char(' ');
+            }
+            req.push_str(cap);
+        }
+        if req.is_empty() {
+            return false;
+        }
+        conn.cap_pending += 1;
+        conn.send_command(IRCCmd("CAP".into_maybe_owned()),
+                          [bytes!("REQ"), req.as_slice().as_bytes()], true);
+        true
+    }
+
+    fn finish_request(conn: &mut Conn) {
+        if conn.cap_pending > 0 {
+            conn.cap_pending -= 1;
+        }
+        if conn.cap_pending == 0 {
+            end(conn);
+        }
+    }
+
+    /// Ends capability negotiation if it's still in progress, allowing registration to complete.
+    /// Does nothing while SASL authentication is underway.
+    pub fn end(conn: &mut Conn) {
+        if !conn.cap_negotiating || conn.sasl_session.is_some() {
+            return;
+        }
+        if conn.sasl.is_some() && !conn.sasl_done {
+            super::sasl::fail(conn, sasl::Unsupported);
+            return;
+        }
+        conn.cap_negotiating = false;
+        conn.send_command(IRCCmd("CAP".into_maybe_owned()), [bytes!("END")], false);
+    }
+}
+
+mod sasl {
+    use conn::{IRCCmd, Conn, Line, ErrSasl};
+    use conn::sasl::{Session, SaslError, Unsupported, AuthFailed, encode_response};
+    use std::str;
+
+    /// Begins authentication, if we have credentials and haven't already done so.
+    pub fn start(conn: &mut Conn) {
+        if conn.sasl_done || conn.sasl_session.is_some() {
+            return;
+        }
+        let creds = match conn.sasl {
+            None => return,
+            Some(creds) => creds
+        };
+        let name = creds.mechanism.name();
+        // CAP LS 302 may tell us the supported mechanisms up front
+        let supported = conn.caps_available.iter().find(|&&(ref c, _)| "sasl" == c.as_slice())
+                            .and_then(|&(_, ref v)| v.as_ref())
+                            .map_or(true, |v| v.as_slice().split(',').any(|m| m == name));
+        if !supported {
+            fail(conn, Unsupported);
+            return;
+        }
+        conn.sasl_session = Some(Session::new(&creds));
+        conn.send_command(IRCCmd("AUTHENTICATE".into_maybe_owned()), [name.as_bytes()], false);
+    }
+
+    pub fn AUTHENTICATE(conn: &mut Conn, line: &Line) {
+        if line.args().is_empty() {
+            return;
+        }
+        let res = match conn.sasl_session {
+            None => return,
+            Some(ref mut session) => {
+                match session.push_chunk(line.args().get(0)) {
+                    None => return,
+                    Some(challenge) => challenge.and_then(|c| session.step(c.as_slice()))
+                }
+            }
+        };
+        match res {
+            Ok(resp) => {
+                for chunk in encode_response(resp.as_slice()).iter() {
+                    conn.send_command(IRCCmd("AUTHENTICATE".into_maybe_owned()),
+                                      [chunk.as_bytes()], false);
+                }
+            }
+            Err(err) => {
+                conn.send_command(IRCCmd("AUTHENTICATE".into_maybe_owned()), [bytes!("*")], false);
+                fail(conn, err);
+            }
+        }
+    }
+
+    // 900
+    pub fn RPL_LOGGEDIN(conn: &mut Conn, line: &Line) {
+        // :server 900 nick nick!user@host account :You are now logged in as account
+        if line.args().len() >= 3 {
+            conn.account = Some(Vec::from_slice(line.args().get(2)));
+        }
+    }
+
+    // 901
+    pub fn RPL_LOGGEDOUT(conn: &mut Conn, _line: &Line) {
+        conn.account = None;
+    }
+
+    // 903, 907
+    pub fn RPL_SASLSUCCESS(conn: &mut Conn, _line: &Line) {
+        conn.sasl_session = None;
+        conn.sasl_done = true;
+        super::cap::end(conn);
+    }
+
+    // 904, 905, 906
+    pub fn ERR_SASLFAIL(conn: &mut Conn, line: &Line) {
+        let code = match *line.command() {
+            ::conn::IRCCode(code) => code,
+            _ => 0
+        };
+        let msg = line.args().last().map_or(String::new(), |v| {
+            str::from_utf8_lossy(v.as_slice()).into_owned()
+        });
+        fail(conn, AuthFailed(code, msg));
+    }
+
+    // 908
+    pub fn RPL_SASLMECHS(conn: &mut Conn, line: &Line) {
+        // :server 908 nick mech1,mech2 :are available SASL mechanisms
+        let name = match conn.sasl_session {
+            None => return,
+            Some(ref session) => session.mechanism().name()
+        };
+        if line.args().len() >= 2 {
+            let mechs = line.args().get(1);
+            if !mechs.split(|&b| b == ',' as u8).any(|m| m == name.as_bytes()) {
+                fail(conn, Unsupported);
+            }
+        }
+    }
+
+    /// Gives up on authentication and shuts down the connection with ErrSasl.
+    pub fn fail(conn: &mut Conn, err: SaslError) {
+        conn.sasl_session = None;
+        if conn.error.is_none() {
+            let _ = conn.quit([]);
+            conn.error = Some(ErrSasl(err));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use conn::{Conn, ErrSasl, Lag};
+    use conn::sasl::{Credentials, Plain, AuthFailed};
+    use conn::tests::{test_conn, recv, sent};
+    use time;
+
+    macro_rules! b(
+        ($args:tt) => (
+            { static b: &'static [u8] = bytes!($args); b }
+        )
+    )
+
+    #[test]
+    fn cap_ls_multiline() {
+        let (mut conn, _rx) = test_conn();
+        conn.caps_requested = vec!["multi-prefix", "away-notify", "batch"];
+        conn.cap_negotiating = true;
+        recv(&mut conn, b!(":irc.example.com CAP * LS * :multi-prefix sasl=PLAIN,EXTERNAL"));
+        // nothing is requested until the last line of the list
+        assert_eq!(sent(&conn), Vec::<String>::new());
+        recv(&mut conn, b!(":irc.example.com CAP * LS :away-notify account-notify"));
+        assert_eq!(sent(&conn), vec!["CAP REQ :multi-prefix away-notify".to_owned()]);
+        assert_eq!(conn.cap_pending, 1);
+        let sasl = conn.caps_available.iter().find(|&&(ref c, _)| c.as_slice() == "sasl");
+        assert_eq!(sasl.and_then(|&(_, ref v)| v.clone()), Some("PLAIN,EXTERNAL".to_owned()));
+        assert!(conn.cap_negotiating);
+    }
+
+    #[test]
+    fn cap_ls_nothing_wanted() {
+        let (mut conn, _rx) = test_conn();
+        conn.caps_requested = vec!["batch"];
+        conn.cap_negotiating = true;
+        recv(&mut conn, b!(":irc.example.com CAP * LS :multi-prefix"));
+        assert_eq!(sent(&conn), vec!["CAP END".to_owned()]);
+        assert!(!conn.cap_negotiating);
+    }
+
+    #[test]
+    fn cap_ack_nak() {
+        let (mut conn, _rx) = test_conn();
+        conn.caps_requested = vec!["multi-prefix", "away-notify"];
+        conn.cap_negotiating = true;
+        recv(&mut conn, b!(":irc.example.com CAP * LS :multi-prefix away-notify"));
+        assert_eq!(sent(&conn), vec!["CAP REQ :multi-prefix away-notify".to_owned()]);
+
+        // a multiline ACK doesn't finish the request
+        recv(&mut conn, b!(":irc.example.com CAP me ACK * :multi-prefix"));
+        assert!(conn.has_cap("multi-prefix"));
+        assert_eq!(sent(&conn), Vec::<String>::new());
+        recv(&mut conn, b!(":irc.example.com CAP me ACK :away-notify"));
+        assert!(conn.has_cap("away-notify"));
+        assert_eq!(conn.cap_pending, 0);
+        assert_eq!(sent(&conn), vec!["CAP END".to_owned()]);
+        assert!(!conn.cap_negotiating);
+
+        // a NAK leaves everything as it was
+        let (mut conn, _rx) = test_conn();
+        conn.caps_requested = vec!["multi-prefix"];
+        conn.cap_negotiating = true;
+        recv(&mut conn, b!(":irc.example.com CAP * LS :multi-prefix"));
+        assert_eq!(sent(&conn), vec!["CAP REQ :multi-prefix".to_owned()]);
+        recv(&mut conn, b!(":irc.example.com CAP me NAK :multi-prefix"));
+        assert!(!conn.has_cap("multi-prefix"));
+        assert_eq!(sent(&conn), vec!["CAP END".to_owned()]);
+    }
+
+    #[test]
+    fn cap_end_waits_for_every_request() {
+        let (mut conn, _rx) = test_conn();
+        conn.caps_requested = vec!["multi-prefix", "away-notify"];
+        conn.cap_negotiating = true;
+        conn.cap_pending = 2;
+        recv(&mut conn, b!(":irc.example.com CAP me ACK :multi-prefix"));
+        assert_eq!(conn.cap_pending, 1);
+        assert_eq!(sent(&conn), Vec::<String>::new());
+        assert!(conn.cap_negotiating);
+        recv(&mut conn, b!(":irc.example.com CAP me NAK :away-notify"));
+        assert_eq!(conn.cap_pending, 0);
+        assert_eq!(sent(&conn), vec!["CAP END".to_owned()]);
+
+        // CAP END is only sent once
+        recv(&mut conn, b!(":irc.example.com CAP me ACK :away-notify"));
+        assert_eq!(sent(&conn), Vec::<String>::new());
+    }
+
+    #[test]
+    fn cap_new_del() {
+        let (mut conn, _rx) = test_conn();
+        conn.caps_requested = vec!["away-notify"];
+        conn.logged_in = true;
+        recv(&mut conn, b!(":irc.example.com CAP me NEW :away-notify extended-join"));
+        assert_eq!(sent(&conn), vec!["CAP REQ :away-notify".to_owned()]);
+        recv(&mut conn, b!(":irc.example.com CAP me ACK :away-notify"));
+        assert!(conn.has_cap("away-notify"));
+        // negotiation is already over
+        assert_eq!(sent(&conn), Vec::<String>::new());
+
+        recv(&mut conn, b!(":irc.example.com CAP me DEL :away-notify"));
+        assert!(!conn.has_cap("away-notify"));
+        assert!(!conn.caps_available.iter().any(|&(ref c, _)| c.as_slice() == "away-notify"));
+        assert!(conn.caps_available.iter().any(|&(ref c, _)| c.as_slice() == "extended-join"));
+
+        // caps we never asked for aren't requested
+        recv(&mut conn, b!(":irc.example.com CAP me NEW :batch"));
+        assert_eq!(sent(&conn), Vec::<String>::new());
+    }
+
+    /// Returns a Conn that has just started SASL PLAIN during registration
+    fn sasl_conn<'a>() -> (Conn<'a>, Receiver<()>) {
+        let (mut conn, rx) = test_conn();
+        conn.sasl = Some(Credentials::new(Plain, "jilles", "sesame"));
+        conn.caps_requested = vec!["sasl"];
+        conn.cap_negotiating = true;
+        recv(&mut conn, b!(":irc.example.com CAP * LS :sasl=PLAIN,EXTERNAL"));
+        assert_eq!(sent(&conn), vec!["CAP REQ :sasl".to_owned()]);
+        recv(&mut conn, b!(":irc.example.com CAP me ACK :sasl"));
+        // CAP END waits for authentication to finish
+        assert_eq!(sent(&conn), vec!["AUTHENTICATE PLAIN".to_owned()]);
+        (conn, rx)
+    }
+
+    #[test]
+    fn sasl_success() {
+        let (mut conn, _rx) = sasl_conn();
+        recv(&mut conn, b!("AUTHENTICATE +"));
+        assert_eq!(sent(&conn), vec!["AUTHENTICATE amlsbGVzAGppbGxlcwBzZXNhbWU=".to_owned()]);
+        recv(&mut conn, b!(":irc.example.com 900 me me!user@host jilles \
+                            :You are now logged in as jilles"));
+        assert_eq!(conn.account(), Some(b!("jilles")));
+        assert!(conn.cap_negotiating);
+        recv(&mut conn, b!(":irc.example.com 903 me :SASL authentication successful"));
+        assert_eq!(sent(&conn), vec!["CAP END".to_owned()]);
+        assert!(conn.sasl_done);
+        assert!(!conn.cap_negotiating);
+        assert!(conn.error.is_none());
+    }
+
+    #[test]
+    fn sasl_failure() {
+        for &code in [904u, 905, 906].iter() {
+            let (mut conn, _rx) = sasl_conn();
+            recv(&mut conn, b!("AUTHENTICATE +"));
+            assert_eq!(sent(&conn).len(), 1);
+            let raw = format!(":irc.example.com {} me :SASL authentication failed", code);
+            recv(&mut conn, raw.as_bytes());
+            match conn.error {
+                Some(ErrSasl(AuthFailed(c, ref msg))) => {
+                    assert_eq!(c, code);
+                    assert_eq!(msg.as_slice(), "SASL authentication failed");
+                }
+                _ => fail!("expected ErrSasl for {}", code)
+            }
+            // registration is abandoned rather than completed without the account
+            assert_eq!(sent(&conn), vec!["QUIT".to_owned()]);
+            assert!(conn.sasl_session.is_none());
+        }
+    }
+
+    #[test]
+    fn sasl_bad_challenge() {
+        let (mut conn, _rx) = sasl_conn();
+        recv(&mut conn, b!("AUTHENTICATE !!!"));
+        match conn.error {
+            Some(ErrSasl(_)) => (),
+            _ => fail!("expected ErrSasl")
+        }
+        assert_eq!(sent(&conn), vec!["AUTHENTICATE *".to_owned(), "QUIT".to_owned()]);
+    }
+
+    #[test]
+    fn reconnect_restores_state() {
+        let (mut conn, _rx) = test_conn();
+        conn.logged_in = true;
+        assert!(conn.join(b!("#a,#b"), b!("key1")).is_ok());
+        assert!(conn.join(b!("#c"), b!("key3")).is_ok());
+        recv(&mut conn, b!(":me!user@host JOIN #a"));
+        recv(&mut conn, b!(":me!user@host JOIN #b"));
+        recv(&mut conn, b!(":me!user@host JOIN #c"));
+        assert!(conn.set_nick(b!("newnick")).is_ok());
+        sent(&conn);
+
+        // the connection drops and we register with a fallback nick
+        conn.reset();
+        conn.reconnecting = true;
+        assert_eq!(conn.me().nick(), b!("newnick"));
+        recv(&mut conn, b!(":irc.example.com 001 newnick_ :Welcome"));
+        assert_eq!(sent(&conn), vec!["NICK newnick".to_owned(), "USERHOST newnick_".to_owned(),
+                                     "JOIN #a key1".to_owned(), "JOIN #b".to_owned(),
+                                     "JOIN #c key3".to_owned()]);
+        assert!(!conn.reconnecting);
+    }
+
+    #[test]
+    fn leaving_forgets_key() {
+        let (mut conn, _rx) = test_conn();
+        conn.logged_in = true;
+        assert!(conn.join(b!("#a,#b"), b!("key1,key2")).is_ok());
+        recv(&mut conn, b!(":me!user@host JOIN #a"));
+        recv(&mut conn, b!(":me!user@host JOIN #b"));
+        recv(&mut conn, b!(":me!user@host PART #A :bye"));
+        recv(&mut conn, b!(":op!user@host KICK #b me :out"));
+        assert!(conn.chan_keys.is_empty());
+        assert!(conn.channels().is_empty());
+        sent(&conn);
+
+        // a later join without a key doesn't pick up the old one
+        assert!(conn.join(b!("#a"), []).is_ok());
+        recv(&mut conn, b!(":me!user@host JOIN #a"));
+        assert_eq!(sent(&conn), vec!["JOIN #a".to_owned(), "WHO #a".to_owned()]);
+        conn.reset();
+        recv(&mut conn, b!(":irc.example.com 001 me :Welcome"));
+        assert_eq!(sent(&conn), vec!["USERHOST me".to_owned(), "JOIN #a".to_owned()]);
+    }
+
+    #[test]
+    fn userhost_reply() {
+        let (mut conn, _rx) = test_conn();
+        conn.logged_in = true;
+        // * marks an operator, and + or - whether we're away
+        recv(&mut conn, b!(":irc.example.com 302 me :bob=+b@b.example me*=-~u@h.example"));
+        assert_eq!(conn.me().user(), Some(b!("~u")));
+        assert_eq!(conn.me().host(), Some(b!("h.example")));
+        recv(&mut conn, b!(":irc.example.com 302 me :me=+u2@h2.example"));
+        assert_eq!(conn.me().user(), Some(b!("u2")));
+        assert_eq!(conn.me().host(), Some(b!("h2.example")));
+        recv(&mut conn, b!(":irc.example.com 302 me :ME*=u3@h3.example"));
+        assert_eq!(conn.me().user(), Some(b!("u3")));
+        assert_eq!(conn.me().host(), Some(b!("h3.example")));
+
+        // replies for other nicks and malformed replies are ignored
+        for &raw in [b!(":irc.example.com 302 me :bob=+b@b.example"),
+                     b!(":irc.example.com 302 me :me"),
+                     b!(":irc.example.com 302 me :me=+nohost"),
+                     b!(":irc.example.com 302 me :me=+@h.example"),
+                     b!(":irc.example.com 302 me")].iter() {
+            recv(&mut conn, raw);
+            assert_eq!(conn.me().user(), Some(b!("u3")));
+            assert_eq!(conn.me().host(), Some(b!("h3.example")));
+        }
+    }
+
+    #[test]
+    fn host_hidden() {
+        let (mut conn, _rx) = test_conn();
+        conn.logged_in = true;
+        // without a user@, we keep the user we have
+        recv(&mut conn, b!(":irc.example.com 396 me hidden.example :is now your displayed host"));
+        assert_eq!(conn.me().user(), Some(b!("user")));
+        assert_eq!(conn.me().host(), Some(b!("hidden.example")));
+        recv(&mut conn, b!(":irc.example.com 396 me ~v@vhost.example :is now your displayed host"));
+        assert_eq!(conn.me().user(), Some(b!("~v")));
+        assert_eq!(conn.me().host(), Some(b!("vhost.example")));
+    }
+
+    #[test]
+    fn chghost() {
+        let (mut conn, _rx) = test_conn();
+        conn.logged_in = true;
+        recv(&mut conn, b!(":me!user@host CHGHOST newuser new.example"));
+        assert_eq!(conn.me().nick(), b!("me"));
+        assert_eq!(conn.me().user(), Some(b!("newuser")));
+        assert_eq!(conn.me().host(), Some(b!("new.example")));
+
+        // someone else changing host doesn't affect us
+        recv(&mut conn, b!(":bob!b@b.example CHGHOST x y.example"));
+        assert_eq!(conn.me().user(), Some(b!("newuser")));
+        assert_eq!(conn.me().host(), Some(b!("new.example")));
+    }
+
+    #[test]
+    fn bounce() {
+        let (mut conn, _rx) = test_conn();
+        recv(&mut conn, b!(":irc.example.com 010 me irc2.example.com 6697 :Server full"));
+        assert_eq!(conn.redirect, Some(("irc2.example.com".to_owned(), 6697)));
+
+        // malformed redirects are ignored
+        for &raw in [b!(":irc.example.com 010 me irc2.example.com :Server full"),
+                     b!(":irc.example.com 010 me irc2.example.com 66970 :Server full"),
+                     b!(":irc.example.com 010 me irc2.example.com port :Server full"),
+                     b!(":irc.example.com 010 me 6697")].iter() {
+            let (mut conn, _rx) = test_conn();
+            recv(&mut conn, raw);
+            assert_eq!(conn.redirect, None);
+        }
+    }
+
+    #[test]
+    fn pong_lag() {
+        let (mut conn, _rx) = test_conn();
+        conn.logged_in = true;
+        let sent_at = time::precise_time_ns() - 250 * 1000000;
+        conn.ping_sent = Some((Vec::from_slice(b!("LAG123")), sent_at));
+
+        // replies to someone else's PING don't count
+        recv(&mut conn, b!(":irc.example.com PONG irc.example.com :LAG999"));
+        assert!(conn.ping_sent.is_some());
+        assert_eq!(conn.lag(), None);
+
+        recv(&mut conn, b!(":irc.example.com PONG irc.example.com :LAG123"));
+        assert!(conn.ping_sent.is_none());
+        let lag = conn.lag().expect("no lag measured");
+        assert!(lag >= 250 && lag < 10000, "lag was {}", lag);
+        match conn.events.as_slice() {
+            [Lag(l)] => assert_eq!(l, lag),
+            _ => fail!("expected a Lag event")
+        }
+
+        // a late duplicate reply changes nothing
+        conn.events.clear();
+        recv(&mut conn, b!(":irc.example.com PONG irc.example.com :LAG123"));
+        assert_eq!(conn.lag(), Some(lag));
+        assert!(conn.events.is_empty());
+    }
+
+    #[test]
+    fn whox_replies() {
+        let (mut conn, _rx) = test_conn();
+        conn.logged_in = true;
+        recv(&mut conn, b!(":me!user@host JOIN #plain"));
+        assert_eq!(sent(&conn), vec!["WHO #plain".to_owned()]);
+
+        recv(&mut conn, b!(":irc.example.com 005 me WHOX :are supported by this server"));
+        recv(&mut conn, b!(":me!user@host JOIN #rust"));
+        assert_eq!(sent(&conn), vec!["WHO #rust %tcuhnfar,152".to_owned()]);
+        recv(&mut conn, b!(":bob!bob@old.example.com JOIN #rust"));
+        recv(&mut conn, b!(":irc.example.com 354 me 152 #rust ~bob bob.example.com bob G bobacct \
+                            :Bob Smith"));
+        recv(&mut conn, b!(":irc.example.com 354 me 152 #rust ~me me.example.com me H 0 :Me"));
+        // replies to queries that aren't ours have an unknown layout
+        recv(&mut conn, b!(":irc.example.com 354 me 7 #rust a b bob H other :Not Bob"));
+
+        {
+            let bob = conn.user(b!("bob")).expect("bob isn't known");
+            assert_eq!(bob.hostmask().user(), Some(b!("~bob")));
+            assert_eq!(bob.hostmask().host(), Some(b!("bob.example.com")));
+            assert_eq!(bob.account(), Some(b!("bobacct")));
+            assert_eq!(bob.realname(), Some(b!("Bob Smith")));
+            assert!(bob.is_away());
+        }
+        let me = conn.user(b!("me")).expect("we aren't known");
+        assert_eq!(me.account(), None);
+        assert_eq!(me.realname(), Some(b!("Me")));
+        assert!(!me.is_away());
+        assert_eq!(conn.me().user(), Some(b!("~me")));
+        assert_eq!(conn.me().host(), Some(b!("me.example.com")));
+    }
+
+    #[test]
+    fn names_refresh() {
+        let (mut conn, _rx) = test_conn();
+        conn.logged_in = true;
+        recv(&mut conn, b!(":me!user@host JOIN #rust"));
+        recv(&mut conn, b!(":me!user@host JOIN #other"));
+        recv(&mut conn, b!(":irc.example.com 353 me = #rust :@me bob carol"));
+        recv(&mut conn, b!(":irc.example.com 366 me #rust :End of /NAMES list."));
+        recv(&mut conn, b!(":irc.example.com 353 me = #other :me carol"));
+        recv(&mut conn, b!(":irc.example.com 366 me #other :End of /NAMES list."));
+        assert_eq!(conn.user(b!("bob")).map(|u| u.channels().len()), Some(1));
+        assert_eq!(conn.user(b!("carol")).map(|u| u.channels().len()), Some(2));
+
+        // bob and carol left #rust without us seeing it
+        recv(&mut conn, b!(":irc.example.com 353 me = #rust :@me dave"));
+        recv(&mut conn, b!(":irc.example.com 366 me #rust :End of /NAMES list."));
+        assert!(conn.user(b!("bob")).is_none());
+        {
+            let carol = conn.user(b!("carol")).expect("carol isn't known");
+            assert_eq!(carol.channels().len(), 1);
+            assert_eq!(carol.channels()[0].as_slice(), b!("#other"));
+        }
+        assert!(conn.user(b!("dave")).is_some());
+        let chan = conn.channel(b!("#rust")).expect("#rust isn't known");
+        assert_eq!(chan.member_count(), 2);
+        assert!(chan.member(b!("carol")).is_none());
     }
 }
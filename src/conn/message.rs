@@ -0,0 +1,381 @@
+//! Typed IRC messages
+
+use std::uint;
+
+use conn::{Command, IRCCmd, IRCCode, IRCAction, IRCCTCP, IRCCTCPReply, Line, Args};
+
+/// A Line's command and arguments, with the arguments checked and named
+///
+/// The prefix and tags aren't part of the Message. Look at the Line for those.
+#[deriving(Eq,Clone,Show)]
+pub enum Message {
+    /// PRIVMSG target :text
+    Privmsg(Vec<u8>, Vec<u8>),
+    /// NOTICE target :text
+    Notice(Vec<u8>, Vec<u8>),
+    /// CTCP ACTION. The args are the target and the text
+    Action(Vec<u8>, Vec<u8>),
+    /// CTCP command. The args are the target, the CTCP command, and its argument
+    Ctcp(Vec<u8>, Vec<u8>, Option<Vec<u8>>),
+    /// CTCP reply. The args are the target, the CTCP command, and its argument
+    CtcpReply(Vec<u8>, Vec<u8>, Option<Vec<u8>>),
+    /// JOIN chan1,chan2 key1,key2
+    Join(Vec<Vec<u8>>, Vec<Vec<u8>>),
+    /// JOIN chan account :realname, as sent with the extended-join capability.
+    /// An account of `*` is given as None.
+    ExtendedJoin(Vec<u8>, Option<Vec<u8>>, Vec<u8>),
+    /// PART chan1,chan2 :reason
+    Part(Vec<Vec<u8>>, Option<Vec<u8>>),
+    /// KICK chan nick :reason
+    Kick(Vec<u8>, Vec<u8>, Option<Vec<u8>>),
+    /// QUIT :reason
+    Quit(Option<Vec<u8>>),
+    /// NICK newnick
+    Nick(Vec<u8>),
+    /// MODE target modes args... The second arg holds the mode string and its arguments,
+    /// and is empty for a mode query. See `conn::modes` for parsing them.
+    Mode(Vec<u8>, Vec<Vec<u8>>),
+    /// TOPIC chan :topic. The topic is None for a query.
+    Topic(Vec<u8>, Option<Vec<u8>>),
+    /// INVITE nick chan
+    Invite(Vec<u8>, Vec<u8>),
+    /// PING server1 server2
+    Ping(Vec<u8>, Option<Vec<u8>>),
+    /// PONG server1 server2
+    Pong(Vec<u8>, Option<Vec<u8>>),
+    /// ERROR :message
+    Error(Vec<u8>),
+    /// CAP. The args are the target, the subcommand, and its params.
+    /// The target is present on lines from the server, which are the ones with a prefix.
+    Cap(Option<Vec<u8>>, Vec<u8>, Vec<Vec<u8>>),
+    /// AUTHENTICATE data
+    Authenticate(Vec<u8>),
+    /// AWAY :message. The message is None when no longer away.
+    Away(Option<Vec<u8>>),
+    /// ACCOUNT account, as sent with account-notify. An account of `*` is given as None.
+    Account(Option<Vec<u8>>),
+    /// CHGHOST user host
+    Chghost(Vec<u8>, Vec<u8>),
+    /// A numeric reply with its args
+    Reply(uint, Vec<Vec<u8>>)
+}
+
+/// Errors converting a Line into a Message
+#[deriving(Eq,Clone,Show)]
+pub enum MessageError {
+    /// The command has no Message variant
+    UnknownCommand,
+    /// The line has fewer args than the command needs. The arg is the minimum.
+    NotEnoughArgs(uint),
+    /// The line has more args than the command allows. The arg is the maximum.
+    TooManyArgs(uint)
+}
+
+impl Message {
+    /// Converts a Line into a Message. Fails if the command is unknown or
+    /// has the wrong number of args.
+    pub fn from_line(line: &Line) -> Result<Message, MessageError> {
+        let args = &line.args();
+        let cmd = match *line.command() {
+            IRCCmd(ref cmd) => cmd.as_slice(),
+            IRCCode(code) => return Ok(Reply(code, copy(args))),
+            IRCAction(ref dst) => {
+                try!(arity(args, 1, 1));
+                return Ok(Action(dst.clone(), arg(args, 0)));
+            }
+            IRCCTCP(ref cmd, ref dst) => {
+                try!(arity(args, 0, 1));
+                return Ok(Ctcp(dst.clone(), cmd.clone(), opt(args, 0)));
+            }
+            IRCCTCPReply(ref cmd, ref dst) => {
+                try!(arity(args, 0, 1));
+                return Ok(CtcpReply(dst.clone(), cmd.clone(), opt(args, 0)));
+            }
+        };
+        let msg = match cmd {
+            "PRIVMSG" => {
+                try!(arity(args, 2, 2));
+                Privmsg(arg(args, 0), arg(args, 1))
+            }
+            "NOTICE" => {
+                try!(arity(args, 2, 2));
+                Notice(arg(args, 0), arg(args, 1))
+            }
+            "JOIN" if args.len() == 3 => {
+                ExtendedJoin(arg(args, 0), account(args.get(1)), arg(args, 2))
+            }
+            "JOIN" => {
+                try!(arity(args, 1, 3));
+                let keys = if args.len() > 1 { split_list(args.get(1)) } else { Vec::new() };
+                Join(split_list(args.get(0)), keys)
+            }
+            "PART" => {
+                try!(arity(args, 1, 2));
+                Part(split_list(args.get(0)), opt(args, 1))
+            }
+            "KICK" => {
+                try!(arity(args, 2, 3));
+                Kick(arg(args, 0), arg(args, 1), opt(args, 2))
+            }
+            "QUIT" => {
+                try!(arity(args, 0, 1));
+                Quit(opt(args, 0))
+            }
+            "NICK" => {
+                try!(arity(args, 1, 1));
+                Nick(arg(args, 0))
+            }
+            "MODE" => {
+                try!(arity(args, 1, uint::MAX));
+                Mode(arg(args, 0), copy(&args.slice_from(1)))
+            }
+            "TOPIC" => {
+                try!(arity(args, 1, 2));
+                Topic(arg(args, 0), opt(args, 1))
+            }
+            "INVITE" => {
+                try!(arity(args, 2, 2));
+                Invite(arg(args, 0), arg(args, 1))
+            }
+            "PING" => {
+                try!(arity(args, 1, 2));
+                Ping(arg(args, 0), opt(args, 1))
+            }
+            "PONG" => {
+                try!(arity(args, 1, 2));
+                Pong(arg(args, 0), opt(args, 1))
+            }
+            "ERROR" => {
+                try!(arity(args, 1, 1));
+                Error(arg(args, 0))
+            }
+            "CAP" => {
+                // only lines from the server have a prefix, and they always carry
+                // a target, which may be a nick that looks like a subcommand
+                if line.prefix().is_some() {
+                    try!(arity(args, 2, uint::MAX));
+                    Cap(Some(arg(args, 0)), arg(args, 1), copy(&args.slice_from(2)))
+                } else {
+                    try!(arity(args, 1, uint::MAX));
+                    Cap(None, arg(args, 0), copy(&args.slice_from(1)))
+                }
+            }
+            "AUTHENTICATE" => {
+                try!(arity(args, 1, 1));
+                Authenticate(arg(args, 0))
+            }
+            "AWAY" => {
+                try!(arity(args, 0, 1));
+                Away(opt(args, 0))
+            }
+            "ACCOUNT" => {
+                try!(arity(args, 1, 1));
+                Account(account(args.get(0)))
+            }
+            "CHGHOST" => {
+                try!(arity(args, 2, 2));
+                Chghost(arg(args, 0), arg(args, 1))
+            }
+            _ => return Err(UnknownCommand)
+        };
+        Ok(msg)
+    }
+
+    /// Converts the Message back into a Line with no prefix or tags
+    pub fn to_line(&self) -> Line {
+        let (command, args) = match *self {
+            Privmsg(ref dst, ref text) => (cmd("PRIVMSG"), vec!(dst.clone(), text.clone())),
+            Notice(ref dst, ref text) => (cmd("NOTICE"), vec!(dst.clone(), text.clone())),
+            Action(ref dst, ref text) => (IRCAction(dst.clone()), vec!(text.clone())),
+            Ctcp(ref dst, ref ctcp, ref arg) => {
+                (IRCCTCP(ctcp.clone(), dst.clone()), arg.iter().map(|a| a.clone()).collect())
+            }
+            CtcpReply(ref dst, ref ctcp, ref arg) => {
+                (IRCCTCPReply(ctcp.clone(), dst.clone()), arg.iter().map(|a| a.clone()).collect())
+            }
+            Join(ref chans, ref keys) => {
+                let mut args = vec!(join_list(chans.as_slice()));
+                if !keys.is_empty() {
+                    args.push(join_list(keys.as_slice()));
+                }
+                (cmd("JOIN"), args)
+            }
+            ExtendedJoin(ref chan, ref acct, ref realname) => {
+                let acct = match *acct {
+                    Some(ref acct) => acct.clone(),
+                    None => Vec::from_slice(bytes!("*"))
+                };
+                (cmd("JOIN"), vec!(chan.clone(), acct, realname.clone()))
+            }
+            Part(ref chans, ref reason) => {
+                (cmd("PART"), with_opt(vec!(join_list(chans.as_slice())), reason))
+            }
+            Kick(ref chan, ref nick, ref reason) => {
+                (cmd("KICK"), with_opt(vec!(chan.clone(), nick.clone()), reason))
+            }
+            Quit(ref reason) => (cmd("QUIT"), with_opt(Vec::new(), reason)),
+            Nick(ref nick) => (cmd("NICK"), vec!(nick.clone())),
+            Mode(ref target, ref modes) => {
+                (cmd("MODE"), vec!(target.clone()).append(modes.as_slice()))
+            }
+            Topic(ref chan, ref topic) => (cmd("TOPIC"), with_opt(vec!(chan.clone()), topic)),
+            Invite(ref nick, ref chan) => (cmd("INVITE"), vec!(nick.clone(), chan.clone())),
+            Ping(ref server, ref server2) => (cmd("PING"), with_opt(vec!(server.clone()), server2)),
+            Pong(ref server, ref server2) => (cmd("PONG"), with_opt(vec!(server.clone()), server2)),
+            Error(ref msg) => (cmd("ERROR"), vec!(msg.clone())),
+            Cap(ref target, ref sub, ref params) => {
+                let args = with_opt(Vec::new(), target).append_one(sub.clone());
+                (cmd("CAP"), args.append(params.as_slice()))
+            }
+            Authenticate(ref data) => (cmd("AUTHENTICATE"), vec!(data.clone())),
+            Away(ref msg) => (cmd("AWAY"), with_opt(Vec::new(), msg)),
+            Account(ref acct) => {
+                let acct = match *acct {
+                    Some(ref acct) => acct.clone(),
+                    None => Vec::from_slice(bytes!("*"))
+                };
+                (cmd("ACCOUNT"), vec!(acct))
+            }
+            Chghost(ref user, ref host) => (cmd("CHGHOST"), vec!(user.clone(), host.clone())),
+            Reply(code, ref args) => (IRCCode(code), args.clone())
+        };
+        Line::new(Vec::new(), None, command, args.as_slice())
+    }
+}
+
+fn arity(args: &Args, min: uint, max: uint) -> Result<(), MessageError> {
+    if args.len() < min {
+        Err(NotEnoughArgs(min))
+    } else if args.len() > max {
+        Err(TooManyArgs(max))
+    } else {
+        Ok(())
+    }
+}
+
+fn arg(args: &Args, idx: uint) -> Vec<u8> {
+    Vec::from_slice(args.get(idx))
+}
+
+fn opt(args: &Args, idx: uint) -> Option<Vec<u8>> {
+    if idx < args.len() { Some(arg(args, idx)) } else { None }
+}
+
+fn copy(args: &Args) -> Vec<Vec<u8>> {
+    args.iter().map(|a| Vec::from_slice(a)).collect()
+}
+
+fn with_opt(mut args: Vec<Vec<u8>>, arg: &Option<Vec<u8>>) -> Vec<Vec<u8>> {
+    match *arg {
+        Some(ref arg) => args.push(arg.clone()),
+        None => ()
+    }
+    args
+}
+
+fn account(acct: &[u8]) -> Option<Vec<u8>> {
+    if acct == bytes!("*") { None } else { Some(Vec::from_slice(acct)) }
+}
+
+fn split_list(list: &[u8]) -> Vec<Vec<u8>> {
+    list.split(|&b| b == ',' as u8).filter(|v| !v.is_empty()).map(|v| Vec::from_slice(v)).collect()
+}
+
+fn join_list(list: &[Vec<u8>]) -> Vec<u8> {
+    let mut res = Vec::new();
+    for (i, item) in list.iter().enumerate() {
+        if i != 0 {
+            res.push(',' as u8);
+        }
+        res.push_all(item.as_slice());
+    }
+    res
+}
+
+fn cmd(name: &'static str) -> Command {
+    IRCCmd(name.into_maybe_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Message, Privmsg, Action, Ctcp, Join, ExtendedJoin, Part, Kick, Quit, Mode,
+                Cap, Account, Reply, NotEnoughArgs, TooManyArgs, UnknownCommand};
+    use conn::Line;
+
+    macro_rules! b(
+        ($args:tt) => (
+            { static b: &'static [u8] = bytes!($args); b }
+        )
+    )
+
+    fn v(s: &[u8]) -> Vec<u8> {
+        Vec::from_slice(s)
+    }
+
+    fn msg(s: &[u8]) -> Message {
+        Message::from_line(&Line::parse(s).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_from_line() {
+        assert_eq!(msg(b!(":bob!b@host PRIVMSG #rust :hello there")),
+                   Privmsg(v(b!("#rust")), v(b!("hello there"))));
+        assert_eq!(msg(b!(":bob!b@host PRIVMSG #rust :\x01ACTION waves\x01")),
+                   Action(v(b!("#rust")), v(b!("waves"))));
+        assert_eq!(msg(b!(":bob!b@host PRIVMSG me :\x01VERSION\x01")),
+                   Ctcp(v(b!("me")), v(b!("VERSION")), None));
+        assert_eq!(msg(b!("JOIN #a,#b,&c key1,key2")),
+                   Join(vec!(v(b!("#a")), v(b!("#b")), v(b!("&c"))),
+                        vec!(v(b!("key1")), v(b!("key2")))));
+        assert_eq!(msg(b!(":bob!b@host JOIN #rust")), Join(vec!(v(b!("#rust"))), vec!()));
+        assert_eq!(msg(b!(":bob!b@host JOIN #rust * :Bob Smith")),
+                   ExtendedJoin(v(b!("#rust")), None, v(b!("Bob Smith"))));
+        assert_eq!(msg(b!(":bob!b@host PART #a,#b")), Part(vec!(v(b!("#a")), v(b!("#b"))), None));
+        assert_eq!(msg(b!(":op!o@host KICK #rust bob :bye")),
+                   Kick(v(b!("#rust")), v(b!("bob")), Some(v(b!("bye")))));
+        assert_eq!(msg(b!(":bob!b@host QUIT")), Quit(None));
+        assert_eq!(msg(b!(":bob!b@host MODE #rust +o-v alice bob")),
+                   Mode(v(b!("#rust")), vec!(v(b!("+o-v")), v(b!("alice")), v(b!("bob")))));
+        assert_eq!(msg(b!(":server CAP * LS :multi-prefix sasl")),
+                   Cap(Some(v(b!("*"))), v(b!("LS")), vec!(v(b!("multi-prefix sasl")))));
+        assert_eq!(msg(b!("CAP REQ :sasl")), Cap(None, v(b!("REQ")), vec!(v(b!("sasl")))));
+        // a nick that looks like a subcommand is still the target
+        assert_eq!(msg(b!(":server CAP END ACK :sasl")),
+                   Cap(Some(v(b!("END"))), v(b!("ACK")), vec!(v(b!("sasl")))));
+        assert_eq!(msg(b!(":bob!b@host ACCOUNT *")), Account(None));
+        assert_eq!(msg(b!(":server 001 me :Welcome")),
+                   Reply(1, vec!(v(b!("me")), v(b!("Welcome")))));
+    }
+
+    #[test]
+    fn test_arity() {
+        let t = |s: &[u8]| Message::from_line(&Line::parse(s).unwrap());
+        assert_eq!(t(b!(":bob!b@host PRIVMSG #rust")), Err(NotEnoughArgs(2)));
+        assert_eq!(t(b!(":bob!b@host PRIVMSG #rust extra :text")), Err(TooManyArgs(2)));
+        assert_eq!(t(b!(":op!o@host KICK #rust")), Err(NotEnoughArgs(2)));
+        assert_eq!(t(b!("NICK")), Err(NotEnoughArgs(1)));
+        assert_eq!(t(b!("PING")), Err(NotEnoughArgs(1)));
+        assert_eq!(t(b!(":server CAP *")), Err(NotEnoughArgs(2)));
+        assert_eq!(t(b!("FROB a b")), Err(UnknownCommand));
+    }
+
+    #[test]
+    fn test_to_line() {
+        let lines = [b!("PRIVMSG #rust :hello there"), b!("PRIVMSG #rust :\x01ACTION waves\x01"),
+                     b!("NOTICE bob :\x01VERSION irclib\x01"), b!("JOIN #a,#b key"),
+                     b!("JOIN #rust * :Bob Smith"), b!("PART #a,#b :bye now"),
+                     b!("KICK #rust bob"), b!("QUIT"), b!("NICK bob"),
+                     b!("MODE #rust +o-v alice bob"), b!("MODE #rust"), b!("TOPIC #rust"),
+                     b!("INVITE bob #rust"), b!("PING irc.example.com"),
+                     b!("PONG me irc.example.com"), b!("ERROR :Closing link"),
+                     b!("CAP * ACK sasl"), b!("CAP END"), b!("AUTHENTICATE +"),
+                     b!("AWAY :gone fishing"), b!("ACCOUNT bob"), b!("CHGHOST bob new.host"),
+                     b!("001 me :Welcome to IRC")];
+        for &raw in lines.iter() {
+            let line = Line::parse(raw).unwrap();
+            let msg = Message::from_line(&line).unwrap();
+            assert_eq!(msg.to_line(), line);
+            assert_eq!(msg.to_line().to_raw().as_slice(), raw);
+        }
+    }
+}
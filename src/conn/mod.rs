@@ -4,15 +4,34 @@ use std::fmt;
 use std::io;
 use std::io::{IoError, IoResult, TcpStream};
 use std::io::BufferedStream;
-use std::{char,str,uint};
+use std::{char,slice,str,uint};
 use std::slice::MutableCloneableVector;
 use std::str::MaybeOwned;
 use std::cmp::min;
 use std::comm;
+use std::mem;
+use std::io::timer;
+use std::io::timer::Timer;
 use std::task::TaskBuilder;
-use User;
+use collections::{RingBuf, Deque};
+use sync::{Arc, Mutex};
+use rand;
+use rand::Rng;
+use time;
+use {User, Prefix, PrefixRef};
+use casemap::Rfc1459;
+use conn::builder::{LineBuilder, BuildError, TooLong, split_text};
+use conn::tls::{NetStream, PlainStream};
 
+pub mod builder;
 mod handlers;
+pub mod message;
+pub mod modes;
+pub mod numeric;
+pub mod sasl;
+pub mod state;
+pub mod support;
+pub mod tls;
 
 /// Conn represenets a connection to a single IRC server
 ///
@@ -21,10 +40,37 @@ mod handlers;
 /// extra data for your handler to use. It is completely ignored by this
 /// library otherwise.
 pub struct Conn<'a> {
-    host: &'a str,
-    write_tx: Option<Sender<~[u8]>>,
+    host: String,
+    port: u16,
+    write_tx: Option<Sender<()>>,
+    queue: Arc<Mutex<WriteQueue>>,
     logged_in: bool,
     user: User,
+    umodes: Vec<char>,
+    caps_requested: Vec<&'a str>,
+    caps_available: Vec<(String, Option<String>)>,
+    caps_enabled: Vec<String>,
+    cap_negotiating: bool,
+    cap_pending: uint,
+    sasl: Option<sasl::Credentials<'a>>,
+    sasl_session: Option<sasl::Session>,
+    sasl_done: bool,
+    account: Option<Vec<u8>>,
+    error: Option<Error>,
+    fingerprint: Option<Vec<u8>>,
+    wanted_nick: Vec<u8>,
+    quitting: bool,
+    reconnecting: bool,
+    channels: Vec<state::Channel>,
+    users: state::Users,
+    support: support::ServerSupport,
+    chan_keys: Vec<(Vec<u8>, Vec<u8>)>,
+    rejoin: Vec<Vec<u8>>,
+    redirect: Option<(String, u16)>,
+    events: Vec<Event>,
+    last_recv: u64,
+    ping_sent: Option<(Vec<u8>, u64)>,
+    lag: Option<u64>,
 }
 
 /// Options used with Conn for connecting to the server.
@@ -36,12 +82,46 @@ pub struct Options<'a, Payload=()> {
     pub host: &'a str,
     /// The server port to connect to
     pub port: u16,
+    /// TLS settings. If None, the connection is unencrypted.
+    /// These settings are also used for any server in `servers` that has `tls` set,
+    /// except for `sni`, which only applies to `host`.
+    pub tls: Option<tls::Config<'a>>,
+    /// The server password to send with PASS, if any
+    pub password: Option<&'a str>,
+    /// Additional servers to fail over to, in order, if `host` can't be reached or
+    /// refuses registration. After the last server, the list wraps back to `host`.
+    pub servers: Vec<Server<'a>>,
     /// The nickname to use
     pub nick: &'a str,
     /// The username to use
     pub user: &'a str,
     /// The real name to use
     pub real: &'a str,
+    /// IRCv3 capabilities to request from the server.
+    /// Capabilities the server does not advertise are silently skipped.
+    /// If this is empty, no capability negotiation is performed.
+    pub caps: &'a [&'a str],
+    /// SASL credentials to authenticate with before registration completes.
+    /// The "sasl" capability is requested automatically. If authentication fails,
+    /// or the server doesn't support it, connect() returns ErrSasl.
+    pub sasl: Option<sasl::Credentials<'a>>,
+    /// How long the connection may be silent before we send a PING, in milliseconds.
+    /// If None (the default), we only answer the server's PINGs, and a dead connection
+    /// may go unnoticed until the OS gives up on it. Two minutes is a reasonable interval.
+    /// Keepalive PINGs, and so Lag events, only start once we've registered.
+    pub ping_interval: Option<u64>,
+    /// How long to wait for a reply to our PING before giving up with ErrPingTimeout,
+    /// in milliseconds
+    pub ping_timeout: u64,
+    /// Outgoing flood control. If None, lines are written as fast as possible.
+    pub throttle: Option<Throttle>,
+    /// Policy for automatically reconnecting when the connection drops.
+    /// If None, connect() returns as soon as the connection terminates.
+    pub reconnect: Option<Reconnect>,
+    /// Accept lines whose command is neither alphabetic nor a 3-digit numeric,
+    /// as with Line::parse_lenient. Lines that still can't be parsed are sent
+    /// as UnparseableLine events, except for blank lines, which are skipped.
+    pub lenient: bool,
     /// A Port to send procs to.
     /// The Port will be closed when connect() returns.
     /// Any proc sent to this port will be executed on the connection's task,
@@ -61,14 +141,275 @@ impl<'a, Payload> Options<'a, Payload> {
         Options {
             host: host,
             port: port,
+            tls: None,
+            password: None,
+            servers: Vec::new(),
             nick: "ircnick",
             user: "ircuser",
             real: "rust-irclib user",
+            caps: &[],
+            sasl: None,
+            ping_interval: None,
+            ping_timeout: 60 * 1000,
+            throttle: None,
+            reconnect: None,
+            lenient: false,
             commands: None
         }
     }
 }
 
+/// A server to connect to, used for failover
+pub struct Server<'a> {
+    /// The server host to connect to
+    pub host: &'a str,
+    /// The server port to connect to
+    pub port: u16,
+    /// Whether to use TLS. The settings in `Options.tls` are used if present,
+    /// otherwise the default settings are. Either way, the certificate is verified
+    /// against this server's own host.
+    pub tls: bool,
+    /// The server password to send with PASS, if any
+    pub password: Option<&'a str>,
+}
+
+impl<'a> Server<'a> {
+    /// Returns a new Server with no TLS and no password
+    pub fn new(host: &'a str, port: u16) -> Server<'a> {
+        #![inline]
+        Server {
+            host: host,
+            port: port,
+            tls: false,
+            password: None
+        }
+    }
+}
+
+/// The servers to connect to, and which of them to try next
+struct Failover<'a> {
+    servers: Vec<Server<'a>>,
+    /// The index of the current server
+    idx: uint,
+    /// The number of servers that have failed in a row
+    failed: uint,
+}
+
+impl<'a> Failover<'a> {
+    fn new(servers: Vec<Server<'a>>) -> Failover<'a> {
+        Failover {
+            servers: servers,
+            idx: 0,
+            failed: 0
+        }
+    }
+
+    /// Returns the server to connect to
+    fn current(&self) -> Server<'a> {
+        *self.servers.get(self.idx)
+    }
+
+    /// Moves on to the next server after the current one failed.
+    /// Returns `false` once every server has failed in a row.
+    fn next(&mut self) -> bool {
+        self.failed += 1;
+        self.idx = (self.idx + 1) % self.servers.len();
+        self.failed < self.servers.len()
+    }
+
+    /// Starts counting failures again, after a successful connection or a backoff
+    fn new_round(&mut self) {
+        self.failed = 0;
+    }
+
+    /// Returns the TLS settings for the current server, if it uses TLS.
+    /// The SNI name in `cfg` is only used for the primary server, and not when
+    /// we were redirected elsewhere.
+    fn tls(&self, cfg: Option<&tls::Config<'a>>, redirected: bool) -> Option<tls::Config<'a>> {
+        if !self.current().tls {
+            return None;
+        }
+        let mut cfg = cfg.map_or(tls::Config::new(), |c| *c);
+        if self.idx != 0 || redirected {
+            cfg.sni = None;
+        }
+        Some(cfg)
+    }
+}
+
+/// Outgoing flood control, using the RFC 1459 penalty scheme.
+///
+/// Every line sent advances a penalty clock by `penalty` milliseconds (the clock is
+/// never allowed to fall behind the current time). A line is written immediately as long
+/// as that leaves the clock no more than `window` milliseconds ahead of the current time;
+/// otherwise the writer waits until it would.
+pub struct Throttle {
+    /// The penalty for each line, in milliseconds
+    pub penalty: u64,
+    /// How far the penalty clock may run ahead of the current time, in milliseconds
+    pub window: u64,
+}
+
+impl Throttle {
+    /// Returns a new Throttle with the RFC 1459 values of 2 seconds per line
+    /// and a 10 second window
+    pub fn new() -> Throttle {
+        #![inline]
+        Throttle {
+            penalty: 2000,
+            window: 10000
+        }
+    }
+
+    /// Advances the penalty clock for a line sent at `now` (in milliseconds) and
+    /// returns how long to wait before sending it. Urgent lines still count towards
+    /// the penalty but are never delayed.
+    fn wait(&self, clock: &mut u64, now: u64, urgent: bool) -> u64 {
+        if *clock < now {
+            *clock = now;
+        }
+        let ahead = *clock - now + self.penalty;
+        *clock += self.penalty;
+        if urgent || ahead <= self.window {
+            0
+        } else {
+            ahead - self.window
+        }
+    }
+
+    /// Returns how long a line that isn't urgent would have to wait if sent at `now`,
+    /// without advancing the clock
+    fn delay(&self, clock: u64, now: u64) -> u64 {
+        let mut clock = clock;
+        self.wait(&mut clock, now, false)
+    }
+}
+
+/// The priority of an outgoing line.
+///
+/// Pending lines are written highest priority first, and in order within a priority.
+#[deriving(Eq,Clone,Show)]
+pub enum Priority {
+    /// Bulk traffic. Pending low-priority lines can be dropped with `Conn::clear_queue()`.
+    LowPriority,
+    /// Ordinary traffic, such as PRIVMSG
+    NormalPriority,
+    /// Control traffic, such as PONG and QUIT.
+    /// High-priority lines are not delayed by flood control.
+    HighPriority
+}
+
+impl Priority {
+    /// Returns the priority used by `send_command()` for the given command.
+    /// Registration and connection-management commands are HighPriority,
+    /// everything else is NormalPriority.
+    pub fn for_command(cmd: &Command) -> Priority {
+        match *cmd {
+            IRCCmd(ref s) => match s.as_slice() {
+                "PONG" | "PING" | "QUIT" | "NICK" | "CAP" | "AUTHENTICATE" | "PASS" | "USER" => {
+                    HighPriority
+                }
+                _ => NormalPriority
+            },
+            _ => NormalPriority
+        }
+    }
+}
+
+/// Lines waiting to be written, with one lane per Priority
+struct WriteQueue {
+    lanes: Vec<RingBuf<~[u8]>>
+}
+
+impl WriteQueue {
+    fn new() -> WriteQueue {
+        WriteQueue { lanes: Vec::from_fn(3, |_| RingBuf::new()) }
+    }
+
+    fn push(&mut self, line: ~[u8], priority: Priority) {
+        self.lanes.get_mut(priority as uint).push_back(line);
+    }
+
+    fn pop(&mut self) -> Option<(~[u8], Priority)> {
+        for &priority in [HighPriority, NormalPriority, LowPriority].iter() {
+            match self.lanes.get_mut(priority as uint).pop_front() {
+                None => (),
+                Some(line) => return Some((line, priority))
+            }
+        }
+        None
+    }
+
+    /// Returns the priority of the line that `pop()` would return
+    fn peek_priority(&self) -> Option<Priority> {
+        [HighPriority, NormalPriority, LowPriority].iter().map(|&p| p)
+            .find(|&p| !self.lanes.get(p as uint).is_empty())
+    }
+
+    fn len(&self) -> uint {
+        self.lanes.iter().fold(0, |n, lane| n + lane.len())
+    }
+
+    /// Drops every line with a priority of `max` or lower. Returns the number dropped.
+    fn clear(&mut self, max: Priority) -> uint {
+        let mut count = 0;
+        for lane in self.lanes.mut_slice_to(max as uint + 1).mut_iter() {
+            count += lane.len();
+            lane.clear();
+        }
+        count
+    }
+}
+
+/// Maximum number of consecutive RPL_BOUNCE redirects to follow
+static MaxRedirects: uint = 5;
+
+/// Policy for automatic reconnection.
+///
+/// The delay doubles after each failed attempt, up to `max_delay`, and a random
+/// jitter of up to half the delay is applied so many clients don't reconnect in lockstep.
+/// After reconnecting, the channels that were joined are rejoined (with their keys),
+/// and the nick that was last asked for is restored.
+pub struct Reconnect {
+    /// The delay before the first attempt, in milliseconds
+    pub initial_delay: u64,
+    /// The maximum delay between attempts, in milliseconds
+    pub max_delay: u64,
+    /// The maximum number of consecutive failed attempts before giving up.
+    /// If None, reconnection is attempted forever.
+    pub max_attempts: Option<uint>,
+}
+
+impl Reconnect {
+    /// Returns a new Reconnect policy with default values
+    pub fn new() -> Reconnect {
+        #![inline]
+        Reconnect {
+            initial_delay: 1000,
+            max_delay: 5 * 60 * 1000,
+            max_attempts: None
+        }
+    }
+
+    /// Returns the delay before the given attempt (starting at 0),
+    /// or None if no more attempts should be made.
+    fn delay(&self, attempt: uint) -> Option<u64> {
+        if self.max_attempts.map_or(false, |max| attempt >= max) {
+            return None;
+        }
+        let mut delay = min(self.initial_delay, self.max_delay);
+        for _ in range(0, attempt) {
+            if delay >= self.max_delay / 2 {
+                delay = self.max_delay;
+                break;
+            }
+            delay *= 2;
+        }
+        let half = delay / 2;
+        Some(delay - half + rand::task_rng().gen_range(0, half + 1))
+    }
+}
+
 /// Typedef for commands that can be sent to the commands Port
 pub type Cmd<Payload=()> = proc(&mut Conn, &mut Payload) : Send;
 
@@ -81,22 +422,42 @@ pub enum Event {
     /// The first received line should be 001
     LineReceived(Line),
     /// The connection has terminated
-    Disconnected
+    Disconnected,
+    /// The round-trip time to the server was measured, in milliseconds.
+    /// This is sent whenever the server replies to one of our keepalive PINGs.
+    Lag(u64),
+    /// The connection is about to be re-established.
+    /// The first arg is the attempt number (starting at 1), the second is the delay
+    /// in milliseconds before the attempt is made. Procs sent to `Options.commands`
+    /// still run during the delay, and quitting from one cancels the reconnect.
+    Reconnecting(uint, u64),
+    /// A line was received that couldn't be parsed. The args are the raw line and
+    /// the reason. Unlike LineReceived, this may be sent before logging in.
+    UnparseableLine(Vec<u8>, ParseError)
 }
 
 /// Errors that can be returned from connect()
 pub enum Error {
     /// Error connecting to server
     ErrConnect(IoError),
+    /// Error establishing a TLS session with the server
+    ErrTls(tls::TlsError),
     /// I/O error raised while connection is active
-    ErrIO(IoError)
+    ErrIO(IoError),
+    /// SASL authentication failed
+    ErrSasl(sasl::SaslError),
+    /// The server stopped responding to our PINGs
+    ErrPingTimeout
 }
 
 impl fmt::Show for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             ErrConnect(ref err) => { write!(f, "connect error: {}", *err) }
-            ErrIO(ref err) => err.fmt(f)
+            ErrTls(ref err) => err.fmt(f),
+            ErrIO(ref err) => err.fmt(f),
+            ErrSasl(ref err) => err.fmt(f),
+            ErrPingTimeout => write!(f, "ping timeout")
         }
     }
 }
@@ -106,69 +467,406 @@ pub type Result = ::std::result::Result<(),Error>;
 
 pub static DefaultPort: u16 = 6667;
 
+pub static DefaultTlsPort: u16 = 6697;
+
 /// Connects to the remote server. This method will not return until the connection
 /// is terminated. Returns Ok(()) after connection termination if the connection was
 /// established successfully, or Err(_) if the connection could not be established in the
 /// first place, or if an error is thrown while the connection is active.
 ///
+/// If `opts.reconnect` is set, a dropped connection is re-established instead, and
+/// this method only returns once the user quits, a fatal error (e.g. a SASL failure)
+/// occurs, or the reconnect attempts are exhausted.
+///
 /// This method spawns some I/O-blocked tasks, so it is recommended that it be called
 /// from a libgreen task.
 ///
 /// Note: If your Conn has no payload, you should pass () as the payload parameter.
-pub fn connect<Payload>(opts: Options<Payload>, mut payload: Payload,
+pub fn connect<Payload>(mut opts: Options<Payload>, mut payload: Payload,
                         cb: |&mut Conn, Event, &mut Payload|) -> Result {
-    let stream = match TcpStream::connect(opts.host, opts.port) {
-        Err(e) => return Err(ErrConnect(e)),
-        Ok(stream) => stream
-    };
+    let mut caps = Vec::from_slice(opts.caps);
+    if opts.sasl.is_some() && !caps.contains(&"sasl") {
+        caps.push("sasl");
+    }
 
-    let mut conn = Conn{
+    let mut servers = vec![Server{
         host: opts.host,
-        write_tx: None,
-        logged_in: false,
-        user: User::new(opts.nick.as_bytes(), Some(opts.user.as_bytes()), None),
-    };
+        port: opts.port,
+        tls: opts.tls.is_some(),
+        password: opts.password
+    }];
+    servers.push_all(opts.servers.as_slice());
+    let mut failover = Failover::new(servers);
+
+    let mut conn = Conn::new(opts.host, opts.port, opts.nick.as_bytes(), opts.user.as_bytes(),
+                             caps, opts.sasl);
+
+    let mut redirects = 0u;
+    let mut redirect = None;
+    let mut attempts = 0u;
+    let mut res;
+    let mut connected;
+    loop {
+        conn.reset();
+        connected = false;
+        let server = failover.current();
+        let tls = failover.tls(opts.tls.as_ref(), redirect.is_some());
+        let (host, port) = match redirect.take() {
+            None => (server.host.to_owned(), server.port),
+            Some(target) => target
+        };
+        conn.host = host;
+        conn.port = port;
+        res = match open_stream(conn.host.as_slice(), port, tls.as_ref()) {
+            Err(e) => Err(e),
+            Ok(mut stream) => {
+                connected = true;
+                conn.fingerprint = stream.peer_fingerprint();
+                cb(&mut conn, Connected, &mut payload);
+                let res = conn.run(stream.clone(), &opts, server.password, &mut payload,
+                                   |c,e,p| cb(c,e,p));
+                // make sure the reader task isn't left blocked on the socket
+                stream.shutdown();
+                res
+            }
+        };
+
+        // follow RPL_BOUNCE right away
+        match conn.redirect.take() {
+            Some(target) if redirects < MaxRedirects && conn.can_fail_over(&res) => {
+                redirects += 1;
+                redirect = Some(target);
+                if connected {
+                    cb(&mut conn, Disconnected, &mut payload);
+                }
+                continue;
+            }
+            _ => ()
+        }
+        redirects = 0;
+
+        if conn.logged_in {
+            failover.new_round();
+            attempts = 0;
+        } else {
+            // connect or registration failed, so move on to the next server.
+            // Only back off once every server has failed.
+            if failover.next() && conn.can_fail_over(&res) {
+                if connected {
+                    cb(&mut conn, Disconnected, &mut payload);
+                }
+                continue;
+            }
+            failover.new_round();
+        }
+
+        let delay = match opts.reconnect {
+            Some(ref policy) if conn.should_reconnect(&res) => policy.delay(attempts),
+            _ => None
+        };
+        match delay {
+            None => break,
+            Some(delay) => {
+                if connected {
+                    cb(&mut conn, Disconnected, &mut payload);
+                }
+                attempts += 1;
+                conn.reconnecting = true;
+                cb(&mut conn, Reconnecting(attempts, delay), &mut payload);
+                match wait_to_reconnect(&mut conn, delay, opts.commands.as_ref(), &mut payload) {
+                    Ok(true) => (),
+                    Ok(false) => {
+                        // quit while waiting, so we're done
+                        res = Ok(());
+                        connected = false;
+                        break;
+                    }
+                    Err(e) => {
+                        res = Err(ErrIO(e));
+                        connected = false;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    // close the commands port, and then run any buffered procs
+    match opts.commands.take() {
+        None => (),
+        Some(port) => {
+            let mut procs = Vec::new();
+            loop {
+                match port.try_recv() {
+                    Err(_) => break,
+                    Ok(cmd) => procs.push(cmd)
+                }
+            }
+            drop(port);
+            for cmd in procs.move_iter() {
+                cmd(&mut conn, &mut payload);
+            }
+        }
+    }
+
+    if connected {
+        cb(&mut conn, Disconnected, &mut payload);
+    }
+
+    res
+}
 
-    cb(&mut conn, Connected, &mut payload);
+/// Waits `delay` ms before reconnecting, running any procs sent to `commands` meanwhile.
+/// Returns `false` if one of them quit, in which case we shouldn't reconnect.
+fn wait_to_reconnect<Payload>(conn: &mut Conn, delay: u64,
+                              commands: Option<&Receiver<Cmd<Payload>>>,
+                              payload: &mut Payload) -> IoResult<bool> {
+    let mut timer = try!(Timer::new());
+    let done = timer.oneshot(delay);
+    let commands = match commands {
+        None => {
+            done.recv();
+            return Ok(true);
+        }
+        Some(port) => port
+    };
+    let select = comm::Select::new();
+    let mut done_handle = select.handle(&done);
+    unsafe { done_handle.add() }
+    let mut cmd_handle = select.handle(commands);
+    unsafe { cmd_handle.add() }
+    loop {
+        if select.wait() == done_handle.id() {
+            done_handle.recv();
+            return Ok(true);
+        }
+        match cmd_handle.recv_opt() {
+            Err(()) => {
+                // nobody can send us commands any more, so just wait
+                unsafe { cmd_handle.remove() }
+                done_handle.recv();
+                return Ok(true);
+            }
+            Ok(cmd) => {
+                cmd(conn, payload);
+                if conn.quitting {
+                    return Ok(false);
+                }
+            }
+        }
+    }
+}
 
-    let res = conn.run(stream, opts, &mut payload, |c,e,p| cb(c,e,p));
+/// Writes queued lines to the stream, taking one line off the queue for each wakeup,
+/// until the wakeups stop.
+fn write_lines<W: Writer>(stream: &mut W, wakeups: Receiver<()>, queue: Arc<Mutex<WriteQueue>>,
+                          throttle: Option<Throttle>) -> IoResult<()> {
+    write_lines_with(stream, wakeups, queue, throttle,
+                     || time::precise_time_ns() / 1000000, |ms| timer::sleep(ms))
+}
 
-    cb(&mut conn, Disconnected, &mut payload);
+/// The body of `write_lines()`, with the clock (in milliseconds) and sleep supplied
+/// by the caller so the throttle can be tested without real delays
+fn write_lines_with<W: Writer>(stream: &mut W, wakeups: Receiver<()>,
+                               queue: Arc<Mutex<WriteQueue>>, throttle: Option<Throttle>,
+                               now: || -> u64, sleep: |u64|) -> IoResult<()> {
+    let mut clock = 0u64;
+    loop {
+        match wakeups.recv_opt() {
+            Err(_) => return Ok(()),
+            Ok(()) => ()
+        }
+        // wait out the throttle before choosing the line, so anything urgent
+        // that's queued in the meantime still goes first
+        match throttle {
+            None => (),
+            Some(ref throttle) => {
+                let urgent = queue.lock().peek_priority() == Some(HighPriority);
+                let wait = if urgent { 0 } else { throttle.delay(clock, now()) };
+                if wait > 0 {
+                    sleep(wait);
+                }
+            }
+        }
+        let (line, priority) = match queue.lock().pop() {
+            // the line was dropped by clear_queue()
+            None => continue,
+            Some(v) => v
+        };
+        match throttle {
+            None => (),
+            Some(ref throttle) => {
+                let wait = throttle.wait(&mut clock, now(), priority == HighPriority);
+                if wait > 0 {
+                    sleep(wait);
+                }
+            }
+        }
+        try!(stream.write(line).and_then(|_| stream.flush()));
+    }
+}
 
-    match res {
-        Err(e) => Err(ErrIO(e)),
-        Ok(()) => Ok(())
+fn open_stream(host: &str, port: u16, tls: Option<&tls::Config>)
+              -> ::std::result::Result<NetStream, Error> {
+    let stream = match TcpStream::connect(host, port) {
+        Err(e) => return Err(ErrConnect(e)),
+        Ok(stream) => stream
+    };
+    match tls {
+        None => Ok(PlainStream(stream)),
+        Some(cfg) => tls::wrap(stream, host, cfg).map_err(|e| ErrTls(e))
     }
 }
 
 impl<'a> Conn<'a> {
-    fn run<Payload>(&mut self, stream: TcpStream, opts: Options<Payload>, payload: &mut Payload,
-                    cb: |&mut Conn, Event, &mut Payload|) -> IoResult<()> {
+    /// Returns a Conn that hasn't connected yet
+    fn new(host: &str, port: u16, nick: &[u8], user: &[u8], caps: Vec<&'a str>,
+           sasl: Option<sasl::Credentials<'a>>) -> Conn<'a> {
+        Conn {
+            host: host.to_owned(),
+            port: port,
+            write_tx: None,
+            queue: Arc::new(Mutex::new(WriteQueue::new())),
+            logged_in: false,
+            umodes: Vec::new(),
+            user: User::new(nick, Some(user), None),
+            caps_requested: caps,
+            caps_available: Vec::new(),
+            caps_enabled: Vec::new(),
+            cap_negotiating: false,
+            cap_pending: 0,
+            sasl: sasl,
+            sasl_session: None,
+            sasl_done: false,
+            account: None,
+            error: None,
+            fingerprint: None,
+            wanted_nick: Vec::from_slice(nick),
+            quitting: false,
+            reconnecting: false,
+            channels: Vec::new(),
+            users: state::Users::new(Rfc1459),
+            support: support::ServerSupport::new(),
+            chan_keys: Vec::new(),
+            rejoin: Vec::new(),
+            redirect: None,
+            events: Vec::new(),
+            last_recv: 0,
+            ping_sent: None,
+            lag: None,
+        }
+    }
+
+    /// Resets the per-connection state in preparation for a new connection
+    fn reset(&mut self) {
+        self.logged_in = false;
+        self.umodes.clear();
+        let user = User::new(self.wanted_nick.as_slice(), self.user.user(), None);
+        self.user = user;
+        self.caps_available.clear();
+        self.caps_enabled.clear();
+        self.cap_negotiating = false;
+        self.cap_pending = 0;
+        self.sasl_session = None;
+        self.sasl_done = false;
+        self.account = None;
+        self.error = None;
+        self.fingerprint = None;
+        self.redirect = None;
+        self.events.clear();
+        self.ping_sent = None;
+        self.lag = None;
+        self.support = support::ServerSupport::new();
+        self.users.clear();
+        self.users.set_casemapping(self.support.casemapping());
+        // remember the channels we were in so we can rejoin them after registering
+        let channels = mem::replace(&mut self.channels, Vec::new());
+        for chan in channels.move_iter() {
+            let name = Vec::from_slice(chan.name());
+            if !self.rejoin.contains(&name) {
+                self.rejoin.push(name);
+            }
+        }
+    }
+
+    /// Returns `true` if `nick` is our nick, under the server's casemapping
+    fn is_me(&self, nick: &[u8]) -> bool {
+        self.user.is_nick(nick, self.support.casemapping())
+    }
+
+    /// Records the username and host the server shows for us
+    fn set_my_host(&mut self, user: &[u8], host: &[u8]) {
+        if user.is_empty() || host.is_empty() {
+            return;
+        }
+        self.user = User::new(self.user.nick(), Some(user), Some(host));
+    }
+
+    /// Returns `true` if it's worth trying another server after this result
+    fn can_fail_over(&self, res: &Result) -> bool {
+        if self.quitting {
+            return false;
+        }
+        match *res {
+            Err(ErrSasl(_)) => false,
+            _ => true
+        }
+    }
+
+    fn should_reconnect(&self, res: &Result) -> bool {
+        if self.quitting {
+            return false;
+        }
+        match *res {
+            Ok(()) | Err(ErrConnect(_)) | Err(ErrIO(_)) | Err(ErrPingTimeout) => true,
+            _ => false
+        }
+    }
+
+    fn run<Payload>(&mut self, stream: NetStream, opts: &Options<Payload>,
+                    password: Option<&str>, payload: &mut Payload,
+                    cb: |&mut Conn, Event, &mut Payload|) -> Result {
+        // set up the keepalive timer
+        let mut timer = None;
+        let tick = match opts.ping_interval {
+            None => None,
+            Some(interval) => {
+                let mut t = match Timer::new() {
+                    Err(e) => return Err(ErrIO(e)),
+                    Ok(t) => t
+                };
+                let tick = t.periodic(min(interval, 1000));
+                timer = Some(t);
+                Some(tick)
+            }
+        };
+        self.last_recv = time::precise_time_ns();
+
         // spawn I/O tasks
-        let (write_tx, write_rx) = channel();
+        // write_tx carries one wakeup per line pushed onto the shared queue
+        let (write_tx, write_rx) = channel::<()>();
         self.write_tx = Some(write_tx);
+        self.queue = Arc::new(Mutex::new(WriteQueue::new()));
         let (read_tx, read_rx) = channel();
         let (err_tx, err_rx) = channel();
+        let (done_tx, writer_done) = channel::<()>();
 
         {
             let stream = stream.clone();
             let err_tx = err_tx.clone();
+            let throttle = opts.throttle;
+            let queue = self.queue.clone();
             TaskBuilder::new().named("libirc writer").spawn(proc() {
+                // dropped when the task exits, which signals writer_done
+                let _done_tx = done_tx;
                 let mut stream = stream;
-                loop {
-                    let line = match write_rx.recv_opt() {
-                        Err(_) => break,
-                        Ok(v) => v
-                    };
-                    match stream.write(line).and_then(|_| stream.flush()) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            if e.kind != io::EndOfFile {
-                                err_tx.send(Err(e));
-                            }
-                            break;
+                match write_lines(&mut stream, write_rx, queue, throttle) {
+                    Err(e) => {
+                        if e.kind != io::EndOfFile {
+                            err_tx.send(Err(e));
                         }
                     }
+                    Ok(()) => ()
                 }
             });
         }
@@ -199,22 +897,39 @@ impl<'a> Conn<'a> {
         }
 
         // send handshake commands
-        self.send_command(IRCCmd("NICK".into_maybe_owned()), [opts.nick.as_bytes()], false);
-        self.send_command(IRCCmd("USER".into_maybe_owned()), [opts.user.as_bytes(), bytes!("8 *"),
-                          opts.real.as_bytes()], true);
+        match password {
+            None => (),
+            Some(pass) => {
+                self.send_command(IRCCmd("PASS".into_maybe_owned()), [pass.as_bytes()], true);
+            }
+        }
+        if !self.caps_requested.is_empty() {
+            // CAP LS suspends registration until we send CAP END
+            self.cap_negotiating = true;
+            self.send_command(IRCCmd("CAP".into_maybe_owned()), [bytes!("LS"), bytes!("302")],
+                              false);
+        }
+        let nick = Vec::from_slice(self.user.nick());
+        self.send_command(IRCCmd("NICK".into_maybe_owned()), [nick.as_slice()], false);
+        self.send_command(IRCCmd("USER".into_maybe_owned()), [opts.user.as_bytes(), bytes!("8"),
+                          bytes!("*"), opts.real.as_bytes()], true);
 
 
         // run event loop
         // need to do some shenanigans with scoping to make borrowck happy
         let mut result = Ok(());
-        let procs = {
+        {
             let select = comm::Select::new();
             let mut read_handle = select.handle(&read_rx);
             unsafe { read_handle.add() }
             let mut err_handle = select.handle(&err_rx);
             unsafe { err_handle.add() }
-            let commands = opts.commands;
-            let mut cmd_handle = commands.as_ref().map(|p| select.handle(p));
+            let mut tick_handle = tick.as_ref().map(|p| select.handle(p));
+            if tick_handle.is_some() {
+                unsafe { tick_handle.as_mut().unwrap().add(); }
+            }
+            let commands = opts.commands.as_ref();
+            let mut cmd_handle = commands.map(|p| select.handle(p));
             if cmd_handle.is_some() {
                 unsafe { cmd_handle.as_mut().unwrap().add(); }
             }
@@ -226,12 +941,23 @@ impl<'a> Conn<'a> {
                     Err(comm::Empty) => (),
                     Err(comm::Disconnected) => break,
                     Ok(err) => {
-                        result = err;
+                        result = err.map_err(|e| ErrIO(e));
                         break;
                     }
                 }
-                if commands.is_some() {
-                    match commands.as_ref().unwrap().try_recv() {
+                if tick.is_some() {
+                    match tick.as_ref().unwrap().try_recv() {
+                        Ok(()) => {
+                            if !self.keepalive(opts.ping_interval.unwrap(), opts.ping_timeout) {
+                                result = Err(ErrPingTimeout);
+                                break;
+                            }
+                        }
+                        Err(_) => ()
+                    }
+                }
+                if cmd_handle.is_some() {
+                    match commands.unwrap().try_recv() {
                         Err(comm::Empty) => (),
                         Err(comm::Disconnected) => {
                             unsafe { cmd_handle.as_mut().unwrap().remove(); }
@@ -247,19 +973,41 @@ impl<'a> Conn<'a> {
                     Err(comm::Disconnected) => break,
                     Ok(line) => line
                 };
-                let line = match Line::parse(line.as_slice()) {
-                    None => {
-                        let line = line.as_slice();
-                        info!("[DEBUG] Found non-parseable line: {}", str::from_utf8_lossy(line));
+                self.last_recv = time::precise_time_ns();
+                let parsed = if opts.lenient {
+                    Line::parse_lenient(line.as_slice())
+                } else {
+                    Line::parse(line.as_slice())
+                };
+                let line = match parsed {
+                    Err(ParseError{ kind: EmptyLine, .. }) => continue,
+                    Err(err) => {
+                        info!("[DEBUG] Found non-parseable line ({}): {}", err,
+                              str::from_utf8_lossy(line.as_slice()));
+                        cb(self, UnparseableLine(line, err), payload);
                         continue;
                     }
-                    Some(line) => line
+                    Ok(line) => line
                 };
                 if log_enabled!(::log::DEBUG) {
                     let line = line.to_raw();
                     debug!("[DEBUG] Received line: {}", str::from_utf8_lossy(line.as_slice()));
                 }
                 handlers::handle_line(self, &line);
+                match self.error.take() {
+                    None => (),
+                    Some(err) => {
+                        result = Err(err);
+                        break;
+                    }
+                }
+                if self.redirect.is_some() {
+                    // the server told us to go elsewhere
+                    break;
+                }
+                for event in mem::replace(&mut self.events, Vec::new()).move_iter() {
+                    cb(self, event, payload);
+                }
                 if self.logged_in {
                     cb(self, LineReceived(line), payload);
                 }
@@ -268,153 +1016,237 @@ impl<'a> Conn<'a> {
                 // check the err_handle one more time
                 match err_rx.try_recv() {
                     Ok(err) => {
-                        result = err;
+                        result = err.map_err(|e| ErrIO(e));
                     }
                     _ => ()
                 }
             }
-
-            // drain the commands
-            match commands {
-                None => None,
-                Some(ref port) => {
-                    let mut procs = Vec::new();
-                    loop {
-                        match port.try_recv() {
-                            Err(_) => break,
-                            Ok(cmd) => procs.push(cmd)
-                        }
-                    }
-                    Some(procs)
-                }
-            }
-        };
-        // at this point the commands port is out of scope and therefore closed
+        }
         // ensure our write handle is closed out, in case we stopped due to read shutting down,
-        // and then run any buffered procs
+        // and let the writer flush anything still queued (e.g. a QUIT)
         self.write_tx = None;
-        match procs {
-            None => (),
-            Some(procs) => {
-                for cmd in procs.move_iter() {
-                    cmd(self, payload);
-                }
-            }
-        }
+        let _ = writer_done.recv_opt();
+        drop(timer);
 
         // return the result
         result
     }
 
+    /// Sends a keepalive PING if the connection has been idle for `interval` ms.
+    /// Returns `false` if an outstanding PING has gone unanswered for `timeout` ms
+    /// without anything else being received either.
+    ///
+    /// Nothing is sent until we've registered, as servers reject PING before that.
+    fn keepalive(&mut self, interval: u64, timeout: u64) -> bool {
+        if !self.logged_in {
+            return true;
+        }
+        let now = time::precise_time_ns();
+        match self.ping_sent {
+            Some((_, sent)) if now - sent >= timeout * 1000000 => {
+                if self.last_recv < sent {
+                    return false;
+                }
+                // the server is alive but didn't answer; try again
+                self.ping_sent = None;
+            }
+            Some(_) => return true,
+            None => ()
+        }
+        if now - self.last_recv >= interval * 1000000 {
+            let token = format!("LAG{}", now);
+            self.send_command(IRCCmd("PING".into_maybe_owned()), [token.as_bytes()], false);
+            self.ping_sent = Some((Vec::from_slice(token.as_bytes()), now));
+        }
+        true
+    }
+
     /// Returns `true` if the connection is still active
     /// (or was at the last pass through the runloop).
     pub fn is_connected(&self) -> bool {
         self.write_tx.is_some()
     }
 
-    /// Returns the host that was used to create this Conn
-    pub fn host(&self) -> &'a str {
-        self.host
+    /// Returns the host of the server we're connected to.
+    /// This may differ from `Options.host` after failover or an RPL_BOUNCE redirect.
+    pub fn host<'b>(&'b self) -> &'b str {
+        self.host.as_slice()
+    }
+
+    /// Returns the port of the server we're connected to
+    pub fn port(&self) -> u16 {
+        self.port
     }
 
     /// Returns the current User.
+    ///
+    /// The username and host are filled in with the server's view of us once it's
+    /// known, from USERHOST and WHO replies, our own JOINs, RPL_HOSTHIDDEN (396) and
+    /// CHGHOST.
     pub fn me<'a>(&'a self) -> &'a User {
         &self.user
     }
 
+    /// Returns the user modes set on us, from MODE lines and RPL_UMODEIS (221)
+    pub fn user_modes<'b>(&'b self) -> &'b [char] {
+        self.umodes.as_slice()
+    }
+
+    /// Returns `true` if the given IRCv3 capability has been acknowledged by the server
+    /// and is currently enabled.
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps_enabled.iter().any(|c| cap == c.as_slice())
+    }
+
+    /// Returns the most recently measured round-trip time to the server, in milliseconds.
+    /// This is measured with keepalive PINGs, see `Options.ping_interval`.
+    pub fn lag(&self) -> Option<u64> {
+        self.lag
+    }
+
+    /// Returns the SHA-256 fingerprint of the server's TLS certificate,
+    /// or None if the connection isn't using TLS.
+    /// This can be compared against a known value to pin the server's certificate.
+    pub fn peer_fingerprint<'b>(&'b self) -> Option<&'b [u8]> {
+        self.fingerprint.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns what the server has advertised that it supports in RPL_ISUPPORT (005)
+    pub fn support<'b>(&'b self) -> &'b support::ServerSupport {
+        &self.support
+    }
+
+    /// Returns the channels we're currently in
+    pub fn channels<'b>(&'b self) -> &'b [state::Channel] {
+        self.channels.as_slice()
+    }
+
+    /// Returns the state of the named channel, if we're in it
+    pub fn channel<'b>(&'b self, name: &[u8]) -> Option<&'b state::Channel> {
+        self.channels.iter().find(|c| c.is_named(name))
+    }
+
+    /// Returns an iterator over every user that shares a channel with us
+    pub fn users<'b>(&'b self) -> state::UserInfos<'b> {
+        self.users.iter()
+    }
+
+    /// Returns what's known about the user with the given nick, if they share a
+    /// channel with us.
+    ///
+    /// Requesting the `extended-join`, `account-notify`, `away-notify`, `chghost`
+    /// and `userhost-in-names` capabilities in `Options.caps` keeps this more
+    /// complete and up to date.
+    pub fn user<'b>(&'b self, nick: &[u8]) -> Option<&'b state::UserInfo> {
+        self.users.get(nick)
+    }
+
+    /// Returns the account name we're logged into, if known.
+    /// This is set from RPL_LOGGEDIN (900), typically after SASL authentication.
+    pub fn account<'b>(&'b self) -> Option<&'b [u8]> {
+        self.account.as_ref().map(|v| v.as_slice())
+    }
+
     /// Sends a command to the server.
     /// The line is truncated to 510 bytes (not including newline) before sending.
     ///
-    /// If the command is an IRCCmd or IRCCode, the args vector is interpreted as a
-    /// space-separated list of arguments, with a ':' argument prefix denoting the final
-    /// (possibly space-containing) argument.
+    /// If the command is an IRCCmd or IRCCode, the args vector is interpreted as its
+    /// params, given without any ':' prefix.
     ///
     /// If the command is an IRCAction, IRCCTCP, or IRCCTCPReply, the args vector is interpreted
     /// as the message that is being sent. It should be not be prefixed with a ':'.
     ///
-    /// No attempt is made to ensure that the args vector is valid. All values in the vector are
-    /// separated with a single space, and no special handling of ':' is performed. It is assumed
-    /// that the caller will provide valid arguments and will ':'-prefix as necessary.
+    /// The args aren't validated. They're separated with a single space, so only the final
+    /// argument may be empty, begin with ':' or contain a space. It gets a ':' prefix when
+    /// it needs one, and CR, LF and NUL bytes are dropped, as is \x01 from the args of a
+    /// CTCP message. Use `send()` to have the line validated instead.
+    ///
+    /// The add_colon flag causes the final argument to have a ':' prepended even when it
+    /// doesn't need one.
     ///
-    /// The add_colon flag causes the final argument in the args list to have a ':' prepended.
+    /// Earlier versions expected callers to add the ':' to the final argument themselves.
+    /// That ':' is now escaped like any other, so `:text` reaches the server as `:text`
+    /// rather than `text`. Callers that added their own ':' must drop it.
+    ///
+    /// The line is queued with the priority given by `Priority::for_command()`, and is
+    /// subject to the flood control configured in `Options.throttle` unless that is
+    /// HighPriority.
     pub fn send_command<V: Vector<u8>>(&mut self, cmd: Command, args: &[V], add_colon: bool) {
-        if !{
-            let chan = match self.write_tx {
-                None => return,
-                Some(ref mut c) => c
-            };
-            let mut line = [0u8, ..512];
-            let len = {
-                let mut buf = line.mut_slice_to(510);
+        let priority = Priority::for_command(&cmd);
+        self.send_command_with_priority(cmd, args, add_colon, priority)
+    }
 
-                fn append(buf: &mut &mut [u8], v: &[u8]) {
-                    let len = buf.copy_from(v);
-                    // this should work:
-                    //   *buf = buf.mut_slice_from(len);
-                    // but I'm getting weird borrowck issues (see mozilla/rust#11361)
-                    *buf = unsafe { ::std::mem::transmute(buf.mut_slice_from(len)) };
-                }
+    /// Sends a command to the server, bypassing flood control.
+    /// See `send_command()` for details.
+    ///
+    /// This is `send_command_with_priority()` with HighPriority, so it should be reserved
+    /// for urgent lines, as the server will still count them towards its own flood limits.
+    pub fn send_command_unthrottled<V: Vector<u8>>(&mut self, cmd: Command, args: &[V],
+                                                   add_colon: bool) {
+        self.send_command_with_priority(cmd, args, add_colon, HighPriority)
+    }
 
-                let is_ctcp = cmd.is_ctcp();
-                match cmd {
-                    IRCCmd(cmd) => {
-                        append(&mut buf, cmd.as_slice().as_bytes());
-                    }
-                    IRCCode(code) => {
-                        uint::to_str_bytes(code, 10, |v| {
-                            append(&mut buf, v);
-                        });
-                    }
-                    IRCAction(ref dst) | IRCCTCP(ref dst,_) => {
-                        append(&mut buf, bytes!("PRIVMSG "));
-                        append(&mut buf, dst.as_slice());
-                        append(&mut buf, bytes!(" :\x01"));
-                        let action = match cmd {
-                            IRCAction(_) => { static b: &'static [u8] = bytes!("ACTION"); b }
-                            IRCCTCP(_,ref action) => action.as_slice(),
-                            _ => unreachable!()
-                        };
-                        append(&mut buf, action);
-                    }
-                    IRCCTCPReply(dst, action) => {
-                        append(&mut buf, bytes!("NOTICE "));
-                        append(&mut buf, dst.as_slice());
-                        append(&mut buf, bytes!(" :\x01"));
-                        append(&mut buf, action.as_slice());
-                    }
-                }
-                if !args.is_empty() {
-                    for arg in args.init().iter() {
-                        append(&mut buf, bytes!(" "));
-                        append(&mut buf, arg.as_slice());
-                    }
-                    if add_colon {
-                        append(&mut buf, bytes!(" :"));
-                    } else {
-                        append(&mut buf, bytes!(" "));
-                    }
-                    append(&mut buf, args.last().unwrap().as_slice());
-                }
-                if is_ctcp {
-                    append(&mut buf, bytes!("\x01"));
-                }
-                510 - buf.len()
-            };
-            debug!("[DEBUG] Sent line: {}", str::from_utf8_lossy(line.slice_to(len)));
-            line.mut_slice_from(len).copy_from(bytes!("\r\n"));
-            chan.send_opt(line.slice_to(len+2).to_owned()).is_ok()
-        } {
-            self.write_tx = None;
-        }
+    /// Sends a command to the server with the given priority.
+    /// See `send_command()` for details.
+    ///
+    /// HighPriority lines bypass flood control, so should be reserved for urgent lines,
+    /// as the server will still count them towards its own flood limits.
+    pub fn send_command_with_priority<V: Vector<u8>>(&mut self, cmd: Command, args: &[V],
+                                                     add_colon: bool, priority: Priority) {
+        let mut line = Vec::new();
+        write_command(&mut line, &cmd, args, add_colon);
+        self.enqueue(line.as_slice(), priority);
+    }
+
+    /// Validates and sends a line to the server.
+    ///
+    /// Returns an error without sending anything if the line can't be encoded.
+    /// See `LineBuilder` for details.
+    ///
+    /// The line is queued with the priority given by `Priority::for_command()`.
+    pub fn send(&mut self, line: LineBuilder) -> Result<(), BuildError> {
+        let priority = Priority::for_command(line.command());
+        self.send_with_priority(line, priority)
+    }
+
+    /// Validates and sends a line to the server with the given priority.
+    /// See `send()` for details.
+    pub fn send_with_priority(&mut self, line: LineBuilder, priority: Priority)
+                             -> Result<(), BuildError> {
+        let raw = try!(line.build());
+        self.enqueue(raw.as_slice(), priority);
+        Ok(())
     }
 
     /// Sends a raw command to the server
     ///
     /// The line is sent exactly as provided, except truncated to 510 characters
     /// and terminated with \r\n.
+    ///
+    /// The line is queued with NormalPriority, and is subject to the flood control
+    /// configured in `Options.throttle`.
     pub fn send_raw(&mut self, raw: &[u8]) {
+        self.send_raw_with_priority(raw, NormalPriority)
+    }
+
+    /// Sends a raw command to the server, bypassing flood control.
+    /// See `send_raw()` for details.
+    ///
+    /// This is `send_raw_with_priority()` with HighPriority.
+    pub fn send_raw_unthrottled(&mut self, raw: &[u8]) {
+        self.send_raw_with_priority(raw, HighPriority)
+    }
+
+    /// Sends a raw command to the server with the given priority.
+    /// See `send_raw()` for details.
+    pub fn send_raw_with_priority(&mut self, raw: &[u8], priority: Priority) {
         let raw = chomp(raw);
         if raw.is_empty() { return }
+        self.enqueue(raw, priority)
+    }
+
+    /// Queues a line, truncated to 510 bytes, and wakes the writer
+    fn enqueue(&mut self, raw: &[u8], priority: Priority) {
         if !{
             let chan = match self.write_tx {
                 None => return,
@@ -424,64 +1256,149 @@ impl<'a> Conn<'a> {
             let len = line.mut_slice_to(510).copy_from(raw);
             debug!("[DEBUG] Sent line: {}", str::from_utf8_lossy(line.slice_to(len)));
             line.mut_slice_from(len).copy_from(bytes!("\r\n"));
-            chan.send_opt(line.slice_to(len+2).to_owned()).is_ok()
+            self.queue.lock().push(line.slice_to(len+2).to_owned(), priority);
+            chan.send_opt(()).is_ok()
         } {
             self.write_tx = None;
         }
     }
 
+    /// Returns the number of lines waiting to be written to the server
+    pub fn queue_len(&self) -> uint {
+        self.queue.lock().len()
+    }
+
+    /// Drops all pending lines with a priority of `max` or lower that haven't been
+    /// written yet. Returns the number of lines dropped.
+    ///
+    /// For example, `conn.clear_queue(LowPriority)` discards queued bulk traffic
+    /// while leaving normal and control traffic alone.
+    pub fn clear_queue(&mut self, max: Priority) -> uint {
+        self.queue.lock().clear(max)
+    }
+
     /// Sets the user's nickname.
-    pub fn set_nick(&mut self, nick: &[u8]) {
-        self.send_command(IRCCmd("NICK".into_maybe_owned()), [nick], false);
+    /// This nick will be restored if the connection is re-established.
+    pub fn set_nick(&mut self, nick: &[u8]) -> Result<(), BuildError> {
+        try!(self.send_nick(nick));
+        self.wanted_nick = Vec::from_slice(nick);
+        Ok(())
+    }
+
+    /// Sends a NICK without changing the nick we want to have.
+    fn send_nick(&mut self, nick: &[u8]) -> Result<(), BuildError> {
+        try!(self.send(LineBuilder::cmd("NICK").middle(nick)));
         // if we're logged in, watch for the NICK reply before changing our nick
         if !self.logged_in {
             self.user = self.user.with_nick(nick);
         }
+        Ok(())
     }
 
     /// Quits the connection
     /// Pass [] for the message to use the default.
-    pub fn quit(&mut self, msg: &[u8]) {
-        if msg.is_empty() {
-            let args: &[&[u8]] = [];
-            self.send_command(IRCCmd("QUIT".into_maybe_owned()), args, false);
+    pub fn quit(&mut self, msg: &[u8]) -> Result<(), BuildError> {
+        let line = if msg.is_empty() {
+            LineBuilder::cmd("QUIT")
         } else {
-            self.send_command(IRCCmd("QUIT".into_maybe_owned()), [msg], true);
-        }
+            LineBuilder::cmd("QUIT").arg(msg)
+        };
+        try!(self.send(line));
+        self.quitting = true;
+        Ok(())
     }
 
-    /// Sends a PRIVMSG
-    pub fn privmsg(&mut self, dst: &[u8], msg: &[u8]) {
-        // NB: .as_slice() calls are necessary to work around mozilla/rust#8874
-        self.send_command(IRCCmd("PRIVMSG".into_maybe_owned()),
-                          [dst.as_slice(), msg.as_slice()], true)
+    /// Sends a PRIVMSG.
+    /// Long messages are split over several lines. See `send_text()`.
+    pub fn privmsg(&mut self, dst: &[u8], msg: &[u8]) -> Result<(), BuildError> {
+        self.send_text(IRCCmd("PRIVMSG".into_maybe_owned()), dst, msg)
     }
 
-    /// Sends a NOTICE
-    pub fn notice(&mut self, dst: &[u8], msg: &[u8]) {
-        self.send_command(IRCCmd("NOTICE".into_maybe_owned()),
-                          [dst.as_slice(), msg.as_slice()], true)
+    /// Sends a NOTICE.
+    /// Long messages are split over several lines. See `send_text()`.
+    pub fn notice(&mut self, dst: &[u8], msg: &[u8]) -> Result<(), BuildError> {
+        self.send_text(IRCCmd("NOTICE".into_maybe_owned()), dst, msg)
+    }
+
+    /// Sends a CTCP ACTION.
+    /// Long messages are split over several lines. See `send_text()`.
+    pub fn action(&mut self, dst: &[u8], msg: &[u8]) -> Result<(), BuildError> {
+        self.send_text(IRCAction(Vec::from_slice(dst)), [], msg)
+    }
+
+    /// Sends text to `dst`, split over as many lines as needed.
+    ///
+    /// `cmd` is usually PRIVMSG or NOTICE, with `dst` as its first param. For IRCAction,
+    /// IRCCTCP and IRCCTCPReply, the destination is part of the command and `dst` should
+    /// be empty.
+    ///
+    /// Each line is kept short enough to survive being relayed by the server, which adds
+    /// our `:nick!user@host` prefix. Text is split at spaces where possible, and otherwise
+    /// between UTF-8 characters. CTCP messages are framed with \x01 on every line.
+    ///
+    /// Nothing is sent if any of the lines can't be encoded, or if `TooLong` is returned
+    /// because the line would be 510 bytes or more before adding any text.
+    pub fn send_text(&mut self, cmd: Command, dst: &[u8], msg: &[u8]) -> Result<(), BuildError> {
+        let mut line = LineBuilder::new(cmd);
+        if !dst.is_empty() {
+            line = line.arg(dst);
+        }
+        // the overhead is everything in the relayed line except the text
+        let overhead = try!(line.clone().arg([]).build()).len() + self.relay_prefix_len();
+        if overhead >= 510 {
+            return Err(TooLong);
+        }
+        let mut lines = Vec::new();
+        for chunk in split_text(msg, 510 - overhead).move_iter() {
+            lines.push(try!(line.clone().arg(chunk).build()));
+        }
+        let priority = Priority::for_command(line.command());
+        for raw in lines.iter() {
+            self.enqueue(raw.as_slice(), priority);
+        }
+        Ok(())
+    }
+
+    /// Returns the length of the `:nick!user@host ` prefix the server adds to our
+    /// lines when relaying them. Parts of our hostmask we haven't seen yet are
+    /// estimated generously.
+    fn relay_prefix_len(&self) -> uint {
+        let (user, host) = match self.user.host() {
+            Some(host) => (self.user.user().map_or(10, |u| u.len()), host.len()),
+            // until we've seen our hostmask, allow for the server adding a ~
+            // to an unverified username
+            None => (self.user.user().map_or(10, |u| u.len() + 1), 63)
+        };
+        1 + self.user.nick().len() + 1 + user + 1 + host + 1
     }
 
     /// Sends a JOIN
     /// Pass [] for keys if there are none.
-    pub fn join(&mut self, room: &[u8], keys: &[u8]) {
-        if keys.is_empty() {
-            self.send_command(IRCCmd("JOIN".into_maybe_owned()), [room], false);
+    pub fn join(&mut self, room: &[u8], keys: &[u8]) -> Result<(), BuildError> {
+        let line = if keys.is_empty() {
+            LineBuilder::cmd("JOIN").middle(room)
         } else {
-            self.send_command(IRCCmd("JOIN".into_maybe_owned()),
-                              [room.as_slice(), keys.as_slice()], false);
+            LineBuilder::cmd("JOIN").middle(room).middle(keys)
+        };
+        try!(self.send(line));
+        // remember keys so we can rejoin after reconnecting
+        if !keys.is_empty() {
+            let (rooms, keys) = (room.split(|&b| b == ',' as u8), keys.split(|&b| b == ',' as u8));
+            for (room, key) in rooms.zip(keys) {
+                self.chan_keys.retain(|&(ref c, _)| room != c.as_slice());
+                self.chan_keys.push((Vec::from_slice(room), Vec::from_slice(key)));
+            }
         }
+        Ok(())
     }
 
     /// Sends a PART
     /// Pass [] for the message to use the default.
-    pub fn part(&mut self, room: &[u8], msg: &[u8]) {
+    pub fn part(&mut self, room: &[u8], msg: &[u8]) -> Result<(), BuildError> {
         if msg.is_empty() {
-            self.send_command(IRCCmd("PART".into_maybe_owned()), [room], false);
+            self.send(LineBuilder::cmd("PART").middle(room))
         } else {
-            self.send_command(IRCCmd("PART".into_maybe_owned()),
-                              [room.as_slice(), msg.as_slice()], true);
+            self.send(LineBuilder::cmd("PART").middle(room).arg(msg))
         }
     }
 }
@@ -532,13 +1449,21 @@ impl Command {
             IRCAction(_) | IRCCTCP(_,_) | IRCCTCPReply(_,_) => true,
             _ => false }
     }
+
+    /// Returns the Numeric if the command is a 3-digit code
+    pub fn numeric(&self) -> Option<numeric::Numeric> {
+        match *self {
+            IRCCode(code) => Some(numeric::Numeric(code)),
+            _ => None
+        }
+    }
 }
 
 impl fmt::Show for Command {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             IRCCmd(ref s) => write!(f, "IRCCmd({})", *s),
-            IRCCode(code) => write!(f, "IRCCode({})", code),
+            IRCCode(code) => write!(f, "IRCCode({})", numeric::Numeric(code)),
             IRCAction(ref v) => write!(f, "IRCAction({})", str::from_utf8_lossy(v.as_slice())),
             IRCCTCP(ref cmd, ref dst) => {
                 let cmd = str::from_utf8_lossy(cmd.as_slice());
@@ -554,225 +1479,789 @@ impl fmt::Show for Command {
     }
 }
 
-/// A parsed line
+/// An IRCv3 message tag
 #[deriving(Eq,Clone)]
+pub struct Tag {
+    /// The tag key, including any client-only '+' marker and vendor prefix
+    pub key: Vec<u8>,
+    /// The unescaped tag value, if any.
+    /// An empty value is treated the same as a missing value.
+    pub value: Option<Vec<u8>>,
+}
+
+impl Tag {
+    /// Construct a new Tag from a key and an optional unescaped value
+    pub fn new(key: &[u8], value: Option<&[u8]>) -> Tag {
+        Tag {
+            key: Vec::from_slice(key),
+            value: value.and_then(|v| if v.is_empty() { None } else { Some(Vec::from_slice(v)) })
+        }
+    }
+
+    /// Parse a single raw tag of the form `key[=escaped value]`
+    pub fn parse(v: &[u8]) -> Tag {
+        match v.position_elem(&('=' as u8)) {
+            None => Tag::new(v, None),
+            Some(idx) => {
+                let value = unescape_tag_value(v.slice_from(idx+1));
+                Tag::new(v.slice_to(idx), Some(value.as_slice()))
+            }
+        }
+    }
+
+    /// Returns `true` if this is a client-only tag (key begins with '+')
+    pub fn is_client_only(&self) -> bool {
+        self.key.as_slice().starts_with(bytes!("+"))
+    }
+
+    /// Returns the vendor prefix of the key, if any.
+    /// For the key `+example.com/foo` this returns `example.com`.
+    pub fn vendor<'a>(&'a self) -> Option<&'a [u8]> {
+        let key = self.unprefixed_key();
+        key.position_elem(&('/' as u8)).map(|idx| key.slice_to(idx))
+    }
+
+    /// Returns the name of the tag, without the client-only marker or vendor prefix.
+    /// For the key `+example.com/foo` this returns `foo`.
+    pub fn name<'a>(&'a self) -> &'a [u8] {
+        let key = self.unprefixed_key();
+        match key.position_elem(&('/' as u8)) {
+            None => key,
+            Some(idx) => key.slice_from(idx+1)
+        }
+    }
+
+    fn unprefixed_key<'a>(&'a self) -> &'a [u8] {
+        if self.is_client_only() {
+            self.key.slice_from(1)
+        } else {
+            self.key.as_slice()
+        }
+    }
+
+    /// Converts into the raw `key[=escaped value]` representation
+    pub fn to_raw(&self) -> Vec<u8> {
+        let mut res = self.key.clone();
+        match self.value {
+            None => (),
+            Some(ref value) => {
+                res.push('=' as u8);
+                escape_tag_value(value.as_slice(), &mut res);
+            }
+        }
+        res
+    }
+}
+
+impl fmt::Show for Tag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let raw = self.to_raw();
+        f.pad(str::from_utf8_lossy(raw.as_slice()).as_slice())
+    }
+}
+
+fn unescape_tag_value(v: &[u8]) -> Vec<u8> {
+    let mut res = Vec::with_capacity(v.len());
+    let mut it = v.iter();
+    loop {
+        match it.next() {
+            None => break,
+            Some(&b) if b == '\\' as u8 => {
+                match it.next() {
+                    // a trailing backslash is dropped
+                    None => break,
+                    Some(&b) => res.push(match b as char {
+                        ':' => ';' as u8,
+                        's' => ' ' as u8,
+                        'r' => '\r' as u8,
+                        'n' => '\n' as u8,
+                        // this covers \\ as well as invalid escapes
+                        _ => b
+                    })
+                }
+            }
+            Some(&b) => res.push(b)
+        }
+    }
+    res
+}
+
+fn escape_tag_value(v: &[u8], res: &mut Vec<u8>) {
+    for &b in v.iter() {
+        match b as char {
+            ';' => res.push_all(bytes!("\\:")),
+            ' ' => res.push_all(bytes!("\\s")),
+            '\\' => res.push_all(bytes!("\\\\")),
+            '\r' => res.push_all(bytes!("\\r")),
+            '\n' => res.push_all(bytes!("\\n")),
+            _ => res.push(b)
+        }
+    }
+}
+
+/// A parsed line
+///
+/// The prefix and args are stored as ranges into a single buffer, so parsing a
+/// line only needs a couple of allocations. See `LineRef` for parsing without
+/// copying the line at all.
+#[deriving(Clone)]
 pub struct Line {
-    /// The optional prefix
-    pub prefix: Option<User>,
-    /// The command
-    pub command: Command,
-    /// Any arguments
-    pub args: Vec<Vec<u8>>,
+    buf: Vec<u8>,
+    tags: Vec<Tag>,
+    prefix: Option<(uint, uint)>,
+    command: Command,
+    args: Vec<(uint, uint)>
+}
+
+impl Eq for Line {
+    fn eq(&self, other: &Line) -> bool {
+        self.tags == other.tags && self.prefix_raw() == other.prefix_raw() &&
+            self.command == other.command && self.args() == other.args()
+    }
 }
 
 impl fmt::Show for Line {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        try!(write!(f, r"Line\{ prefix: {}, command: {}, args: [", self.prefix, self.command));
-        for (i, v) in self.args.iter().enumerate() {
+        try!(write!(f, r"Line\{ tags: ["));
+        for (i, tag) in self.tags.iter().enumerate() {
+            if i != 0 {
+                try!(write!(f, ", "));
+            }
+            try!(write!(f, "{}", *tag));
+        }
+        try!(write!(f, r"], prefix: {}, command: {}, args: [", self.prefix(), self.command));
+        for (i, v) in self.args().iter().enumerate() {
             if i != 0 {
                 try!(write!(f, ", "));
             }
-            try!(write!(f, "{}", str::from_utf8_lossy(v.as_slice())));
+            try!(write!(f, "{}", str::from_utf8_lossy(v)));
         }
         write!(f, "]")
     }
 }
 
+/// The args of a Line
+#[deriving(Clone)]
+pub struct Args<'a> {
+    buf: &'a [u8],
+    ranges: &'a [(uint, uint)]
+}
+
+impl<'a> Args<'a> {
+    /// Returns the number of args
+    pub fn len(&self) -> uint {
+        self.ranges.len()
+    }
+
+    /// Returns `true` if there are no args
+    pub fn is_empty(&self) -> bool {
+        self.ranges.is_empty()
+    }
+
+    /// Returns the arg at the given index. Fails if the index is out of bounds.
+    pub fn get(&self, idx: uint) -> &'a [u8] {
+        let (start, end) = self.ranges[idx];
+        self.buf.slice(start, end)
+    }
+
+    /// Returns the first arg, if any
+    pub fn head(&self) -> Option<&'a [u8]> {
+        if self.is_empty() { None } else { Some(self.get(0)) }
+    }
+
+    /// Returns the last arg, if any
+    pub fn last(&self) -> Option<&'a [u8]> {
+        if self.is_empty() { None } else { Some(self.get(self.len()-1)) }
+    }
+
+    /// Returns the args from `start` up to but not including `end`
+    pub fn slice(&self, start: uint, end: uint) -> Args<'a> {
+        Args { buf: self.buf, ranges: self.ranges.slice(start, end) }
+    }
+
+    /// Returns the args from `start` on
+    pub fn slice_from(&self, start: uint) -> Args<'a> {
+        Args { buf: self.buf, ranges: self.ranges.slice_from(start) }
+    }
+
+    /// Returns an iterator over the args
+    pub fn iter(&self) -> ArgItems<'a> {
+        ArgItems { buf: self.buf, iter: self.ranges.iter() }
+    }
+
+    /// Copies the arg slices into a vector
+    pub fn to_vec(&self) -> Vec<&'a [u8]> {
+        self.iter().collect()
+    }
+}
+
+impl<'a> Eq for Args<'a> {
+    fn eq(&self, other: &Args) -> bool {
+        self.len() == other.len() && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+/// An iterator over the args of a Line
+pub struct ArgItems<'a> {
+    buf: &'a [u8],
+    iter: slice::Items<'a, (uint, uint)>
+}
+
+impl<'a> Iterator<&'a [u8]> for ArgItems<'a> {
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let buf = self.buf;
+        self.iter.next().map(|&(start, end)| buf.slice(start, end))
+    }
+
+    fn size_hint(&self) -> (uint, Option<uint>) {
+        self.iter.size_hint()
+    }
+}
+
+/// The reason a line couldn't be parsed
+#[deriving(Eq,Clone,Show)]
+pub enum ParseErrorKind {
+    /// The line is empty, or only spaces
+    EmptyLine,
+    /// The line has no command
+    MissingCommand,
+    /// The command has a character that isn't allowed
+    InvalidCommand,
+    /// A CTCP message has no destination
+    MissingCTCPDest
+}
+
+/// An error from parsing a line
+#[deriving(Eq,Clone)]
+pub struct ParseError {
+    /// What went wrong
+    pub kind: ParseErrorKind,
+    /// The byte offset in the line where the error was found
+    pub offset: uint
+}
+
+impl fmt::Show for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let desc = match self.kind {
+            EmptyLine => "empty line",
+            MissingCommand => "missing command",
+            InvalidCommand => "invalid command character",
+            MissingCTCPDest => "missing CTCP destination"
+        };
+        write!(f, "{} at byte {}", desc, self.offset)
+    }
+}
+
 impl Line {
-    /// Parse a line into a Line struct
-    pub fn parse(mut v: &[u8]) -> Option<Line> {
-        let mut prefix = None;
-        if v.starts_with(bytes!(":")) {
-            let idx = match v.position_elem(&(' ' as u8)) {
-                None => return None,
-                Some(idx) => idx
-            };
-            prefix = Some(User::parse(v.slice(1, idx).to_owned()));
-            v = v.slice_from(idx+1);
+    /// Constructs a Line from its parts
+    pub fn new<V: Vector<u8>>(tags: Vec<Tag>, prefix: Option<Prefix>, command: Command,
+                              args: &[V]) -> Line {
+        let mut buf = Vec::new();
+        let prefix = prefix.map(|prefix| {
+            buf.push_all(prefix.raw());
+            (0, buf.len())
+        });
+        let mut ranges = Vec::with_capacity(args.len());
+        for arg in args.iter() {
+            let start = buf.len();
+            buf.push_all(arg.as_slice());
+            ranges.push((start, buf.len()));
         }
-        let (mut command, checkCTCP) = {
-            let cmd;
-            match v.position_elem(&(' ' as u8)) {
-                Some(0) => return None,
-                None => {
-                    cmd = v;
-                    v = &[];
+        Line {
+            buf: buf,
+            tags: tags,
+            prefix: prefix,
+            command: command,
+            args: ranges
+        }
+    }
+
+    /// Parse a line into a Line struct.
+    ///
+    /// The command must be alphabetic or a 3-digit numeric.
+    pub fn parse(v: &[u8]) -> Result<Line, ParseError> {
+        LineRef::parse(v).and_then(|line| line.to_line())
+    }
+
+    /// Parse a line into a Line struct, accepting any ASCII command that
+    /// isn't a 3-digit numeric, such as `f23`.
+    pub fn parse_lenient(v: &[u8]) -> Result<Line, ParseError> {
+        LineRef::parse_lenient(v).and_then(|line| line.to_line())
+    }
+
+    /// Returns any IRCv3 message tags
+    pub fn tags<'a>(&'a self) -> &'a [Tag] {
+        self.tags.as_slice()
+    }
+
+    /// Returns the tag with the given key, if any.
+    /// If the key is repeated, the last one wins.
+    pub fn tag<'a>(&'a self, key: &[u8]) -> Option<&'a Tag> {
+        self.tags.iter().rev().find(|t| key == t.key.as_slice())
+    }
+
+    /// Returns the optional prefix, which says whether the line came from a server or
+    /// a user. The prefix borrows the line, so use `to_prefix()` to keep it.
+    pub fn prefix<'a>(&'a self) -> Option<PrefixRef<'a>> {
+        self.prefix_raw().map(|raw| PrefixRef::parse(raw))
+    }
+
+    /// Returns the prefix without the leading ':'
+    pub fn prefix_raw<'a>(&'a self) -> Option<&'a [u8]> {
+        let buf = self.buf.as_slice();
+        self.prefix.map(|(start, end)| buf.slice(start, end))
+    }
+
+    /// Returns the command
+    pub fn command<'a>(&'a self) -> &'a Command {
+        &self.command
+    }
+
+    /// Returns the args
+    pub fn args<'a>(&'a self) -> Args<'a> {
+        Args { buf: self.buf.as_slice(), ranges: self.args.as_slice() }
+    }
+
+    /// Converts into the "raw" representation @tags :prefix cmd args
+    ///
+    /// The last arg is ':'-prefixed whenever it needs to be, so the result parses back
+    /// into an equal Line, provided no middle arg is empty or contains a space.
+    /// Bytes that can't be sent are dropped, as described for `Conn::send_command()`.
+    pub fn to_raw(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.buf.len() + self.args.len() + 32);
+        if !self.tags.is_empty() {
+            res.push('@' as u8);
+            for (i, tag) in self.tags.iter().enumerate() {
+                if i != 0 {
+                    res.push(';' as u8);
                 }
-                Some(idx) => {
-                    cmd = v.slice_to(idx);
-                    v = v.slice_from(idx+1);
+                res.push_all(tag.to_raw().as_slice());
+            }
+            res.push(' ' as u8);
+        }
+        match self.prefix_raw() {
+            None => (),
+            Some(prefix) => {
+                res.push(':' as u8);
+                res.push_all(prefix);
+                res.push(' ' as u8);
+            }
+        }
+        write_command(&mut res, &self.command, self.args().to_vec().as_slice(), false);
+        res
+    }
+}
+
+/// Appends the command and args of a line to `res`, as they should be sent.
+///
+/// The last arg gets a ':' when `add_colon` is set, or when it's empty, starts with ':'
+/// or contains a space. CTCP args are joined with spaces inside the CTCP message.
+/// CR, LF and NUL can't appear in a line, and \x01 would end a CTCP message early,
+/// so those bytes are dropped.
+fn write_command<V: Vector<u8>>(res: &mut Vec<u8>, cmd: &Command, args: &[V], add_colon: bool) {
+    fn push_arg(res: &mut Vec<u8>, arg: &[u8], is_ctcp: bool) {
+        for &b in arg.iter() {
+            if !(b == '\r' as u8 || b == '\n' as u8 || b == 0 || (is_ctcp && b == 0x1)) {
+                res.push(b);
+            }
+        }
+    }
+
+    match *cmd {
+        IRCCmd(ref cmd) => res.push_all(cmd.as_slice().as_bytes()),
+        IRCCode(c) => {
+            uint::to_str_bytes(c, 10, |v| {
+                for _ in range(v.len(), 3) {
+                    res.push('0' as u8);
                 }
+                res.push_all(v);
+            })
+        }
+        IRCAction(ref dst) => {
+            res.push_all(bytes!("PRIVMSG "));
+            res.push_all(dst.as_slice());
+            res.push_all(bytes!(" :\x01ACTION"));
+        }
+        IRCCTCP(ref cmd, ref dst) => {
+            res.push_all(bytes!("PRIVMSG "));
+            res.push_all(dst.as_slice());
+            res.push_all(bytes!(" :\x01"));
+            res.push_all(cmd.as_slice());
+        }
+        IRCCTCPReply(ref cmd, ref dst) => {
+            res.push_all(bytes!("NOTICE "));
+            res.push_all(dst.as_slice());
+            res.push_all(bytes!(" :\x01"));
+            res.push_all(cmd.as_slice());
+        }
+    }
+    if cmd.is_ctcp() {
+        // an ACTION with no text is parsed with a single empty arg
+        let no_text = match *cmd {
+            IRCAction(_) => args.len() == 1 && args[0].as_slice().is_empty(),
+            _ => false
+        };
+        if !no_text {
+            for arg in args.iter() {
+                res.push(' ' as u8);
+                push_arg(res, arg.as_slice(), true);
             }
-            if cmd.len() == 3 && cmd.iter().all(|&b| b >= '0' as u8 && b <= '9' as u8) {
-                (IRCCode(uint::parse_bytes(cmd, 10).unwrap()), false)
-            } else if cmd.iter().all(|&b| b < 0x80 && char::is_alphabetic(b as char)) {
-                let shouldCheck = cmd == bytes!("PRIVMSG") || cmd == bytes!("NOTICE");
-                (IRCCmd(str::from_utf8(cmd).unwrap().to_owned().into_maybe_owned()), shouldCheck)
-            } else {
-                return None;
+        }
+        res.push(0x1);
+        return;
+    }
+    for (i, arg) in args.iter().enumerate() {
+        res.push(' ' as u8);
+        let start = res.len();
+        push_arg(res, arg.as_slice(), false);
+        if i + 1 == args.len() {
+            let needs_colon = {
+                let last = res.slice_from(start);
+                last.is_empty() || last[0] == ':' as u8 || last.contains(&(' ' as u8))
+            };
+            if add_colon || needs_colon {
+                res.insert(start, ':' as u8);
+            }
+        }
+    }
+}
+
+/// Commands that are common enough to be worth not allocating a string for
+static KnownCommands: &'static [&'static str] = &["PRIVMSG", "NOTICE", "JOIN", "PART", "QUIT",
+                                                  "NICK", "MODE", "TOPIC", "KICK", "INVITE",
+                                                  "PING", "PONG", "ERROR", "CAP",
+                                                  "AUTHENTICATE", "AWAY", "ACCOUNT", "CHGHOST",
+                                                  "WALLOPS", "KILL", "TAGMSG", "BATCH",
+                                                  "SETNAME"];
+
+fn intern_command(cmd: &[u8]) -> MaybeOwned<'static> {
+    match KnownCommands.iter().find(|c| c.as_bytes() == cmd) {
+        Some(c) => (*c).into_maybe_owned(),
+        None => str::from_utf8_lossy(cmd).into_owned().into_maybe_owned()
+    }
+}
+
+fn is_numeric(cmd: &[u8]) -> bool {
+    cmd.len() == 3 && cmd.iter().all(|&b| b >= '0' as u8 && b <= '9' as u8)
+}
+
+/// A line that borrows the buffer it was parsed from.
+///
+/// Parsing only records where each part of the line starts and ends, so it never
+/// allocates. Tags and params are split as they're read, and CTCP messages are
+/// left as plain PRIVMSG and NOTICE lines. Use `to_line()` to get a Line.
+///
+/// As the IRC specs recommend, params may be separated by more than one space.
+#[deriving(Clone)]
+pub struct LineRef<'a> {
+    raw: &'a [u8],
+    tags: Option<(uint, uint)>,
+    prefix: Option<(uint, uint)>,
+    command: (uint, uint),
+    params: uint
+}
+
+impl<'a> LineRef<'a> {
+    /// Parse a line without copying it.
+    ///
+    /// The command must be alphabetic or a 3-digit numeric.
+    pub fn parse(v: &'a [u8]) -> Result<LineRef<'a>, ParseError> {
+        LineRef::parse_with(v, false)
+    }
+
+    /// Parse a line without copying it, accepting any ASCII command that
+    /// isn't a 3-digit numeric, such as `f23`.
+    pub fn parse_lenient(v: &'a [u8]) -> Result<LineRef<'a>, ParseError> {
+        LineRef::parse_with(v, true)
+    }
+
+    fn parse_with(line: &'a [u8], lenient: bool) -> Result<LineRef<'a>, ParseError> {
+        let err = |kind, offset| Err(ParseError{ kind: kind, offset: offset });
+        if line.iter().all(|&b| b == ' ' as u8) {
+            return err(EmptyLine, 0);
+        }
+        let mut pos = 0;
+        let mut tags = None;
+        if line.starts_with(bytes!("@")) {
+            let idx = match line.position_elem(&(' ' as u8)) {
+                None => return err(MissingCommand, line.len()),
+                Some(idx) => idx
+            };
+            tags = Some((1, idx));
+            pos = idx+1;
+        }
+        let mut prefix = None;
+        if line.slice_from(pos).starts_with(bytes!(":")) {
+            let idx = match line.slice_from(pos).position_elem(&(' ' as u8)) {
+                None => return err(MissingCommand, line.len()),
+                Some(idx) => pos+idx
+            };
+            prefix = Some((pos+1, idx));
+            pos = idx+1;
+        }
+        let (end, params) = match line.slice_from(pos).position_elem(&(' ' as u8)) {
+            Some(0) => return err(MissingCommand, pos),
+            None if pos == line.len() => return err(MissingCommand, pos),
+            None => (line.len(), line.len()),
+            Some(idx) => (pos+idx, pos+idx+1)
+        };
+        let cmd = line.slice(pos, end);
+        if !is_numeric(cmd) {
+            let valid = |&b: &u8| b < 0x80 && (lenient || char::is_alphabetic(b as char));
+            match cmd.iter().position(|b| !valid(b)) {
+                Some(idx) => return err(InvalidCommand, pos+idx),
+                None => ()
             }
+        }
+        Ok(LineRef {
+            raw: line,
+            tags: tags,
+            prefix: prefix,
+            command: (pos, end),
+            params: params
+        })
+    }
+
+    /// Returns the line this was parsed from
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// Returns the IRCv3 message tags, without the leading '@'
+    pub fn tags_raw(&self) -> Option<&'a [u8]> {
+        let raw = self.raw;
+        self.tags.map(|(start, end)| raw.slice(start, end))
+    }
+
+    /// Returns the tag with the given key, if any.
+    /// If the key is repeated, the last one wins.
+    pub fn tag(&self, key: &[u8]) -> Option<Tag> {
+        let tags = match self.tags_raw() {
+            None => return None,
+            Some(tags) => tags
         };
+        let mut found = None;
+        for tag in tags.split(|&b| b == ';' as u8) {
+            let tag_key = match tag.position_elem(&('=' as u8)) {
+                None => tag,
+                Some(idx) => tag.slice_to(idx)
+            };
+            if key == tag_key {
+                found = Some(tag);
+            }
+        }
+        found.map(|tag| Tag::parse(tag))
+    }
+
+    /// Returns the prefix, without the leading ':'
+    pub fn prefix(&self) -> Option<&'a [u8]> {
+        let raw = self.raw;
+        self.prefix.map(|(start, end)| raw.slice(start, end))
+    }
+
+    /// Returns the command name or numeric, exactly as it appears in the line
+    pub fn command(&self) -> &'a [u8] {
+        let (start, end) = self.command;
+        self.raw.slice(start, end)
+    }
+
+    /// Returns the numeric, if the command is one
+    pub fn numeric(&self) -> Option<uint> {
+        let cmd = self.command();
+        if is_numeric(cmd) { uint::parse_bytes(cmd, 10) } else { None }
+    }
+
+    /// Returns an iterator over the params
+    pub fn args(&self) -> Params<'a> {
+        Params { raw: self.raw, pos: self.params }
+    }
+
+    /// Returns the param at the given index, if any
+    pub fn arg(&self, idx: uint) -> Option<&'a [u8]> {
+        self.args().nth(idx)
+    }
+
+    /// Copies the line into a Line, interpreting any CTCP message.
+    ///
+    /// A CTCP message ends at its closing \x01, and anything after that is ignored.
+    /// This fails with `MissingCTCPDest` if a CTCP message has no destination.
+    pub fn to_line(&self) -> Result<Line, ParseError> {
+        let raw = self.raw;
+        let mut tags = Vec::new();
+        match self.tags_raw() {
+            None => (),
+            Some(v) => {
+                for tag in v.split(|&b| b == ';' as u8) {
+                    if !tag.is_empty() {
+                        tags.push(Tag::parse(tag));
+                    }
+                }
+            }
+        }
         let mut args = Vec::new();
-        while !v.is_empty() {
-            if v[0] == ':' as u8 {
-                args.push(Vec::from_slice(v.slice_from(1)));
-                break;
+        let mut params = self.args();
+        loop {
+            match params.next_range() {
+                None => break,
+                Some(range) => args.push(range)
+            }
+        }
+        let cmd = self.command();
+        let mut command = match self.numeric() {
+            Some(code) => IRCCode(code),
+            None => IRCCmd(intern_command(cmd))
+        };
+        let is_ctcp = match args.last() {
+            Some(&(start, end)) => end > start && raw[start] == 0x1,
+            None => false
+        };
+        if is_ctcp && (cmd == bytes!("PRIVMSG") || cmd == bytes!("NOTICE")) {
+            // the message ends at the closing \x01, if there is one
+            let (start, mut end) = args.pop().unwrap();
+            match raw.slice(start+1, end).position_elem(&0x1) {
+                Some(idx) => end = start+1+idx,
+                None => ()
             }
-            let idx = match v.position_elem(&(' ' as u8)) {
+            let dst = match args.as_slice().head() {
                 None => {
-                    args.push(Vec::from_slice(v));
-                    break;
+                    let offset = if raw[start-1] == ':' as u8 { start-1 } else { start };
+                    return Err(ParseError{ kind: MissingCTCPDest, offset: offset });
                 }
-                Some(idx) => idx
+                Some(&(s, e)) => Vec::from_slice(raw.slice(s, e))
             };
-            args.push(Vec::from_slice(v.slice_to(idx)));
-            v = v.slice_from(idx+1);
-        }
-        if checkCTCP && args.last().map_or(false, |v| v.as_slice().starts_with([0x1])) {
-            let mut text = args.pop().unwrap();
-            if text.len() > 1 && text.as_slice().ends_with([0x1]) {
-                text = Vec::from_slice(text.slice(1,text.len()-1));
-            } else {
-                text.shift();
-            }
-            let dst = args.move_iter().next().unwrap();
+            let text = start+1;
             let ctcpcmd;
-            match text.as_slice().position_elem(&(' ' as u8)) {
+            match raw.slice(text, end).position_elem(&(' ' as u8)) {
                 Some(idx) => {
-                    ctcpcmd = Vec::from_slice(text.slice_to(idx));
-                    args = vec![Vec::from_slice(text.slice_from(idx+1))];
+                    ctcpcmd = raw.slice(text, text+idx);
+                    args = vec![(text+idx+1, end)];
                 }
                 None => {
-                    ctcpcmd = text.clone();
+                    ctcpcmd = raw.slice(text, end);
                     args = Vec::new();
                 }
             }
-            let cmdstr = match command {
-                IRCCmd(ref s) if "PRIVMSG" == s.as_slice() => "PRIVMSG",
-                IRCCmd(ref s) if "NOTICE" == s.as_slice() => "NOTICE",
-                _ => unreachable!()
-            };
-            match cmdstr {
-                "PRIVMSG" => {
-                    if bytes!("ACTION") == ctcpcmd.as_slice() {
-                        command = IRCAction(dst);
-                        if args.is_empty() {
-                            args.push(Vec::new());
-                        }
-                    } else {
-                        command = IRCCTCP(ctcpcmd, dst);
-                    }
-                }
-                "NOTICE" => {
-                    command = IRCCTCPReply(ctcpcmd, dst);
+            if cmd == bytes!("NOTICE") {
+                command = IRCCTCPReply(Vec::from_slice(ctcpcmd), dst);
+            } else if ctcpcmd == bytes!("ACTION") {
+                command = IRCAction(dst);
+                if args.is_empty() {
+                    args.push((end, end));
                 }
-                _ => unreachable!()
+            } else {
+                command = IRCCTCP(Vec::from_slice(ctcpcmd), dst);
             }
         }
-        Some(Line{
-            prefix: prefix,
+        // the tags have been copied out already, so the buffer starts after them
+        let skip = match self.tags {
+            Some((_, end)) => end+1,
+            None => 0
+        };
+        for range in args.mut_iter() {
+            let (start, end) = *range;
+            *range = (start-skip, end-skip);
+        }
+        Ok(Line {
+            buf: Vec::from_slice(raw.slice_from(skip)),
+            tags: tags,
+            prefix: self.prefix.map(|(start, end)| (start-skip, end-skip)),
             command: command,
             args: args
         })
     }
+}
 
-    /// Converts into the "raw" representation :prefix cmd args
-    pub fn to_raw(&self) -> Vec<u8> {
-        let mut cap = self.prefix.as_ref().map_or(0, |s| 1+s.raw().len()+1);
-        let mut found_space = false;
-        cap += match self.command {
-            IRCCmd(ref cmd) => cmd.len(),
-            IRCCode(_) => 3,
-            IRCAction(ref dst) => {
-                "PRIVMSG".len() + 1 + dst.len() + 1 + ":\x01ACTION".len()
-            }
-            IRCCTCP(ref cmd, ref dst) => {
-                "PRIVMSG".len() + 1 + dst.len() + 1 + 2 + cmd.len()
-            }
-            IRCCTCPReply(ref cmd, ref dst) => {
-                "NOTICE".len() + 1 + dst.len() + 1 + 2 + cmd.len()
-            }
-        };
-        if self.command.is_ctcp() {
-            for arg in self.args.iter() {
-                cap += 1 + arg.len();
-            }
-            cap += 1; // for the final \x01
-        } else if !self.args.is_empty() {
-            if self.args.len() > 1 {
-                for arg in self.args.init().iter() {
-                    cap += 1 + arg.len();
-                }
-            }
-            let last = self.args.last().unwrap();
-            found_space = last.contains(&(' ' as u8));
-            if found_space {
-                cap += 1 + 1 /* : */ + last.len();
-            } else {
-                cap += 1 + last.len();
-            }
+/// An iterator over the params of a LineRef
+#[deriving(Clone)]
+pub struct Params<'a> {
+    raw: &'a [u8],
+    pos: uint
+}
+
+impl<'a> Params<'a> {
+    fn next_range(&mut self) -> Option<(uint, uint)> {
+        let (mut start, len) = (self.pos, self.raw.len());
+        // params may be separated by more than one space
+        while start < len && self.raw[start] == ' ' as u8 {
+            start += 1;
         }
-        let mut res = Vec::with_capacity(cap);
-        if self.prefix.is_some() {
-            res.push(':' as u8);
-            res.push_all(self.prefix.as_ref().unwrap().raw());
-            res.push(' ' as u8);
+        if start >= len {
+            return None;
         }
-        match self.command {
-            IRCCmd(ref cmd) => res.push_all(cmd.as_slice().as_bytes()),
-            IRCCode(c) => {
-                uint::to_str_bytes(c, 10, |v| {
-                    for _ in range(0, 3 - min(v.len(), 3)) {
-                        res.push('0' as u8);
-                    }
-                    res.push_all(v);
-                })
-            }
-            IRCAction(ref dst) => {
-                res.push_all(bytes!("PRIVMSG "));
-                res.push_all(dst.as_slice());
-                res.push_all(bytes!(" :\x01ACTION"));
-            }
-            IRCCTCP(ref cmd, ref dst) => {
-                res.push_all(bytes!("PRIVMSG "));
-                res.push_all(dst.as_slice());
-                res.push_all(bytes!(" :\x01"));
-                res.push_all(cmd.as_slice());
-            }
-            IRCCTCPReply(ref cmd, ref dst) => {
-                res.push_all(bytes!("NOTICE "));
-                res.push_all(dst.as_slice());
-                res.push_all(bytes!(" :\x01"));
-                res.push_all(cmd.as_slice());
-            }
+        if self.raw[start] == ':' as u8 {
+            self.pos = len;
+            return Some((start+1, len));
         }
-        if self.command.is_ctcp() {
-            for arg in self.args.iter() {
-                res.push(' ' as u8);
-                res.push_all(arg.as_slice());
+        match self.raw.slice_from(start).position_elem(&(' ' as u8)) {
+            None => {
+                self.pos = len;
+                Some((start, len))
             }
-            res.push(0x1);
-        } else if !self.args.is_empty() {
-            if self.args.len() > 1 {
-                for arg in self.args.init().iter() {
-                    res.push(' ' as u8);
-                    res.push_all(arg.as_slice());
-                }
+            Some(idx) => {
+                self.pos = start+idx+1;
+                Some((start, start+idx))
             }
-            res.push(' ' as u8);
-            if found_space {
-                res.push(':' as u8);
-            }
-            res.push_all(self.args.last().unwrap().as_slice());
         }
-        res
+    }
+}
+
+impl<'a> Iterator<&'a [u8]> for Params<'a> {
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let raw = self.raw;
+        self.next_range().map(|(start, end)| raw.slice(start, end))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Line,IRCCmd,IRCCode,IRCAction,IRCCTCP,IRCCTCPReply};
-    use User;
+    use super::{Line,LineRef,Tag,Reconnect,Throttle,WriteQueue,Priority,IRCCmd,IRCCode,IRCAction,
+                IRCCTCP,IRCCTCPReply,LowPriority,NormalPriority,HighPriority,ParseError,EmptyLine,
+                MissingCommand,InvalidCommand,MissingCTCPDest};
+    use super::{Conn,Server,Failover,Options,connect,handlers,tls,wait_to_reconnect,
+                write_lines_with};
+    use conn::builder::{InvalidMiddleParam, TooLong};
+    use {Prefix, UserPrefix, User};
+    use std::str;
+    use std::io::{MemWriter, Acceptor, Listener, BufferedStream, TcpListener};
+    use std::io::timer;
+    use std::cell::Cell;
+    use sync::{Arc, Mutex};
+    use openssl::ssl::{SslContext, SslStream, Sslv23};
+    use openssl::x509::PEM;
+    use time;
+    use test::Bencher;
+
+    /// Returns a Conn for the nick `me` that queues lines without a server, along with
+    /// the receiving end of its wakeups, which must be kept alive for lines to be queued.
+    pub fn test_conn<'a>() -> (Conn<'a>, Receiver<()>) {
+        let mut conn = Conn::new("irc.example.com", 6667, bytes!("me"), bytes!("user"),
+                                 Vec::new(), None);
+        let (tx, rx) = channel();
+        conn.write_tx = Some(tx);
+        (conn, rx)
+    }
+
+    /// Handles a line as if it was received from the server
+    pub fn recv(conn: &mut Conn, raw: &[u8]) {
+        let line = Line::parse(raw).ok().expect("unparseable test line");
+        handlers::handle_line(conn, &line);
+    }
+
+    /// Takes the queued lines, without their CRLF, in the order they'd be written
+    pub fn sent(conn: &Conn) -> Vec<String> {
+        let mut queue = conn.queue.lock();
+        let mut lines = Vec::new();
+        loop {
+            match queue.pop() {
+                None => break,
+                Some((line, _)) => {
+                    let line = line.slice_to(line.len()-2);
+                    lines.push(str::from_utf8_lossy(line).into_owned());
+                }
+            }
+        }
+        lines
+    }
 
     #[test]
     fn parse_line() {
@@ -780,73 +2269,734 @@ mod tests {
             ($val:expr) => (Vec::from_slice(bytes!($val)))
         )
         macro_rules! t(
-            ($v:expr, Some($exp:expr)) => (
-                t!($v, Some($exp), $v);
+            ($v:expr, Some(Line $exp:tt)) => (
+                t!($v, Some(Line $exp), $v);
             );
-            ($v:expr, Some($exp:expr), $res:expr) => ({
+            ($v:expr, Some(Line{ tags: $tags:expr, prefix: $prefix:expr, command: $cmd:expr,
+                                 args: $args:expr }), $res:expr) => ({
                 let v = $v;
-                let exp = $exp;
+                let (tags, args): (Vec<Tag>, Vec<Vec<u8>>) = ($tags, $args);
                 let line = Line::parse(v);
-                assert!(line.is_some());
+                assert!(line.is_ok());
                 let line = line.unwrap();
-                assert_eq!(line.prefix, exp.prefix);
-                assert_eq!(line.command, exp.command);
-                assert_eq!(line.args, exp.args);
+                assert_eq!(Vec::from_slice(line.tags()), tags);
+                assert_eq!(line.prefix().map(|p| p.to_prefix()), $prefix);
+                assert_eq!(*line.command(), $cmd);
+                let line_args = line.args().iter().map(|a| Vec::from_slice(a));
+                assert_eq!(line_args.collect::<Vec<Vec<u8>>>(), args);
+                assert!(line == Line::new(tags, $prefix, $cmd, args.as_slice()));
                 let line = line.to_raw();
                 assert_eq!(line.as_slice(), $res);
             });
-            ($s:expr, None) => (
-                assert_eq!(Line::parse($s), None);
+            ($s:expr, Err($kind:expr, $offset:expr)) => (
+                assert_eq!(Line::parse($s), Err(ParseError{ kind: $kind, offset: $offset }));
             )
         )
         t!(bytes!(":sendak.freenode.net 001 asldfkj :Welcome to the freenode Internet \
             Relay Chat Network asldfkj"),
             Some(Line{
-                prefix: Some(User::parse(bytes!("sendak.freenode.net"))),
+                tags: vec![],
+                prefix: Some(Prefix::parse(bytes!("sendak.freenode.net"))),
                 command: IRCCode(1),
                 args: vec![b!("asldfkj"),
                            b!("Welcome to the freenode Internet Relay Chat Network asldfkj")]
             }));
         t!(bytes!("004 asdf :This is a test"),
             Some(Line{
+                tags: vec![],
                 prefix: None,
                 command: IRCCode(4),
                 args: vec![b!("asdf"), b!("This is a test")]
             }));
         t!(bytes!(":nick!user@host.com PRIVMSG #channel :Some message"),
             Some(Line{
-                prefix: Some(User::parse(bytes!("nick!user@host.com"))),
+                tags: vec![],
+                prefix: Some(Prefix::parse(bytes!("nick!user@host.com"))),
                 command: IRCCmd("PRIVMSG".into_maybe_owned()),
                 args: vec![b!("#channel"), b!("Some message")]
             }));
-        t!(bytes!(" :sendak.freenode.net 001 asdf :Test"), None);
-        t!(bytes!(":sendak  001 asdf :Test"), None);
+        t!(bytes!(" :sendak.freenode.net 001 asdf :Test"), Err(MissingCommand, 0));
+        t!(bytes!(":sendak  001 asdf :Test"), Err(MissingCommand, 8));
         t!(bytes!("004"),
             Some(Line{
+                tags: vec![],
                 prefix: None,
                 command: IRCCode(4),
                 args: vec![]
             }));
         t!(bytes!(":bob!user@host.com PRIVMSG #channel :\x01ACTION does some stuff"),
             Some(Line{
-                prefix: Some(User::parse(bytes!("bob!user@host.com"))),
+                tags: vec![],
+                prefix: Some(Prefix::parse(bytes!("bob!user@host.com"))),
                 command: IRCAction(b!("#channel")),
                 args: vec![b!("does some stuff")]
             }),
             bytes!(":bob!user@host.com PRIVMSG #channel :\x01ACTION does some stuff\x01"));
         t!(bytes!(":bob!user@host.com PRIVMSG #channel :\x01VERSION\x01"),
             Some(Line{
-                prefix: Some(User::parse(bytes!("bob!user@host.com"))),
+                tags: vec![],
+                prefix: Some(Prefix::parse(bytes!("bob!user@host.com"))),
                 command: IRCCTCP(b!("VERSION"), b!("#channel")),
                 args: vec![]
             }));
         t!(bytes!(":bob NOTICE #frobnitz :\x01RESPONSE to whatever\x01"),
             Some(Line{
-                prefix: Some(User::parse(bytes!("bob"))),
+                tags: vec![],
+                prefix: Some(Prefix::parse(bytes!("bob"))),
                 command: IRCCTCPReply(b!("RESPONSE"), b!("#frobnitz")),
                 args: vec![b!("to whatever")]
             }));
-        t!(bytes!(":bob föo"), None);
-        t!(bytes!(":bob f23"), None);
+        t!(bytes!(":bob föo"), Err(InvalidCommand, 6));
+        t!(bytes!(":bob f23"), Err(InvalidCommand, 6));
+        t!(bytes!("@aaa=bbb;ccc;example.com/ddd=eee :nick!ident@host.com PRIVMSG me :Hello"),
+            Some(Line{
+                tags: vec![Tag::new(bytes!("aaa"), Some(bytes!("bbb"))),
+                           Tag::new(bytes!("ccc"), None),
+                           Tag::new(bytes!("example.com/ddd"), Some(bytes!("eee")))],
+                prefix: Some(Prefix::parse(bytes!("nick!ident@host.com"))),
+                command: IRCCmd("PRIVMSG".into_maybe_owned()),
+                args: vec![b!("me"), b!("Hello")]
+            }));
+        t!(bytes!("@a=b\\\\and\\nk;c=72\\s45;d=gh\\:764 foo"),
+            Some(Line{
+                tags: vec![Tag::new(bytes!("a"), Some(bytes!("b\\and\nk"))),
+                           Tag::new(bytes!("c"), Some(bytes!("72 45"))),
+                           Tag::new(bytes!("d"), Some(bytes!("gh;764")))],
+                prefix: None,
+                command: IRCCmd("foo".into_maybe_owned()),
+                args: vec![]
+            }));
+        t!(bytes!("@+draft/reply=abc;time=2014-05-01T12:00:00.000Z TAGMSG #chan"),
+            Some(Line{
+                tags: vec![Tag::new(bytes!("+draft/reply"), Some(bytes!("abc"))),
+                           Tag::new(bytes!("time"), Some(bytes!("2014-05-01T12:00:00.000Z")))],
+                prefix: None,
+                command: IRCCmd("TAGMSG".into_maybe_owned()),
+                args: vec![b!("#chan")]
+            }));
+        t!(bytes!("@a=;b=\\x\\ foo"),
+            Some(Line{
+                tags: vec![Tag::new(bytes!("a"), None),
+                           Tag::new(bytes!("b"), Some(bytes!("x")))],
+                prefix: None,
+                command: IRCCmd("foo".into_maybe_owned()),
+                args: vec![]
+            }),
+            bytes!("@a;b=x foo"));
+        t!(bytes!("@a=b"), Err(MissingCommand, 4));
+        t!(bytes!(""), Err(EmptyLine, 0));
+        t!(bytes!("  "), Err(EmptyLine, 0));
+        t!(bytes!(":bob"), Err(MissingCommand, 4));
+        t!(bytes!(":bob PRIVMSG :\x01VERSION\x01"), Err(MissingCTCPDest, 13));
+    }
+
+    // The conformance tests below are the msg-split, msg-join and userhost-split
+    // test vectors from https://github.com/ircdocs/parser-tests
+
+    #[test]
+    fn msg_split() {
+        macro_rules! t(
+            ($raw:expr, [$($key:expr => $val:expr),*], $src:expr, $verb:expr,
+             [$($param:expr),*]) => ({
+                let line = Line::parse(bytes!($raw)).unwrap();
+                let tags = vec![$(Tag::new(bytes!($key), $val)),*];
+                assert_eq!(Vec::from_slice(line.tags()), tags);
+                // a repeated key finds the last tag
+                for tag in tags.iter() {
+                    let last = tags.iter().rev().find(|t| t.key == tag.key);
+                    assert_eq!(line.tag(tag.key.as_slice()), last);
+                }
+                assert_eq!(line.prefix_raw(), $src);
+                match *line.command() {
+                    IRCCmd(ref cmd) => assert_eq!(cmd.as_slice(), $verb),
+                    IRCCode(code) => assert_eq!(format!("{:03u}", code).as_slice(), $verb),
+                    ref cmd => fail!("unexpected command {}", cmd)
+                }
+                let params: Vec<&[u8]> = vec![$(bytes!($param)),*];
+                assert_eq!(line.args().to_vec(), params);
+                let line = LineRef::parse(bytes!($raw)).unwrap();
+                assert_eq!(line.args().collect::<Vec<&[u8]>>(), params);
+            })
+        )
+        macro_rules! s(($val:expr) => (Some(bytes!($val))))
+
+        t!("foo bar baz asdf", [], None, "foo", ["bar", "baz", "asdf"]);
+        t!(":coolguy foo bar baz asdf", [], s!("coolguy"), "foo", ["bar", "baz", "asdf"]);
+        t!("foo bar baz :asdf quux", [], None, "foo", ["bar", "baz", "asdf quux"]);
+        t!("foo bar baz :", [], None, "foo", ["bar", "baz", ""]);
+        t!("foo bar baz ::asdf", [], None, "foo", ["bar", "baz", ":asdf"]);
+        t!(":coolguy foo bar baz :asdf quux", [], s!("coolguy"), "foo",
+           ["bar", "baz", "asdf quux"]);
+        t!(":coolguy foo bar baz :  asdf quux ", [], s!("coolguy"), "foo",
+           ["bar", "baz", "  asdf quux "]);
+        t!(":coolguy PRIVMSG bar :lol :) ", [], s!("coolguy"), "PRIVMSG", ["bar", "lol :) "]);
+        t!(":coolguy foo bar baz :", [], s!("coolguy"), "foo", ["bar", "baz", ""]);
+        t!(":coolguy foo bar baz :  ", [], s!("coolguy"), "foo", ["bar", "baz", "  "]);
+        t!("@a=b;c=32;k;rt=ql7 foo", ["a" => s!("b"), "c" => s!("32"), "k" => None,
+                                      "rt" => s!("ql7")], None, "foo", []);
+        t!("@a=b\\\\and\\nk;c=72\\s45;d=gh\\:764 foo",
+           ["a" => s!("b\\and\nk"), "c" => s!("72 45"), "d" => s!("gh;764")], None, "foo", []);
+        t!("@c;h=;a=b :quux ab cd", ["c" => None, "h" => None, "a" => s!("b")], s!("quux"),
+           "ab", ["cd"]);
+        t!(":src JOIN #chan", [], s!("src"), "JOIN", ["#chan"]);
+        t!(":src JOIN :#chan", [], s!("src"), "JOIN", ["#chan"]);
+        t!(":src AWAY", [], s!("src"), "AWAY", []);
+        t!(":src AWAY ", [], s!("src"), "AWAY", []);
+        t!(":cool\tguy foo bar baz", [], s!("cool\tguy"), "foo", ["bar", "baz"]);
+        t!(":coolguy!ag@net\x035w\x03ork.admin PRIVMSG foo :bar baz", [],
+           s!("coolguy!ag@net\x035w\x03ork.admin"), "PRIVMSG", ["foo", "bar baz"]);
+        t!(":coolguy!~ag@n\x02et\x0305w\x0fork.admin PRIVMSG foo :bar baz", [],
+           s!("coolguy!~ag@n\x02et\x0305w\x0fork.admin"), "PRIVMSG", ["foo", "bar baz"]);
+        t!("@tag1=value1;tag2;vendor1/tag3=value2;vendor2/tag4= :irc.example.com COMMAND \
+            param1 param2 :param3 param3",
+           ["tag1" => s!("value1"), "tag2" => None, "vendor1/tag3" => s!("value2"),
+            "vendor2/tag4" => None], s!("irc.example.com"), "COMMAND",
+           ["param1", "param2", "param3 param3"]);
+        t!(":irc.example.com COMMAND param1 param2 :param3 param3", [], s!("irc.example.com"),
+           "COMMAND", ["param1", "param2", "param3 param3"]);
+        t!("@tag1=value1;tag2;vendor1/tag3=value2;vendor2/tag4 COMMAND param1 param2 \
+            :param3 param3",
+           ["tag1" => s!("value1"), "tag2" => None, "vendor1/tag3" => s!("value2"),
+            "vendor2/tag4" => None], None, "COMMAND", ["param1", "param2", "param3 param3"]);
+        t!("COMMAND", [], None, "COMMAND", []);
+        t!("@foo=\\\\\\\\\\:\\\\s\\s\\r\\n COMMAND", ["foo" => s!("\\\\;\\s \r\n")], None,
+           "COMMAND", []);
+        t!(":gravel.mozilla.org 432  #momo :Erroneous Nickname: Illegal characters", [],
+           s!("gravel.mozilla.org"), "432", ["#momo", "Erroneous Nickname: Illegal characters"]);
+        t!(":gravel.mozilla.org MODE #tckk +n ", [], s!("gravel.mozilla.org"), "MODE",
+           ["#tckk", "+n"]);
+        t!(":services.esper.net MODE #foo-bar +o foobar  ", [], s!("services.esper.net"),
+           "MODE", ["#foo-bar", "+o", "foobar"]);
+        t!("@tag1=value\\\\ntest COMMAND", ["tag1" => s!("value\\ntest")], None, "COMMAND", []);
+        t!("@tag1=value\\1 COMMAND", ["tag1" => s!("value1")], None, "COMMAND", []);
+        t!("@tag1=value1\\ COMMAND", ["tag1" => s!("value1")], None, "COMMAND", []);
+        t!("@tag1=1;tag2=3;tag3=4;tag1=5 COMMAND",
+           ["tag1" => s!("1"), "tag2" => s!("3"), "tag3" => s!("4"), "tag1" => s!("5")], None,
+           "COMMAND", []);
+        t!("@tag1=1;tag2=3;tag3=4;tag1=5;vendor/tag2=8 COMMAND",
+           ["tag1" => s!("1"), "tag2" => s!("3"), "tag3" => s!("4"), "tag1" => s!("5"),
+            "vendor/tag2" => s!("8")], None, "COMMAND", []);
+        t!(":SomeOp MODE #channel :+i", [], s!("SomeOp"), "MODE", ["#channel", "+i"]);
+        t!(":SomeOp MODE #channel +oo SomeUser :AnotherUser", [], s!("SomeOp"), "MODE",
+           ["#channel", "+oo", "SomeUser", "AnotherUser"]);
+    }
+
+    #[test]
+    fn msg_join() {
+        macro_rules! t(
+            ([$($key:expr => $val:expr),*], $src:expr, $verb:expr, [$($param:expr),*],
+             $raw:expr) => ({
+                let tags = vec![$(Tag::new(bytes!($key), $val)),*];
+                let src = $src.map(|s: &[u8]| Prefix::parse(s));
+                let params: Vec<&[u8]> = vec![$(bytes!($param)),*];
+                let line = Line::new(tags, src, IRCCmd($verb.into_maybe_owned()),
+                                     params.as_slice());
+                assert_eq!(line.to_raw().as_slice(), bytes!($raw));
+                assert_eq!(Line::parse(bytes!($raw)), Ok(line));
+            })
+        )
+        macro_rules! s(($val:expr) => (Some(bytes!($val))))
+
+        t!([], None, "foo", ["bar", "baz", "asdf"], "foo bar baz asdf");
+        t!([], s!("src"), "AWAY", [], ":src AWAY");
+        t!([], s!("src"), "AWAY", [""], ":src AWAY :");
+        t!([], None, "foo", ["bar", "baz", "asdf quux"], "foo bar baz :asdf quux");
+        t!([], None, "foo", ["bar", "baz", ""], "foo bar baz :");
+        t!([], None, "foo", ["bar", "baz", ":asdf"], "foo bar baz ::asdf");
+        t!([], s!("coolguy"), "foo", ["bar", "baz", "asdf quux"],
+           ":coolguy foo bar baz :asdf quux");
+        t!([], s!("coolguy"), "foo", ["bar", "baz", "  asdf quux "],
+           ":coolguy foo bar baz :  asdf quux ");
+        t!([], s!("coolguy"), "PRIVMSG", ["bar", "lol :) "], ":coolguy PRIVMSG bar :lol :) ");
+        t!([], s!("coolguy"), "foo", ["bar", "baz", ""], ":coolguy foo bar baz :");
+        t!([], s!("coolguy"), "foo", ["bar", "baz", "  "], ":coolguy foo bar baz :  ");
+        t!([], s!("coolguy"), "foo", ["b\tar", "baz"], ":coolguy foo b\tar baz");
+        t!(["a" => s!("b"), "c" => s!("32"), "k" => None, "rt" => s!("ql7")], None, "foo", [],
+           "@a=b;c=32;k;rt=ql7 foo");
+        t!(["a" => s!("b\\and\nk"), "c" => s!("72 45"), "d" => s!("gh;764")], None, "foo", [],
+           "@a=b\\\\and\\nk;c=72\\s45;d=gh\\:764 foo");
+        // an empty tag value is the same as none, so this is written without the '='
+        t!(["c" => None, "h" => s!(""), "a" => s!("b")], s!("quux"), "ab", ["cd"],
+           "@c;h;a=b :quux ab cd");
+        t!([], s!("src"), "JOIN", ["#chan"], ":src JOIN #chan");
+    }
+
+    #[test]
+    fn userhost_split() {
+        macro_rules! t(
+            ($raw:expr, $nick:expr, $user:expr, $host:expr) => ({
+                let user = User::parse(bytes!($raw));
+                assert_eq!(user.nick(), bytes!($nick));
+                assert_eq!(user.user(), $user);
+                assert_eq!(user.host(), $host);
+                assert_eq!(Prefix::parse(bytes!($raw)), UserPrefix(user));
+            })
+        )
+        macro_rules! s(($val:expr) => (Some(bytes!($val))))
+
+        t!("coolguy", "coolguy", None, None);
+        t!("coolguy!ag@127.0.0.1", "coolguy", s!("ag"), s!("127.0.0.1"));
+        t!("coolguy!~ag@localhost", "coolguy", s!("~ag"), s!("localhost"));
+        t!("coolguy@127.0.0.1", "coolguy", None, s!("127.0.0.1"));
+        t!("coolguy!ag", "coolguy", s!("ag"), None);
+        t!("coolguy!ag@net\x035w\x03ork.admin", "coolguy", s!("ag"), s!("net\x035w\x03ork.admin"));
+        t!("coolguy!~ag@n\x02et\x0305w\x0fork.admin", "coolguy", s!("~ag"),
+           s!("n\x02et\x0305w\x0fork.admin"));
+    }
+
+    // Lines that used to serialize differently from how they were parsed
+    #[test]
+    fn to_raw_round_trip() {
+        let lines = [bytes!("TOPIC #rust :"), bytes!("PRIVMSG #rust ::)"),
+                     bytes!("PRIVMSG #rust ::"), bytes!("PRIVMSG #rust :\x01ACTION\x01"),
+                     bytes!("PRIVMSG bob :\x01PING 1 2\x01"), bytes!("005 me :are supported")];
+        for &raw in lines.iter() {
+            let line = Line::parse(raw).unwrap();
+            assert_eq!(line.to_raw().as_slice(), raw);
+        }
+
+        // \x01 ends a CTCP message, so it's dropped from args on output
+        let ping = IRCCTCP(Vec::from_slice(bytes!("PING")), Vec::from_slice(bytes!("bob")));
+        let line = Line::new(Vec::new(), None, ping, [bytes!("1\x012")]);
+        assert_eq!(line.to_raw().as_slice(), bytes!("PRIVMSG bob :\x01PING 12\x01"));
+        let line = Line::parse(bytes!("PRIVMSG bob :\x01PING 1\x01 2")).unwrap();
+        assert_eq!(line.args().to_vec(), vec![bytes!("1")]);
+
+        // as are bytes that would end the line
+        let line = Line::new(Vec::new(), None, IRCCmd("PRIVMSG".into_maybe_owned()),
+                             [bytes!("#rust"), bytes!("hi\r\nQUIT")]);
+        assert_eq!(line.to_raw().as_slice(), bytes!("PRIVMSG #rust :hiQUIT"));
+    }
+
+    #[test]
+    fn parse_line_lenient() {
+        let line = Line::parse_lenient(bytes!(":bob f23 :some args")).unwrap();
+        assert_eq!(*line.command(), IRCCmd("f23".into_maybe_owned()));
+        assert_eq!(line.args().to_vec(), vec![bytes!("some args")]);
+
+        let line = Line::parse_lenient(bytes!("PRIVMSG #rust :\x01ACTION waves\x01")).unwrap();
+        assert_eq!(*line.command(), IRCAction(Vec::from_slice(bytes!("#rust"))));
+
+        assert_eq!(Line::parse_lenient(bytes!(":bob  f23")),
+                   Err(ParseError{ kind: MissingCommand, offset: 5 }));
+        assert_eq!(Line::parse_lenient(bytes!(":bob föo")),
+                   Err(ParseError{ kind: InvalidCommand, offset: 6 }));
+    }
+
+    #[test]
+    fn parse_line_ref() {
+        let raw = bytes!("@time=12:00;id=7 :bob!user@host PRIVMSG #rust :\x01ACTION waves\x01");
+        let line = LineRef::parse(raw).unwrap();
+        assert_eq!(line.tags_raw(), Some(bytes!("time=12:00;id=7")));
+        assert_eq!(line.tag(bytes!("id")), Some(Tag::new(bytes!("id"), Some(bytes!("7")))));
+        assert_eq!(line.tag(bytes!("i")), None);
+        assert_eq!(line.prefix(), Some(bytes!("bob!user@host")));
+        assert_eq!(line.command(), bytes!("PRIVMSG"));
+        assert_eq!(line.numeric(), None);
+        assert_eq!(line.args().collect::<Vec<&[u8]>>(),
+                   vec![bytes!("#rust"), bytes!("\x01ACTION waves\x01")]);
+        assert_eq!(line.arg(1), Some(bytes!("\x01ACTION waves\x01")));
+        assert_eq!(line.arg(2), None);
+        assert_eq!(line.to_line(), Line::parse(raw));
+
+        let line = LineRef::parse(bytes!("001 me  :Welcome")).unwrap();
+        assert_eq!(line.numeric(), Some(1));
+        assert_eq!(line.args().collect::<Vec<&[u8]>>(),
+                   vec![bytes!("me"), bytes!("Welcome")]);
+
+        assert!(LineRef::parse(bytes!(":bob f23")).is_err());
+        assert!(LineRef::parse_lenient(bytes!(":bob f23")).is_ok());
+        assert_eq!(LineRef::parse(bytes!(":bob PRIVMSG :\x01VERSION\x01")).unwrap().to_line(),
+                   Err(ParseError{ kind: MissingCTCPDest, offset: 13 }));
+    }
+
+    static BenchLine: &'static [u8] = bytes!("@time=2014-05-01T12:00:00.000Z;account=bob \
+        :bob!~bob@host.example.com PRIVMSG #rust :hello there, this is a fairly typical line");
+
+    #[bench]
+    fn bench_parse_line(b: &mut Bencher) {
+        b.bytes = BenchLine.len() as u64;
+        b.iter(|| Line::parse(BenchLine).unwrap());
+    }
+
+    #[bench]
+    fn bench_parse_line_ref(b: &mut Bencher) {
+        b.bytes = BenchLine.len() as u64;
+        b.iter(|| LineRef::parse(BenchLine).unwrap());
+    }
+
+    #[test]
+    fn line_buffers() {
+        // apart from the tags, a Line holds two Vecs: one copy of the line after
+        // the tags, and the arg ranges into it. Known commands aren't copied.
+        let line = Line::parse(BenchLine).unwrap();
+        let idx = BenchLine.position_elem(&(' ' as u8)).unwrap();
+        assert_eq!(line.buf.as_slice(), BenchLine.slice_from(idx+1));
+        assert_eq!(line.tags.len(), 2);
+        assert_eq!(line.args.len(), 2);
+        match *line.command() {
+            IRCCmd(str::Slice(_)) => (),
+            ref cmd => fail!("{} was copied", cmd)
+        }
+        assert_eq!(line.prefix_raw(), Some(bytes!("bob!~bob@host.example.com")));
+        assert_eq!(line.args().get(0), bytes!("#rust"));
+        assert_eq!(line.args().get(1), bytes!("hello there, this is a fairly typical line"));
+
+        // CTCP ranges are shifted the same way
+        let line = Line::parse(bytes!("@a=b :bob PRIVMSG #rust :\x01ACTION waves\x01")).unwrap();
+        assert_eq!(line.buf.as_slice(), bytes!(":bob PRIVMSG #rust :\x01ACTION waves\x01"));
+        assert_eq!(*line.command(), IRCAction(Vec::from_slice(bytes!("#rust"))));
+        assert_eq!(line.args().get(0), bytes!("waves"));
+    }
+
+    #[test]
+    fn tag_key_parts() {
+        let tag = Tag::parse(bytes!("+example.com/foo=bar"));
+        assert!(tag.is_client_only());
+        assert_eq!(tag.vendor(), Some(bytes!("example.com")));
+        assert_eq!(tag.name(), bytes!("foo"));
+        assert_eq!(tag.value, Some(Vec::from_slice(bytes!("bar"))));
+
+        let tag = Tag::parse(bytes!("account"));
+        assert!(!tag.is_client_only());
+        assert_eq!(tag.vendor(), None);
+        assert_eq!(tag.name(), bytes!("account"));
+        assert_eq!(tag.value, None);
+    }
+
+    #[test]
+    fn keepalive() {
+        let (mut conn, _rx) = test_conn();
+        let (interval, timeout) = (60000, 30000);
+        let ms = 1000000;
+        let now = time::precise_time_ns();
+
+        // nothing is sent before registration
+        conn.last_recv = now - 61000 * ms;
+        assert!(conn.keepalive(interval, timeout));
+        assert!(conn.ping_sent.is_none());
+        assert_eq!(sent(&conn), Vec::<String>::new());
+
+        conn.logged_in = true;
+        conn.last_recv = now;
+        assert!(conn.keepalive(interval, timeout));
+        assert_eq!(sent(&conn), Vec::<String>::new());
+
+        // ping once the connection has been idle for the interval
+        conn.last_recv = now - 61000 * ms;
+        assert!(conn.keepalive(interval, timeout));
+        let token = match conn.ping_sent {
+            Some((ref token, _)) => str::from_utf8(token.as_slice()).unwrap().to_owned(),
+            None => fail!("no PING sent")
+        };
+        assert!(token.as_slice().starts_with("LAG"));
+        assert_eq!(sent(&conn), vec![format!("PING {}", token)]);
+        // but not again while that one is outstanding
+        assert!(conn.keepalive(interval, timeout));
+        assert_eq!(sent(&conn), Vec::<String>::new());
+
+        // give up if nothing at all arrives before the timeout
+        conn.ping_sent = Some((Vec::from_slice(token.as_bytes()), now - 31000 * ms));
+        assert!(!conn.keepalive(interval, timeout));
+
+        // if something else arrived, the server is alive and we just ping again later
+        conn.last_recv = now - 30000 * ms;
+        assert!(conn.keepalive(interval, timeout));
+        assert!(conn.ping_sent.is_none());
+        assert_eq!(sent(&conn), Vec::<String>::new());
+    }
+
+    #[test]
+    fn send_targets() {
+        let (mut conn, _rx) = test_conn();
+        assert!(conn.join(bytes!("#a,#b"), []).is_ok());
+        assert!(conn.part(bytes!("#a"), []).is_ok());
+        assert!(conn.part(bytes!("#b"), bytes!("bye now")).is_ok());
+        assert!(conn.set_nick(bytes!("newnick")).is_ok());
+        assert_eq!(sent(&conn), vec!["NICK newnick".to_owned(), "JOIN #a,#b".to_owned(),
+                                     "PART #a".to_owned(), "PART #b :bye now".to_owned()]);
+
+        // targets are never sent as a trailing param
+        assert_eq!(conn.join(bytes!("#a #b"), []), Err(InvalidMiddleParam(0)));
+        assert_eq!(conn.join(bytes!(":#a"), []), Err(InvalidMiddleParam(0)));
+        assert_eq!(conn.join(bytes!("#a"), bytes!("a key")), Err(InvalidMiddleParam(1)));
+        assert_eq!(conn.part(bytes!("#a #b"), []), Err(InvalidMiddleParam(0)));
+        assert_eq!(conn.set_nick(bytes!("bad nick")), Err(InvalidMiddleParam(0)));
+        assert_eq!(conn.set_nick(bytes!(":x")), Err(InvalidMiddleParam(0)));
+        assert_eq!(conn.set_nick([]), Err(InvalidMiddleParam(0)));
+        assert_eq!(sent(&conn), Vec::<String>::new());
+        assert_eq!(conn.wanted_nick.as_slice(), bytes!("newnick"));
+        assert!(conn.chan_keys.is_empty());
+    }
+
+    #[test]
+    fn send_text_budget() {
+        let (mut conn, _rx) = test_conn();
+        // until we've seen our hostmask, assume the longest host and a ~ on the user
+        assert_eq!(conn.relay_prefix_len(), 1 + 2 + 1 + 5 + 1 + 63 + 1);
+        conn.user = User::new(bytes!("me"), Some(bytes!("~user")), Some(bytes!("host.example")));
+        let prefix = conn.relay_prefix_len();
+        assert_eq!(prefix, ":me!~user@host.example ".len());
+
+        let mut msg = Vec::new();
+        for _ in range(0, 200) {
+            msg.push_all(bytes!("hello "));
+        }
+        msg.pop();
+        let msg = str::from_utf8(msg.as_slice()).unwrap().to_owned();
+
+        assert!(conn.privmsg(bytes!("#rust"), msg.as_bytes()).is_ok());
+        let lines = sent(&conn);
+        assert_eq!(lines.len(), 3);
+        for line in lines.iter() {
+            assert!(line.as_slice().starts_with("PRIVMSG #rust :"));
+            assert!(line.len() + prefix <= 510);
+        }
+        // the first line is as full as whole words allow
+        assert!(lines.get(0).len() + prefix > 510 - "hello ".len());
+        let full = lines.get(0).len() - 15;
+        let texts: Vec<&str> = lines.iter().map(|l| l.as_slice().slice_from(15)).collect();
+        assert_eq!(texts.as_slice().connect(" "), msg);
+
+        // a longer target leaves less room for text
+        assert!(conn.privmsg(bytes!("#a-much-longer-channel"), msg.as_bytes()).is_ok());
+        let lines = sent(&conn);
+        assert_eq!(lines.len(), 3);
+        assert!(lines.iter().all(|l| l.len() + prefix <= 510));
+        assert!(lines.get(0).len() - 32 < full);
+
+        // every line of a CTCP message is framed
+        assert!(conn.action(bytes!("#rust"), msg.as_bytes()).is_ok());
+        let lines = sent(&conn);
+        assert_eq!(lines.len(), 3);
+        let mut texts = Vec::new();
+        for line in lines.iter() {
+            let line = line.as_slice();
+            assert!(line.starts_with("PRIVMSG #rust :\x01ACTION "));
+            assert!(line.ends_with("\x01"));
+            assert!(line.len() + prefix <= 510);
+            texts.push(line.slice(23, line.len()-1));
+        }
+        assert_eq!(texts.as_slice().connect(" "), msg);
+
+        // no room for any text at all
+        let mut dst = vec!['#' as u8];
+        dst.grow(490, &('a' as u8));
+        assert_eq!(conn.privmsg(dst.as_slice(), bytes!("hi")), Err(TooLong));
+        assert_eq!(sent(&conn), Vec::<String>::new());
+    }
+
+    #[test]
+    fn failover_order() {
+        let servers = vec![Server::new("a.example.com", 6667), Server::new("b.example.com", 6667),
+                           Server::new("c.example.com", 6667)];
+        let mut failover = Failover::new(servers);
+        assert_eq!(failover.current().host, "a.example.com");
+        assert!(failover.next());
+        assert_eq!(failover.current().host, "b.example.com");
+        assert!(failover.next());
+        assert_eq!(failover.current().host, "c.example.com");
+        // every server has failed, so back off before going round again
+        assert!(!failover.next());
+        assert_eq!(failover.current().host, "a.example.com");
+        failover.new_round();
+        assert!(failover.next());
+        assert_eq!(failover.current().host, "b.example.com");
+
+        // after registering, a later failure gets a full round again
+        failover.new_round();
+        assert!(failover.next());
+        assert!(failover.next());
+        assert_eq!(failover.current().host, "a.example.com");
+        assert!(!failover.next());
+    }
+
+    #[test]
+    fn failover_tls() {
+        let mut primary = Server::new("irc.example.com", 6697);
+        primary.tls = true;
+        let mut backup = Server::new("backup.example.net", 6697);
+        backup.tls = true;
+        let mut failover = Failover::new(vec![primary, backup, Server::new("10.0.0.1", 6667)]);
+        let mut cfg = tls::Config::new();
+        cfg.sni = Some("irc.example.org");
+
+        assert_eq!(failover.tls(Some(&cfg), false).map(|c| c.sni), Some(Some("irc.example.org")));
+        // a redirect goes to some other host
+        assert_eq!(failover.tls(Some(&cfg), true).map(|c| c.sni), Some(None));
+        assert!(failover.next());
+        assert_eq!(failover.tls(Some(&cfg), false).map(|c| c.sni), Some(None));
+        assert_eq!(failover.tls(None, false).map(|c| c.verify), Some(true));
+        assert!(failover.next());
+        assert!(failover.tls(Some(&cfg), false).is_none());
+    }
+
+    #[test]
+    fn reconnect_delay() {
+        let mut policy = Reconnect::new();
+        policy.initial_delay = 1000;
+        policy.max_delay = 10000;
+        policy.max_attempts = Some(5);
+        for (attempt, &base) in [1000u64, 2000, 4000, 8000, 10000].iter().enumerate() {
+            let delay = policy.delay(attempt).unwrap();
+            assert!(delay >= base / 2 && delay <= base, "attempt {}: {}", attempt, delay);
+        }
+        assert_eq!(policy.delay(5), None);
+
+        policy.max_attempts = None;
+        let delay = policy.delay(100).unwrap();
+        assert!(delay >= 5000 && delay <= 10000);
+    }
+
+    #[test]
+    fn throttle_penalty() {
+        let throttle = Throttle::new();
+        let mut clock = 0u64;
+        let now = 100000;
+        // the first 5 lines fit within the window
+        for _ in range(0, 5) {
+            assert_eq!(throttle.wait(&mut clock, now, false), 0);
+        }
+        assert_eq!(throttle.wait(&mut clock, now, false), 2000);
+        assert_eq!(throttle.wait(&mut clock, now, false), 4000);
+        // urgent lines aren't delayed, but still advance the clock
+        assert_eq!(throttle.wait(&mut clock, now, true), 0);
+        assert_eq!(throttle.wait(&mut clock, now, false), 8000);
+        // once the clock has drained we can burst again
+        let now = clock;
+        for _ in range(0, 5) {
+            assert_eq!(throttle.wait(&mut clock, now, false), 0);
+        }
+    }
+
+    #[test]
+    fn reconnect_wait_runs_commands() {
+        let (mut conn, _rx) = test_conn();
+        let (tx, rx) = channel();
+        let mut count = 0u;
+        // procs run while we wait, and the wait still lasts the whole delay
+        tx.send(proc(_: &mut Conn, count: &mut uint) { *count += 1; });
+        let start = time::precise_time_ns();
+        assert_eq!(wait_to_reconnect(&mut conn, 100, Some(&rx), &mut count).ok(), Some(true));
+        assert!(time::precise_time_ns() - start >= 100 * 1000000);
+        assert_eq!(count, 1);
+
+        // quitting cancels the reconnect without waiting out the delay
+        tx.send(proc(conn: &mut Conn, _: &mut uint) { conn.quit([]).unwrap(); });
+        let start = time::precise_time_ns();
+        assert_eq!(wait_to_reconnect(&mut conn, 60000, Some(&rx), &mut count).ok(), Some(false));
+        assert!(time::precise_time_ns() - start < 30000 * 1000000);
+
+        // the delay is still waited out once nobody can send commands
+        conn.quitting = false;
+        drop(tx);
+        assert_eq!(wait_to_reconnect(&mut conn, 50, Some(&rx), &mut count).ok(), Some(true));
+        assert_eq!(wait_to_reconnect(&mut conn, 50, None, &mut count).ok(), Some(true));
+    }
+
+    #[test]
+    fn tls_write_while_idle() {
+        let listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut acceptor = listener.listen().unwrap();
+        let (line_tx, line_rx) = channel();
+        spawn(proc() {
+            let mut ctx = SslContext::new(Sslv23).unwrap();
+            assert!(ctx.set_certificate_file(&Path::new("test/cert.pem"), PEM).is_none());
+            assert!(ctx.set_private_key_file(&Path::new("test/key.pem"), PEM).is_none());
+            let stream = acceptor.accept().unwrap();
+            let mut stream = BufferedStream::new(SslStream::new_server(&ctx, stream).unwrap());
+            // the handshake is written while the client's reader has nothing to read
+            line_tx.send(stream.read_line().unwrap());
+            line_tx.send(stream.read_line().unwrap());
+            // long after the first read timeout, reads and writes still work
+            timer::sleep(300);
+            assert!(stream.write(bytes!("PING :x\r\n")).and_then(|_| stream.flush()).is_ok());
+            line_tx.send(stream.read_line().unwrap());
+            line_tx.send(stream.read_line().unwrap());
+        });
+
+        let (cmd_tx, cmd_rx) = channel();
+        spawn(proc() {
+            timer::sleep(600);
+            cmd_tx.send(proc(conn: &mut Conn, _: &mut ()) {
+                conn.privmsg(bytes!("#a"), bytes!("idle")).unwrap();
+            });
+        });
+
+        let ca = Path::new("test/cert.pem");
+        let mut cfg = tls::Config::new();
+        cfg.ca_file = Some(&ca);
+        let mut opts = Options::new("localhost", port);
+        opts.tls = Some(cfg);
+        opts.nick = "me";
+        opts.commands = Some(cmd_rx);
+        let _ = connect(opts, (), |_, _, _| ());
+
+        let lines: Vec<String> = line_rx.iter().collect();
+        assert_eq!(lines, vec!["NICK me\r\n".to_owned(),
+                               "USER ircuser 8 * :rust-irclib user\r\n".to_owned(),
+                               "PONG x\r\n".to_owned(), "PRIVMSG #a idle\r\n".to_owned()]);
+    }
+
+    #[test]
+    fn writer_throttle_priority() {
+        let queue = Arc::new(Mutex::new(WriteQueue::new()));
+        let (tx, rx) = channel();
+        fn push(queue: &Arc<Mutex<WriteQueue>>, tx: &Sender<()>, line: &[u8], priority: Priority) {
+            queue.lock().push(line.to_owned(), priority);
+            tx.send(());
+        }
+        push(&queue, &tx, bytes!("PRIVMSG #a :1\r\n"), NormalPriority);
+        push(&queue, &tx, bytes!("PRIVMSG #a :2\r\n"), NormalPriority);
+        // the wakeup for the PONG, which is queued while the writer waits
+        tx.send(());
+        drop(tx);
+        let throttle = Throttle { penalty: 200, window: 200 };
+        let clock = Cell::new(0u64);
+        let mut waits = Vec::new();
+        let mut out = MemWriter::new();
+        write_lines_with(&mut out, rx, queue.clone(), Some(throttle), || clock.get(), |ms| {
+            // the writer has sent the first line and is waiting to send another
+            if waits.is_empty() {
+                queue.lock().push(bytes!("PONG :x\r\n").to_owned(), HighPriority);
+            }
+            waits.push(ms);
+            clock.set(clock.get() + ms);
+        }).unwrap();
+        assert_eq!(str::from_utf8(out.unwrap().as_slice()),
+                   Some("PRIVMSG #a :1\r\nPONG :x\r\nPRIVMSG #a :2\r\n"));
+        // the PONG isn't delayed, but counts towards the penalty for the next line
+        assert_eq!(waits.as_slice(), &[200u64, 200]);
+    }
+
+    #[test]
+    fn write_queue_priority() {
+        let mut queue = WriteQueue::new();
+        queue.push(bytes!("PRIVMSG #a :1").to_owned(), NormalPriority);
+        queue.push(bytes!("PRIVMSG #a :bulk").to_owned(), LowPriority);
+        queue.push(bytes!("PRIVMSG #a :2").to_owned(), NormalPriority);
+        queue.push(bytes!("PONG :x").to_owned(), HighPriority);
+        assert_eq!(queue.len(), 4);
+        assert_eq!(queue.pop(), Some((bytes!("PONG :x").to_owned(), HighPriority)));
+        assert_eq!(queue.pop(), Some((bytes!("PRIVMSG #a :1").to_owned(), NormalPriority)));
+
+        assert_eq!(queue.clear(LowPriority), 1);
+        assert_eq!(queue.len(), 1);
+        assert_eq!(queue.pop(), Some((bytes!("PRIVMSG #a :2").to_owned(), NormalPriority)));
+        assert_eq!(queue.pop(), None);
+
+        assert_eq!(Priority::for_command(&IRCCmd("PONG".into_maybe_owned())), HighPriority);
+        assert_eq!(Priority::for_command(&IRCCmd("PRIVMSG".into_maybe_owned())), NormalPriority);
+        assert_eq!(Priority::for_command(&IRCCode(1)), NormalPriority);
+    }
+
+    #[test]
+    fn command_show() {
+        assert_eq!(format!("{}", IRCCode(433)), "IRCCode(ERR_NICKNAMEINUSE)".to_strbuf());
+        assert_eq!(format!("{}", IRCCode(5)), "IRCCode(RPL_ISUPPORT)".to_strbuf());
+        assert_eq!(format!("{}", IRCCode(999)), "IRCCode(999)".to_strbuf());
+        assert_eq!(IRCCode(433).numeric().map(|n| n.is_error()), Some(true));
+        assert_eq!(IRCCmd("PRIVMSG".into_maybe_owned()).numeric(), None);
     }
 }
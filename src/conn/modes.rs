@@ -0,0 +1,165 @@
+//! Parsing of MODE changes
+
+use conn::{IRCCmd, Line};
+use conn::support::ServerSupport;
+
+/// A single mode being set or unset
+#[deriving(Eq,Clone,Show)]
+pub struct ModeChange {
+    /// `true` for `+`, `false` for `-`
+    pub set: bool,
+    /// The mode character
+    pub mode: char,
+    /// The mode's argument, if it takes one
+    pub arg: Option<Vec<u8>>
+}
+
+/// The changes from a MODE line
+#[deriving(Eq,Clone,Show)]
+pub enum Modes {
+    /// Modes changed on the given channel
+    ChannelModes(Vec<u8>, Vec<ModeChange>),
+    /// Modes changed on the given user (usually ourselves)
+    UserModes(Vec<u8>, Vec<ModeChange>)
+}
+
+impl Modes {
+    /// Parses a MODE line. Returns None if the line isn't a MODE or has no modes.
+    ///
+    /// The target is a channel if it starts with one of the server's CHANTYPES.
+    pub fn from_line(line: &Line, support: &ServerSupport) -> Option<Modes> {
+        // :src MODE target modes [args...]
+        match *line.command() {
+            IRCCmd(ref s) if "MODE" == s.as_slice() => (),
+            _ => return None
+        }
+        if line.args().len() < 2 {
+            return None;
+        }
+        let target = Vec::from_slice(line.args().get(0));
+        let modes = line.args().get(1);
+        let args = line.args().slice_from(2).to_vec();
+        if support.is_channel(target.as_slice()) {
+            Some(ChannelModes(target, parse_channel_modes(modes, args.as_slice(), support)))
+        } else {
+            Some(UserModes(target, parse_user_modes(modes, args.as_slice())))
+        }
+    }
+}
+
+/// Parses channel mode changes such as `+ol-k nick key`.
+///
+/// Which modes take an argument is decided by the server's PREFIX and CHANMODES:
+/// prefix modes and type A and B modes always do, type C modes only do when set,
+/// and anything else never does. A mode whose argument is missing gets None.
+pub fn parse_channel_modes<V: Vector<u8>>(modes: &[u8], args: &[V], support: &ServerSupport)
+                                         -> Vec<ModeChange> {
+    let chanmodes = support.chanmodes();
+    parse(modes, args, |set, mode| {
+        support.prefixes().iter().any(|&(m, _)| m == mode) ||
+        chanmodes.list.contains(&mode) || chanmodes.param.contains(&mode) ||
+        (set && chanmodes.set_param.contains(&mode))
+    })
+}
+
+/// Parses user mode changes such as `+iw`.
+///
+/// Only the server notice mask, `+s`, takes an argument, and only if one is given.
+pub fn parse_user_modes<V: Vector<u8>>(modes: &[u8], args: &[V]) -> Vec<ModeChange> {
+    parse(modes, args, |set, mode| set && mode == 's')
+}
+
+fn parse<V: Vector<u8>>(modes: &[u8], args: &[V], takes_arg: |bool, char| -> bool)
+                       -> Vec<ModeChange> {
+    let mut args = args.iter().map(|a| a.as_slice());
+    let mut set = true;
+    let mut changes = Vec::new();
+    for &b in modes.iter() {
+        let mode = b as char;
+        match mode {
+            '+' => set = true,
+            '-' => set = false,
+            _ => {
+                let arg = if takes_arg(set, mode) { args.next() } else { None };
+                changes.push(ModeChange {
+                    set: set,
+                    mode: mode,
+                    arg: arg.map(|a| Vec::from_slice(a))
+                });
+            }
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ModeChange, Modes, ChannelModes, UserModes, parse_channel_modes};
+    use conn::Line;
+    use conn::support::ServerSupport;
+
+    macro_rules! b(
+        ($args:tt) => (
+            { static b: &'static [u8] = bytes!($args); b }
+        )
+    )
+
+    fn change(set: bool, mode: char, arg: Option<&[u8]>) -> ModeChange {
+        ModeChange { set: set, mode: mode, arg: arg.map(|a| Vec::from_slice(a)) }
+    }
+
+    #[test]
+    fn test_rfc_defaults() {
+        let support = ServerSupport::new();
+        let changes = parse_channel_modes(b!("+ntlk-l+bo-kv"),
+                                          [b!("10"), b!("key"), b!("*!*@spam"), b!("alice"),
+                                           b!("key"), b!("bob")],
+                                          &support);
+        assert_eq!(changes, vec!(change(true, 'n', None), change(true, 't', None),
+                                 change(true, 'l', Some(b!("10"))),
+                                 change(true, 'k', Some(b!("key"))),
+                                 change(false, 'l', None),
+                                 change(true, 'b', Some(b!("*!*@spam"))),
+                                 change(true, 'o', Some(b!("alice"))),
+                                 change(false, 'k', Some(b!("key"))),
+                                 change(false, 'v', Some(b!("bob")))));
+
+        // missing args
+        let changes = parse_channel_modes(b!("+ko"), [b!("key")], &support);
+        assert_eq!(changes, vec!(change(true, 'k', Some(b!("key"))), change(true, 'o', None)));
+    }
+
+    #[test]
+    fn test_isupport() {
+        let mut support = ServerSupport::new();
+        support.parse_tokens([b!("PREFIX=(qohv)~@%+"), b!("CHANMODES=beIq,k,flj,CimnpstP")]);
+        let changes = parse_channel_modes(b!("+hfC-j"), [b!("bob"), b!("#overflow")], &support);
+        assert_eq!(changes, vec!(change(true, 'h', Some(b!("bob"))),
+                                 change(true, 'f', Some(b!("#overflow"))),
+                                 change(true, 'C', None), change(false, 'j', None)));
+    }
+
+    #[test]
+    fn test_from_line() {
+        let support = ServerSupport::new();
+        let line = Line::parse(b!(":bob!b@host MODE #rust +o-v alice bob")).unwrap();
+        assert_eq!(Modes::from_line(&line, &support),
+                   Some(ChannelModes(Vec::from_slice(b!("#rust")),
+                                     vec!(change(true, 'o', Some(b!("alice"))),
+                                          change(false, 'v', Some(b!("bob")))))));
+
+        let line = Line::parse(b!(":me MODE me :+iw-x")).unwrap();
+        assert_eq!(Modes::from_line(&line, &support),
+                   Some(UserModes(Vec::from_slice(b!("me")),
+                                  vec!(change(true, 'i', None), change(true, 'w', None),
+                                       change(false, 'x', None)))));
+
+        let line = Line::parse(b!(":me MODE me +s +cF")).unwrap();
+        assert_eq!(Modes::from_line(&line, &support),
+                   Some(UserModes(Vec::from_slice(b!("me")),
+                                  vec!(change(true, 's', Some(b!("+cF")))))));
+
+        let line = Line::parse(b!(":bob PRIVMSG #rust :+o alice")).unwrap();
+        assert_eq!(Modes::from_line(&line, &support), None);
+    }
+}
@@ -0,0 +1,384 @@
+//! SASL authentication support
+
+use std::fmt;
+use std::str;
+use serialize::base64::{ToBase64, FromBase64, STANDARD};
+use openssl::crypto::hash;
+use openssl::crypto::hash::SHA256;
+use openssl::crypto::hmac::HMAC;
+use rand;
+use rand::Rng;
+
+/// Size of a single AUTHENTICATE payload chunk
+static CHUNK_SIZE: uint = 400;
+
+/// The most SCRAM iterations we'll compute, so a server can't stall us in PBKDF2
+static MAX_SCRAM_ITERATIONS: uint = 100000;
+
+/// SASL mechanisms supported by the library
+#[deriving(Eq,Clone,Show)]
+pub enum Mechanism {
+    /// PLAIN: username and password sent in the clear (use with TLS)
+    Plain,
+    /// EXTERNAL: identity established by other means, e.g. a TLS client certificate
+    External,
+    /// SCRAM-SHA-256: salted challenge-response, the password never leaves the client
+    ScramSha256
+}
+
+impl Mechanism {
+    /// Returns the mechanism name as sent in AUTHENTICATE
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Plain => "PLAIN",
+            External => "EXTERNAL",
+            ScramSha256 => "SCRAM-SHA-256"
+        }
+    }
+}
+
+/// Credentials used for SASL authentication
+pub struct Credentials<'a> {
+    /// The mechanism to authenticate with
+    pub mechanism: Mechanism,
+    /// The account name.
+    /// For EXTERNAL this is the optional authorization identity and may be empty.
+    pub user: &'a str,
+    /// The account password. Ignored for EXTERNAL.
+    pub password: &'a str,
+}
+
+impl<'a> Credentials<'a> {
+    /// Returns new Credentials using the given mechanism
+    pub fn new(mechanism: Mechanism, user: &'a str, password: &'a str) -> Credentials<'a> {
+        Credentials {
+            mechanism: mechanism,
+            user: user,
+            password: password
+        }
+    }
+
+    /// Returns new Credentials for SASL EXTERNAL, which logs in with the
+    /// TLS client certificate configured in `tls::Config`.
+    pub fn external() -> Credentials<'a> {
+        Credentials::new(External, "", "")
+    }
+}
+
+/// Reasons SASL authentication can fail
+pub enum SaslError {
+    /// The server does not support SASL, or does not support the requested mechanism
+    Unsupported,
+    /// The server rejected authentication.
+    /// The first arg is the numeric (904-906), the second is the server's message.
+    AuthFailed(uint, String),
+    /// The server sent something we couldn't make sense of, or failed verification
+    ProtocolError(&'static str)
+}
+
+impl fmt::Show for SaslError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Unsupported => write!(f, "SASL mechanism not supported by server"),
+            AuthFailed(code, ref msg) => {
+                write!(f, "SASL authentication failed ({}): {}", code, *msg)
+            }
+            ProtocolError(msg) => write!(f, "SASL protocol error: {}", msg)
+        }
+    }
+}
+
+enum State {
+    Initial,
+    ScramServerFirst(String, String),
+    ScramServerFinal(Vec<u8>),
+    Finished
+}
+
+/// An in-progress SASL exchange
+pub struct Session {
+    mechanism: Mechanism,
+    user: String,
+    password: String,
+    nonce: String,
+    state: State,
+    buf: Vec<u8>,
+}
+
+impl Session {
+    /// Starts a new exchange for the given credentials
+    pub fn new(creds: &Credentials) -> Session {
+        let nonce = rand::task_rng().gen_ascii_str(24);
+        Session::with_nonce(creds, nonce.as_slice())
+    }
+
+    fn with_nonce(creds: &Credentials, nonce: &str) -> Session {
+        Session {
+            mechanism: creds.mechanism,
+            user: creds.user.to_owned(),
+            password: creds.password.to_owned(),
+            nonce: nonce.to_owned(),
+            state: Initial,
+            buf: Vec::new()
+        }
+    }
+
+    /// Returns the mechanism used by this exchange
+    pub fn mechanism(&self) -> Mechanism {
+        self.mechanism
+    }
+
+    /// Accumulates a single AUTHENTICATE argument from the server.
+    /// Returns the decoded challenge once the final chunk has been received.
+    pub fn push_chunk(&mut self, chunk: &[u8]) -> Option<Result<Vec<u8>, SaslError>> {
+        if chunk != bytes!("+") {
+            self.buf.push_all(chunk);
+        }
+        if chunk.len() == CHUNK_SIZE {
+            return None;
+        }
+        let res = match str::from_utf8(self.buf.as_slice()).map(|s| s.from_base64()) {
+            Some(Ok(v)) => Ok(v),
+            _ => Err(ProtocolError("invalid base64 in AUTHENTICATE"))
+        };
+        self.buf.clear();
+        Some(res)
+    }
+
+    /// Processes a decoded challenge and returns the response to send
+    pub fn step(&mut self, challenge: &[u8]) -> Result<Vec<u8>, SaslError> {
+        match (self.mechanism, ::std::mem::replace(&mut self.state, Finished)) {
+            (Plain, Initial) => {
+                let mut resp = Vec::new();
+                resp.push_all(self.user.as_bytes());
+                resp.push(0);
+                resp.push_all(self.user.as_bytes());
+                resp.push(0);
+                resp.push_all(self.password.as_bytes());
+                Ok(resp)
+            }
+            (External, Initial) => Ok(Vec::from_slice(self.user.as_bytes())),
+            (ScramSha256, Initial) => {
+                let bare = format!("n={},r={}", scram_name(self.user.as_slice()), self.nonce);
+                let resp = Vec::from_slice(bytes!("n,,")).append(bare.as_bytes());
+                self.state = ScramServerFirst(bare, self.nonce.clone());
+                Ok(resp)
+            }
+            (ScramSha256, ScramServerFirst(bare, nonce)) => {
+                let (resp, sig) = try!(scram_client_final(bare.as_slice(), nonce.as_slice(),
+                                                          self.password.as_slice(), challenge));
+                self.state = ScramServerFinal(sig);
+                Ok(resp)
+            }
+            (ScramSha256, ScramServerFinal(sig)) => {
+                let msg = match str::from_utf8(challenge) {
+                    None => return Err(ProtocolError("invalid SCRAM server-final-message")),
+                    Some(s) => s
+                };
+                if msg.starts_with("e=") {
+                    return Err(ProtocolError("server rejected SCRAM exchange"));
+                }
+                let verifier = match scram_attr(msg, 'v').map(|v| v.from_base64()) {
+                    Some(Ok(v)) => v,
+                    _ => return Err(ProtocolError("invalid SCRAM server-final-message"))
+                };
+                if verifier.as_slice() != sig.as_slice() {
+                    return Err(ProtocolError("SCRAM server signature mismatch"));
+                }
+                // the server still expects an empty response before 903
+                Ok(Vec::new())
+            }
+            (_, _) => Err(ProtocolError("unexpected AUTHENTICATE from server"))
+        }
+    }
+}
+
+/// Encodes a response into the list of AUTHENTICATE arguments that carry it.
+/// The base64 text is split into 400-byte chunks, and a final "+" is added if the
+/// last chunk is exactly 400 bytes (or the response is empty).
+pub fn encode_response(resp: &[u8]) -> Vec<String> {
+    let encoded = resp.to_base64(STANDARD);
+    let mut chunks = Vec::new();
+    let mut rest = encoded.as_slice();
+    while rest.len() >= CHUNK_SIZE {
+        chunks.push(rest.slice_to(CHUNK_SIZE).to_owned());
+        rest = rest.slice_from(CHUNK_SIZE);
+    }
+    if rest.is_empty() {
+        chunks.push("+".to_owned());
+    } else {
+        chunks.push(rest.to_owned());
+    }
+    chunks
+}
+
+fn scram_name(user: &str) -> String {
+    user.replace("=", "=3D").as_slice().replace(",", "=2C")
+}
+
+fn scram_attr<'a>(msg: &'a str, attr: char) -> Option<&'a str> {
+    msg.split(',').find(|s| s.len() >= 2 && s.char_at(0) == attr && s.char_at(1) == '=')
+       .map(|s| s.slice_from(2))
+}
+
+/// Computes the client-final-message for the given server-first-message.
+/// Also returns the expected server signature.
+fn scram_client_final(bare: &str, nonce: &str, password: &str, server_first: &[u8])
+                     -> Result<(Vec<u8>, Vec<u8>), SaslError> {
+    let server_first = match str::from_utf8(server_first) {
+        None => return Err(ProtocolError("invalid SCRAM server-first-message")),
+        Some(s) => s
+    };
+    let (rnonce, salt, iters) = match (scram_attr(server_first, 'r'),
+                                       scram_attr(server_first, 's').map(|s| s.from_base64()),
+                                       scram_attr(server_first, 'i').and_then(from_str::<uint>)) {
+        (Some(r), Some(Ok(s)), Some(i)) => (r, s, i),
+        _ => return Err(ProtocolError("invalid SCRAM server-first-message"))
+    };
+    if iters == 0 || iters > MAX_SCRAM_ITERATIONS {
+        return Err(ProtocolError("SCRAM iteration count out of range"));
+    }
+    // the server must add its own part to our nonce
+    if rnonce.len() <= nonce.len() || !rnonce.starts_with(nonce) {
+        return Err(ProtocolError("SCRAM server nonce mismatch"));
+    }
+
+    let salted = hi(password.as_bytes(), salt.as_slice(), iters);
+    let client_key = hmac_sha256(salted.as_slice(), bytes!("Client Key"));
+    let stored_key = hash::hash(SHA256, client_key.as_slice());
+    let without_proof = format!("c=biws,r={}", rnonce);
+    let auth_msg = format!("{},{},{}", bare, server_first, without_proof);
+    let client_sig = hmac_sha256(stored_key.as_slice(), auth_msg.as_bytes());
+    let mut proof = client_key;
+    for (p, &b) in proof.mut_iter().zip(client_sig.iter()) {
+        *p ^= b;
+    }
+    let server_key = hmac_sha256(salted.as_slice(), bytes!("Server Key"));
+    let server_sig = hmac_sha256(server_key.as_slice(), auth_msg.as_bytes());
+
+    let resp = format!("{},p={}", without_proof, proof.as_slice().to_base64(STANDARD));
+    Ok((Vec::from_slice(resp.as_bytes()), server_sig))
+}
+
+/// PBKDF2 with HMAC-SHA-256, producing a single block.
+/// OpenSSL's PBKDF2 binding only offers SHA-1, so this iterates its HMAC directly.
+fn hi(password: &[u8], salt: &[u8], iterations: uint) -> Vec<u8> {
+    let mut msg = Vec::from_slice(salt);
+    msg.push_all([0, 0, 0, 1]);
+    let mut u = hmac_sha256(password, msg.as_slice());
+    let mut result = u.clone();
+    for _ in range(1, iterations) {
+        u = hmac_sha256(password, u.as_slice());
+        for (r, &b) in result.mut_iter().zip(u.iter()) {
+            *r ^= b;
+        }
+    }
+    result
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut hmac = HMAC(SHA256, key);
+    hmac.update(data);
+    hmac.final()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Credentials, Session, Plain, ScramSha256, ProtocolError, encode_response, hi,
+                hmac_sha256};
+    use std::str;
+    use serialize::hex::ToHex;
+
+    #[test]
+    fn test_hi() {
+        assert_eq!(hmac_sha256(bytes!("key"), bytes!("The quick brown fox jumps over the lazy dog"))
+                   .as_slice().to_hex().as_slice(),
+                   "f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8");
+        // PBKDF2-HMAC-SHA-256 vectors from RFC 7914
+        assert_eq!(hi(bytes!("passwd"), bytes!("salt"), 1).as_slice().to_hex().as_slice(),
+                   "55ac046e56e3089fec1691c22544b605f94185216dde0465e68b9d57c20dacbc");
+        assert_eq!(hi(bytes!("password"), bytes!("salt"), 4096).as_slice().to_hex().as_slice(),
+                   "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a");
+    }
+
+    #[test]
+    fn test_plain() {
+        let creds = Credentials::new(Plain, "jilles", "sesame");
+        let mut session = Session::new(&creds);
+        assert_eq!(session.step([]).ok(), Some(Vec::from_slice(bytes!("jilles\0jilles\0sesame"))));
+    }
+
+    #[test]
+    fn test_scram_sha256() {
+        // test vector from RFC 7677
+        let creds = Credentials::new(ScramSha256, "user", "pencil");
+        let mut session = Session::with_nonce(&creds, "rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(session.step([]).ok(),
+                   Some(Vec::from_slice(bytes!("n,,n=user,r=rOprNGfwEbeRWgbNEkqO"))));
+        let resp = session.step(bytes!("r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                        s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"));
+        let exp = bytes!("c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                          p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=");
+        assert_eq!(resp.ok(), Some(Vec::from_slice(exp)));
+        let resp = session.step(bytes!("v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4="));
+        assert_eq!(resp.ok(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_scram_bad_signature() {
+        let creds = Credentials::new(ScramSha256, "user", "pencil");
+        let mut session = Session::with_nonce(&creds, "rOprNGfwEbeRWgbNEkqO");
+        assert!(session.step([]).is_ok());
+        assert!(session.step(bytes!("r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,\
+                                     s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096")).is_ok());
+        assert!(session.step(bytes!("v=AAAATRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=")).is_err());
+    }
+
+    #[test]
+    fn test_scram_bad_server_first() {
+        let creds = Credentials::new(ScramSha256, "user", "pencil");
+        for &(first, err) in [
+            (bytes!("r=rOprNGfwEbeRWgbNEkqO%hvY,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=0"),
+             "SCRAM iteration count out of range"),
+            (bytes!("r=rOprNGfwEbeRWgbNEkqO%hvY,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=100001"),
+             "SCRAM iteration count out of range"),
+            (bytes!("r=rOprNGfwEbeRWgbNEkqO%hvY,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4294967295"),
+             "SCRAM iteration count out of range"),
+            (bytes!("r=rOprNGfwEbeRWgbNEkqO,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"),
+             "SCRAM server nonce mismatch"),
+            (bytes!("r=rOprNGfwEbeRWgbNEkq,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"),
+             "SCRAM server nonce mismatch"),
+            (bytes!("r=xOprNGfwEbeRWgbNEkqO%hvY,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"),
+             "SCRAM server nonce mismatch")].iter() {
+            let mut session = Session::with_nonce(&creds, "rOprNGfwEbeRWgbNEkqO");
+            assert!(session.step([]).is_ok());
+            match session.step(first) {
+                Err(ProtocolError(msg)) => assert_eq!(msg, err),
+                _ => fail!("expected a protocol error for {}", str::from_utf8_lossy(first))
+            }
+        }
+    }
+
+    #[test]
+    fn test_chunking() {
+        assert_eq!(encode_response([]), vec!["+".to_owned()]);
+
+        // 300 bytes encode to exactly 400 base64 characters
+        let chunks = encode_response([0u8, ..300]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks.get(0).len(), 400);
+        assert_eq!(chunks.get(1).as_slice(), "+");
+
+        let chunks = encode_response([0u8, ..301]);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks.get(0).len(), 400);
+        assert_eq!(chunks.get(1).len(), 4);
+
+        let creds = Credentials::new(Plain, "", "");
+        let mut session = Session::new(&creds);
+        let long = String::from_char(400, 'A');
+        assert!(session.push_chunk(long.as_bytes()).is_none());
+        let res = session.push_chunk(bytes!("+"));
+        assert_eq!(res.and_then(|r| r.ok()).map(|v| v.len()), Some(300));
+    }
+}
@@ -0,0 +1,636 @@
+//! Tracking of joined channels, their members, and the users in them
+
+use User;
+use casemap::{CaseMapping, CaseMap, Entries};
+use conn::modes::ModeChange;
+use conn::support::ServerSupport;
+
+/// A channel the connection has joined
+#[deriving(Clone)]
+pub struct Channel {
+    name: Vec<u8>,
+    topic: Option<Topic>,
+    modes: Vec<(char, Option<Vec<u8>>)>,
+    members: CaseMap<Member>,
+    synced: bool
+}
+
+/// The topic of a channel
+#[deriving(Eq,Clone)]
+pub struct Topic {
+    /// The topic text
+    pub text: Vec<u8>,
+    /// Who set the topic, as a nick or a full hostmask, if known
+    pub setter: Option<Vec<u8>>,
+    /// When the topic was set, in seconds since the epoch, if known
+    pub time: Option<u64>
+}
+
+/// A member of a channel
+#[deriving(Eq,Clone)]
+pub struct Member {
+    nick: Vec<u8>,
+    modes: Vec<char>
+}
+
+impl Member {
+    /// Returns the member's nickname
+    pub fn nick<'a>(&'a self) -> &'a [u8] {
+        self.nick.as_slice()
+    }
+
+    /// Returns the member's prefix modes (e.g. `o` for op), highest rank first
+    pub fn modes<'a>(&'a self) -> &'a [char] {
+        self.modes.as_slice()
+    }
+
+    /// Returns `true` if the member has the given prefix mode
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.contains(&mode)
+    }
+
+    fn add_mode(&mut self, mode: char, prefixes: &[(char, char)]) {
+        if self.has_mode(mode) {
+            return;
+        }
+        self.modes.push(mode);
+        // keep modes ordered by rank
+        let rank = |m: &char| prefixes.iter().position(|&(p, _)| p == *m).unwrap_or(prefixes.len());
+        self.modes.sort_by(|a, b| rank(a).cmp(&rank(b)));
+    }
+}
+
+impl Channel {
+    /// Returns a new Channel with no known topic, modes or members.
+    /// Member nicks are compared using `casemap`.
+    pub fn new(name: &[u8], casemap: CaseMapping) -> Channel {
+        Channel {
+            name: Vec::from_slice(name),
+            topic: None,
+            modes: Vec::new(),
+            members: CaseMap::new(casemap),
+            synced: false
+        }
+    }
+
+    /// Returns the channel name
+    pub fn name<'a>(&'a self) -> &'a [u8] {
+        self.name.as_slice()
+    }
+
+    /// Returns the channel topic, if one is set
+    pub fn topic<'a>(&'a self) -> Option<&'a Topic> {
+        self.topic.as_ref()
+    }
+
+    /// Returns the channel modes that are set, along with their arguments.
+    /// List modes such as bans are not tracked.
+    pub fn modes<'a>(&'a self) -> &'a [(char, Option<Vec<u8>>)] {
+        self.modes.as_slice()
+    }
+
+    /// Returns `true` if the given channel mode is set
+    pub fn has_mode(&self, mode: char) -> bool {
+        self.modes.iter().any(|&(m, _)| m == mode)
+    }
+
+    /// Returns the argument of the given channel mode, such as the key for `k`
+    pub fn mode_arg<'a>(&'a self, mode: char) -> Option<&'a [u8]> {
+        self.modes.iter().find(|&&(m, _)| m == mode).and_then(|&(_, ref arg)| {
+            arg.as_ref().map(|a| a.as_slice())
+        })
+    }
+
+    /// Returns an iterator over the members of the channel, in no particular order
+    pub fn members<'a>(&'a self) -> Members<'a> {
+        Members { iter: self.members.iter() }
+    }
+
+    /// Returns the number of members
+    pub fn member_count(&self) -> uint {
+        self.members.len()
+    }
+
+    /// Returns the member with the given nick, if any
+    pub fn member<'a>(&'a self, nick: &[u8]) -> Option<&'a Member> {
+        self.members.find(nick)
+    }
+
+    /// Returns `true` if the channel's name is equivalent to `name`
+    pub fn is_named(&self, name: &[u8]) -> bool {
+        self.members.casemapping().equiv(self.name.as_slice(), name)
+    }
+
+    /// Changes the casemapping used to compare names
+    pub fn set_casemapping(&mut self, casemap: CaseMapping) {
+        self.members.set_casemapping(casemap);
+    }
+
+    /// Returns `true` once the initial NAMES list has been received
+    pub fn is_synced(&self) -> bool {
+        self.synced
+    }
+
+    /// Adds a member to the channel, or merges in `modes` if it's already present
+    pub fn add_member(&mut self, nick: &[u8], modes: &[char], prefixes: &[(char, char)]) {
+        if !self.members.contains_key(nick) {
+            self.members.insert(nick, Member { nick: Vec::from_slice(nick), modes: Vec::new() });
+        }
+        let member = self.members.find_mut(nick).unwrap();
+        for &mode in modes.iter() {
+            member.add_mode(mode, prefixes);
+        }
+    }
+
+    /// Removes a member from the channel. Returns `true` if it was present.
+    pub fn remove_member(&mut self, nick: &[u8]) -> bool {
+        self.members.pop(nick).is_some()
+    }
+
+    /// Renames a member after a nick change
+    pub fn rename_member(&mut self, old: &[u8], new: &[u8]) {
+        match self.members.pop(old) {
+            None => (),
+            Some(mut member) => {
+                member.nick = Vec::from_slice(new);
+                self.members.insert(new, member);
+            }
+        }
+    }
+
+    /// Sets or clears the topic text. The setter and time are reset.
+    pub fn set_topic(&mut self, text: Option<&[u8]>) {
+        self.topic = text.map(|text| Topic {
+            text: Vec::from_slice(text),
+            setter: None,
+            time: None
+        });
+    }
+
+    /// Records who set the current topic, and when
+    pub fn set_topic_info(&mut self, setter: &[u8], time: Option<u64>) {
+        match self.topic {
+            None => (),
+            Some(ref mut topic) => {
+                topic.setter = Some(Vec::from_slice(setter));
+                topic.time = time;
+            }
+        }
+    }
+
+    /// Adds the names from an RPL_NAMREPLY (353), as returned by `parse_names()`.
+    /// A reply that arrives after the list was complete starts a fresh member list.
+    pub fn add_names(&mut self, names: &[(User, Vec<char>)], prefixes: &[(char, char)]) {
+        if self.synced {
+            self.members.clear();
+            self.synced = false;
+        }
+        for &(ref user, ref modes) in names.iter() {
+            self.add_member(user.nick(), modes.as_slice(), prefixes);
+        }
+    }
+
+    /// Marks the NAMES list as complete, on RPL_ENDOFNAMES (366)
+    pub fn end_names(&mut self) {
+        self.synced = true;
+    }
+
+    /// Clears the channel modes, ahead of an RPL_CHANNELMODEIS (324)
+    pub fn clear_modes(&mut self) {
+        self.modes.clear();
+    }
+
+    /// Applies channel mode changes, as parsed by `modes::parse_channel_modes()`
+    /// with the same ServerSupport.
+    pub fn apply_modes(&mut self, changes: &[ModeChange], support: &ServerSupport) {
+        let (prefixes, chanmodes) = (support.prefixes(), support.chanmodes());
+        for change in changes.iter() {
+            let mode = change.mode;
+            if prefixes.iter().any(|&(m, _)| m == mode) {
+                let nick = match change.arg {
+                    Some(ref nick) => nick.as_slice(),
+                    None => continue
+                };
+                match self.members.find_mut(nick) {
+                    None => (),
+                    Some(member) => if change.set {
+                        member.add_mode(mode, prefixes);
+                    } else {
+                        member.modes.retain(|&m| m != mode);
+                    }
+                }
+            } else if chanmodes.list.contains(&mode) {
+                // list modes aren't tracked
+            } else {
+                self.modes.retain(|&(m, _)| m != mode);
+                if change.set {
+                    self.modes.push((mode, change.arg.clone()));
+                }
+            }
+        }
+    }
+}
+
+/// An iterator over the members of a Channel
+pub struct Members<'a> {
+    iter: Entries<'a, Member>
+}
+
+impl<'a> Iterator<&'a Member> for Members<'a> {
+    fn next(&mut self) -> Option<&'a Member> {
+        self.iter.next().map(|(_, member)| member)
+    }
+}
+
+/// Everything known about a user that shares a channel with us
+#[deriving(Clone)]
+pub struct UserInfo {
+    user: User,
+    realname: Option<Vec<u8>>,
+    account: Option<Vec<u8>>,
+    away: Option<Vec<u8>>,
+    channels: Vec<Vec<u8>>
+}
+
+impl UserInfo {
+    /// Returns the user's nickname
+    pub fn nick<'a>(&'a self) -> &'a [u8] {
+        self.user.nick()
+    }
+
+    /// Returns the user's hostmask. The username and host are filled in
+    /// once they've been seen in a prefix, a NAMES reply or a WHO reply.
+    pub fn hostmask<'a>(&'a self) -> &'a User {
+        &self.user
+    }
+
+    /// Returns the user's real name, if known.
+    /// This is learned from WHO replies and the `extended-join` capability.
+    pub fn realname<'a>(&'a self) -> Option<&'a [u8]> {
+        self.realname.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns the account the user is logged into, if known.
+    /// This is learned from WHOX replies and the `extended-join` and
+    /// `account-notify` capabilities.
+    pub fn account<'a>(&'a self) -> Option<&'a [u8]> {
+        self.account.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns `true` if the user is known to be away
+    pub fn is_away(&self) -> bool {
+        self.away.is_some()
+    }
+
+    /// Returns the user's away message, if they're away and it's known
+    pub fn away_message<'a>(&'a self) -> Option<&'a [u8]> {
+        match self.away {
+            Some(ref msg) if !msg.is_empty() => Some(msg.as_slice()),
+            _ => None
+        }
+    }
+
+    /// Returns the names of the channels we share with the user
+    pub fn channels<'a>(&'a self) -> &'a [Vec<u8>] {
+        self.channels.as_slice()
+    }
+
+    /// Sets the user's username and host, e.g. from CHGHOST
+    pub fn set_host(&mut self, user: &[u8], host: &[u8]) {
+        self.user = User::new(self.user.nick(), Some(user), Some(host));
+    }
+
+    /// Sets the user's real name
+    pub fn set_realname(&mut self, realname: &[u8]) {
+        self.realname = Some(Vec::from_slice(realname));
+    }
+
+    /// Sets the user's account. Pass None, or the `*` used by IRCv3, if they're
+    /// not logged in.
+    pub fn set_account(&mut self, account: Option<&[u8]>) {
+        self.account = match account {
+            Some(acct) if acct != bytes!("*") => Some(Vec::from_slice(acct)),
+            _ => None
+        };
+    }
+
+    /// Marks the user as away with the given message (which may be empty),
+    /// or as back if None
+    pub fn set_away(&mut self, msg: Option<&[u8]>) {
+        self.away = msg.map(|m| Vec::from_slice(m));
+    }
+}
+
+/// The registry of users that share a channel with us, with one record per nick.
+///
+/// A record is dropped once we no longer share any channels with the user.
+#[deriving(Clone)]
+pub struct Users {
+    users: CaseMap<UserInfo>
+}
+
+impl Users {
+    /// Returns an empty registry that compares names using `casemap`
+    pub fn new(casemap: CaseMapping) -> Users {
+        Users { users: CaseMap::new(casemap) }
+    }
+
+    /// Changes the casemapping used to compare names
+    pub fn set_casemapping(&mut self, casemap: CaseMapping) {
+        self.users.set_casemapping(casemap);
+    }
+
+    /// Returns the number of known users
+    pub fn len(&self) -> uint {
+        self.users.len()
+    }
+
+    /// Returns an iterator over all known users, in no particular order
+    pub fn iter<'a>(&'a self) -> UserInfos<'a> {
+        UserInfos { iter: self.users.iter() }
+    }
+
+    /// Returns the record for the given nick, if any
+    pub fn get<'a>(&'a self, nick: &[u8]) -> Option<&'a UserInfo> {
+        self.users.find(nick)
+    }
+
+    /// Returns the record for the given nick for updating, if any
+    pub fn get_mut<'a>(&'a mut self, nick: &[u8]) -> Option<&'a mut UserInfo> {
+        self.users.find_mut(nick)
+    }
+
+    /// Returns the record for `user`'s nick, creating it if necessary.
+    /// Any username and host in `user` are recorded.
+    pub fn update<'a>(&'a mut self, user: &User) -> &'a mut UserInfo {
+        if !self.users.contains_key(user.nick()) {
+            self.users.insert(user.nick(), UserInfo {
+                user: User::new(user.nick(), None, None),
+                realname: None,
+                account: None,
+                away: None,
+                channels: Vec::new()
+            });
+        }
+        let info = self.users.find_mut(user.nick()).unwrap();
+        match (user.user(), user.host()) {
+            (Some(u), Some(h)) => info.set_host(u, h),
+            _ => ()
+        }
+        info
+    }
+
+    /// Records that the user is in the given channel, creating their record
+    /// if necessary as with `update()`.
+    pub fn join<'a>(&'a mut self, user: &User, chan: &[u8]) -> &'a mut UserInfo {
+        let casemap = self.users.casemapping();
+        let info = self.update(user);
+        if !info.channels.iter().any(|c| casemap.equiv(c.as_slice(), chan)) {
+            info.channels.push(Vec::from_slice(chan));
+        }
+        info
+    }
+
+    /// Renames a user after a nick change
+    pub fn rename(&mut self, old: &[u8], new: &[u8]) {
+        match self.users.pop(old) {
+            None => (),
+            Some(mut info) => {
+                info.user = info.user.with_nick(new);
+                // replaces any stale record under the new nick
+                self.users.insert(new, info);
+            }
+        }
+    }
+
+    /// Removes a user, e.g. after a QUIT
+    pub fn remove(&mut self, nick: &[u8]) {
+        self.users.pop(nick);
+    }
+
+    /// Records that the user left the channel, dropping them if it was the last
+    /// channel we shared
+    pub fn part(&mut self, nick: &[u8], chan: &[u8]) {
+        let casemap = self.users.casemapping();
+        let empty = match self.users.find_mut(nick) {
+            None => return,
+            Some(info) => {
+                info.channels.retain(|c| !casemap.equiv(c.as_slice(), chan));
+                info.channels.is_empty()
+            }
+        };
+        if empty {
+            self.users.pop(nick);
+        }
+    }
+
+    /// Forgets the channel for every user, after we leave it
+    pub fn part_all(&mut self, chan: &[u8]) {
+        let casemap = self.users.casemapping();
+        self.users.retain(|_, info| {
+            info.channels.retain(|c| !casemap.equiv(c.as_slice(), chan));
+            !info.channels.is_empty()
+        });
+    }
+
+    /// Forgets the channel for every user that isn't one of its members, such as after
+    /// a NAMES refresh, dropping them if it was the last channel we shared
+    pub fn sync_channel(&mut self, chan: &Channel) {
+        let casemap = self.users.casemapping();
+        self.users.retain(|_, info| {
+            if chan.member(info.nick()).is_none() {
+                info.channels.retain(|c| !casemap.equiv(c.as_slice(), chan.name()));
+            }
+            !info.channels.is_empty()
+        });
+    }
+
+    /// Removes every user
+    pub fn clear(&mut self) {
+        self.users.clear();
+    }
+}
+
+/// An iterator over the users in a Users registry
+pub struct UserInfos<'a> {
+    iter: Entries<'a, UserInfo>
+}
+
+impl<'a> Iterator<&'a UserInfo> for UserInfos<'a> {
+    fn next(&mut self) -> Option<&'a UserInfo> {
+        self.iter.next().map(|(_, info)| info)
+    }
+}
+
+/// Parses the space-separated names from an RPL_NAMREPLY (353) into users and
+/// their prefix modes.
+/// Names may carry several prefix symbols (multi-prefix) and a hostmask
+/// (userhost-in-names).
+pub fn parse_names(names: &[u8], prefixes: &[(char, char)]) -> Vec<(User, Vec<char>)> {
+    let mut result = Vec::new();
+    for name in names.split(|&b| b == ' ' as u8).filter(|n| !n.is_empty()) {
+        let mut modes = Vec::new();
+        let mut rest = name;
+        loop {
+            let sym = match rest.head() {
+                Some(&b) => b as char,
+                None => break
+            };
+            match prefixes.iter().find(|&&(_, s)| s == sym) {
+                Some(&(mode, _)) => modes.push(mode),
+                None => break
+            }
+            rest = rest.slice_from(1);
+        }
+        let user = User::parse(rest);
+        if !user.nick().is_empty() {
+            result.push((user, modes));
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Channel, Users, parse_names};
+    use conn::modes::parse_channel_modes;
+    use conn::support::ServerSupport;
+    use casemap::{Ascii, Rfc1459};
+    use User;
+
+    macro_rules! b(
+        ($args:tt) => (
+            { static b: &'static [u8] = bytes!($args); b }
+        )
+    )
+
+    #[test]
+    fn test_names() {
+        let support = ServerSupport::new();
+        let prefixes = support.prefixes();
+        let mut chan = Channel::new(b!("#rust"), Rfc1459);
+        chan.add_names(parse_names(b!("@alice +bob carol"), prefixes).as_slice(), prefixes);
+        let names = parse_names(b!("@+dave eve!e@host.com "), prefixes);
+        let &(ref eve, _) = names.get(1);
+        assert_eq!(eve.host(), Some(b!("host.com")));
+        chan.add_names(names.as_slice(), prefixes);
+        assert!(!chan.is_synced());
+        chan.end_names();
+        assert!(chan.is_synced());
+
+        let mut nicks: Vec<&[u8]> = chan.members().map(|m| m.nick()).collect();
+        nicks.sort();
+        assert_eq!(nicks, vec!(b!("alice"), b!("bob"), b!("carol"), b!("dave"), b!("eve")));
+        assert_eq!(chan.member(b!("alice")).unwrap().modes(), ['o'].as_slice());
+        assert_eq!(chan.member(b!("dave")).unwrap().modes(), ['o', 'v'].as_slice());
+        assert!(chan.member(b!("carol")).unwrap().modes().is_empty());
+
+        // a later NAMES reply replaces the list
+        let wide = [('q', '~'), ('o', '@'), ('h', '%'), ('v', '+')];
+        chan.add_names(parse_names(b!("~%frank"), wide).as_slice(), wide);
+        chan.end_names();
+        assert_eq!(chan.member_count(), 1);
+        assert_eq!(chan.member(b!("frank")).unwrap().modes(), ['q', 'h'].as_slice());
+    }
+
+    #[test]
+    fn test_members() {
+        let support = ServerSupport::new();
+        let prefixes = support.prefixes();
+        let mut chan = Channel::new(b!("#rust"), Rfc1459);
+        chan.add_member(b!("alice"), [], prefixes);
+        chan.add_member(b!("bob"), ['v'], prefixes);
+        chan.add_member(b!("bob"), ['o'], prefixes);
+        assert_eq!(chan.member(b!("bob")).unwrap().modes(), ['o', 'v'].as_slice());
+
+        chan.rename_member(b!("BOB"), b!("robert"));
+        assert!(chan.member(b!("bob")).is_none());
+        assert!(chan.member(b!("Robert")).is_some());
+        assert!(chan.member(b!("robert")).unwrap().has_mode('v'));
+
+        assert!(chan.remove_member(b!("alice")));
+        assert!(!chan.remove_member(b!("alice")));
+        assert_eq!(chan.member_count(), 1);
+    }
+
+    #[test]
+    fn test_apply_modes() {
+        let support = ServerSupport::new();
+        let prefixes = support.prefixes();
+        let mut chan = Channel::new(b!("#rust"), Rfc1459);
+        chan.add_member(b!("alice"), [], prefixes);
+        chan.add_member(b!("bob"), ['v'], prefixes);
+
+        let changes = parse_channel_modes(b!("+ntlk-v+bo"), [b!("10"), b!("secret"), b!("bob"),
+                                                             b!("*!*@spam"), b!("alice")],
+                                          &support);
+        chan.apply_modes(changes.as_slice(), &support);
+        assert!(chan.has_mode('n') && chan.has_mode('t'));
+        assert_eq!(chan.mode_arg('l'), Some(b!("10")));
+        assert_eq!(chan.mode_arg('k'), Some(b!("secret")));
+        assert!(!chan.has_mode('b'));
+        assert!(chan.member(b!("bob")).unwrap().modes().is_empty());
+        assert!(chan.member(b!("alice")).unwrap().has_mode('o'));
+
+        let changes = parse_channel_modes(b!("-lko"), [b!("secret"), b!("alice")], &support);
+        chan.apply_modes(changes.as_slice(), &support);
+        assert!(!chan.has_mode('l') && !chan.has_mode('k'));
+        assert!(!chan.member(b!("alice")).unwrap().has_mode('o'));
+        assert!(chan.has_mode('n'));
+    }
+
+    #[test]
+    fn test_topic() {
+        let mut chan = Channel::new(b!("#rust"), Rfc1459);
+        assert!(chan.topic().is_none());
+        chan.set_topic(Some(b!("Rust 0.11")));
+        chan.set_topic_info(b!("bob!b@host"), Some(1400000000));
+        let topic = chan.topic().unwrap();
+        assert_eq!(topic.text.as_slice(), b!("Rust 0.11"));
+        assert_eq!(topic.setter.as_ref().map(|s| s.as_slice()), Some(b!("bob!b@host")));
+        assert_eq!(topic.time, Some(1400000000));
+        chan.set_topic(None);
+        assert!(chan.topic().is_none());
+    }
+
+    #[test]
+    fn test_users() {
+        let mut users = Users::new(Rfc1459);
+        users.join(&User::parse(b!("alice!a@host.com")), b!("#rust"));
+        users.join(&User::parse(b!("bob")), b!("#rust"));
+        {
+            let bob = users.join(&User::parse(b!("Bob")), b!("#irc"));
+            bob.set_account(Some(b!("bobby")));
+            bob.set_away(Some(b!("")));
+        }
+        users.join(&User::parse(b!("BOB")), b!("#IRC"));
+        let bob = users.get(b!("bob")).unwrap();
+        assert_eq!(bob.hostmask().host(), None);
+        assert_eq!(bob.account(), Some(b!("bobby")));
+        assert!(bob.is_away() && bob.away_message().is_none());
+        assert_eq!(bob.channels(),
+                   vec!(Vec::from_slice(b!("#rust")), Vec::from_slice(b!("#irc"))).as_slice());
+
+        users.get_mut(b!("bob")).unwrap().set_account(Some(b!("*")));
+        assert_eq!(users.get(b!("bob")).unwrap().account(), None);
+
+        users.rename(b!("alice"), b!("alicia"));
+        assert!(users.get(b!("alice")).is_none());
+        assert_eq!(users.get(b!("alicia")).unwrap().hostmask().raw(), b!("alicia!a@host.com"));
+
+        // users are dropped once we share no channels with them
+        users.part_all(b!("#rust"));
+        assert!(users.get(b!("alicia")).is_none());
+        assert!(users.get(b!("bob")).is_some());
+        users.part(b!("bob"), b!("#Irc"));
+        assert_eq!(users.len(), 0);
+
+        // with ascii casemapping, [bob] and {bob} are different users
+        let mut users = Users::new(Ascii);
+        users.join(&User::parse(b!("[bob]")), b!("#rust"));
+        users.join(&User::parse(b!("{bob}")), b!("#rust"));
+        assert_eq!(users.len(), 2);
+        users.set_casemapping(Rfc1459);
+        assert_eq!(users.get(b!("{BOB}")).unwrap().nick(), b!("[bob]"));
+    }
+}
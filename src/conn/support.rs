@@ -0,0 +1,389 @@
+//! The server's advertised features, from RPL_ISUPPORT (005)
+
+use std::{num, str};
+use casemap::{CaseMapping, Ascii, Rfc1459};
+
+/// The channel modes a server supports, grouped by how they take arguments
+#[deriving(Eq,Clone,Show)]
+pub struct ChanModes {
+    /// Type A: list modes such as bans, which always take an argument
+    pub list: Vec<char>,
+    /// Type B: modes that always take an argument, such as the key
+    pub param: Vec<char>,
+    /// Type C: modes that take an argument only when set, such as the limit
+    pub set_param: Vec<char>,
+    /// Type D: modes that never take an argument
+    pub flag: Vec<char>
+}
+
+/// What the server supports, as advertised in RPL_ISUPPORT (005).
+///
+/// Until the server says otherwise, the RFC 2812 defaults are assumed.
+#[deriving(Clone)]
+pub struct ServerSupport {
+    tokens: Vec<(String, Vec<u8>)>,
+    prefixes: Vec<(char, char)>,
+    chantypes: Vec<char>,
+    chanmodes: ChanModes,
+    casemapping: CaseMapping,
+    nicklen: Option<uint>,
+    channellen: Option<uint>,
+    topiclen: Option<uint>,
+    modes: Option<uint>,
+    maxtargets: Option<uint>,
+    targmax: Vec<(String, Option<uint>)>,
+    network: Option<Vec<u8>>,
+    statusmsg: Vec<char>,
+    excepts: Option<char>,
+    invex: Option<char>
+}
+
+impl ServerSupport {
+    /// Returns a ServerSupport describing an RFC 2812 server
+    pub fn new() -> ServerSupport {
+        ServerSupport {
+            tokens: Vec::new(),
+            prefixes: vec!(('o', '@'), ('v', '+')),
+            chantypes: vec!('#', '&', '+', '!'),
+            chanmodes: ChanModes {
+                list: vec!('b', 'e', 'I'),
+                param: vec!('k'),
+                set_param: vec!('l'),
+                flag: "aimnqpsrt".chars().collect()
+            },
+            casemapping: Rfc1459,
+            nicklen: Some(9),
+            channellen: None,
+            topiclen: None,
+            modes: Some(3),
+            maxtargets: None,
+            targmax: Vec::new(),
+            network: None,
+            statusmsg: Vec::new(),
+            excepts: None,
+            invex: None
+        }
+    }
+
+    /// Applies the tokens from an RPL_ISUPPORT line,
+    /// i.e. every arg except the first (our nick) and the last (the trailing text).
+    pub fn parse_tokens<V: Vector<u8>>(&mut self, tokens: &[V]) {
+        for token in tokens.iter() {
+            self.parse_token(token.as_slice());
+        }
+    }
+
+    /// Applies a single `KEY`, `KEY=value` or `-KEY` token.
+    /// Values may contain `\xHH` escapes.
+    pub fn parse_token(&mut self, token: &[u8]) {
+        if token.is_empty() {
+            return;
+        }
+        let (negate, token) = if token[0] == '-' as u8 {
+            (true, token.slice_from(1))
+        } else {
+            (false, token)
+        };
+        let (key, value) = match token.iter().position(|&b| b == '=' as u8) {
+            None => (token, Vec::new()),
+            Some(idx) => (token.slice_to(idx), unescape(token.slice_from(idx+1)))
+        };
+        let key = match str::from_utf8(key) {
+            Some(key) if !key.is_empty() => key,
+            _ => return
+        };
+
+        self.tokens.retain(|&(ref k, _)| key != k.as_slice());
+        if negate {
+            self.apply(key, None);
+        } else {
+            self.apply(key, Some(value.as_slice()));
+            self.tokens.push((key.to_owned(), value));
+        }
+    }
+
+    /// Updates the typed field for `key`. A value of None resets it to the default.
+    fn apply(&mut self, key: &str, value: Option<&[u8]>) {
+        let default = ServerSupport::new();
+        match key {
+            "PREFIX" => {
+                self.prefixes = value.and_then(parse_prefix).unwrap_or(default.prefixes)
+            }
+            "CHANTYPES" => {
+                self.chantypes = value.map(chars).unwrap_or(default.chantypes)
+            }
+            "CHANMODES" => {
+                self.chanmodes = match value {
+                    None => default.chanmodes,
+                    Some(v) => {
+                        let mut classes = v.split(|&b| b == ',' as u8).map(chars);
+                        ChanModes {
+                            list: classes.next().unwrap_or(Vec::new()),
+                            param: classes.next().unwrap_or(Vec::new()),
+                            set_param: classes.next().unwrap_or(Vec::new()),
+                            flag: classes.next().unwrap_or(Vec::new())
+                        }
+                    }
+                }
+            }
+            "CASEMAPPING" => {
+                // unknown casemappings, such as rfc7613, at least fold ASCII
+                self.casemapping = match value.and_then(str::from_utf8) {
+                    Some(v) if !v.is_empty() => CaseMapping::from_name(v).unwrap_or(Ascii),
+                    _ => default.casemapping
+                }
+            }
+            "NICKLEN" => self.nicklen = value.map_or(default.nicklen, parse_uint),
+            "CHANNELLEN" => self.channellen = value.and_then(parse_uint),
+            "TOPICLEN" => self.topiclen = value.and_then(parse_uint),
+            // MODES with no value means there's no limit
+            "MODES" => self.modes = value.map_or(default.modes, parse_uint),
+            "MAXTARGETS" => self.maxtargets = value.and_then(parse_uint),
+            "TARGMAX" => {
+                self.targmax = match value {
+                    None => default.targmax,
+                    Some(v) => {
+                        v.split(|&b| b == ',' as u8).filter_map(|entry| {
+                            let idx = match entry.iter().position(|&b| b == ':' as u8) {
+                                Some(idx) => idx,
+                                None => return None
+                            };
+                            str::from_utf8(entry.slice_to(idx)).map(|cmd| {
+                                (cmd.to_ascii_upper(), parse_uint(entry.slice_from(idx+1)))
+                            })
+                        }).collect()
+                    }
+                }
+            }
+            "NETWORK" => {
+                self.network = value.and_then(|v| {
+                    if v.is_empty() { None } else { Some(Vec::from_slice(v)) }
+                })
+            }
+            "STATUSMSG" => self.statusmsg = value.map_or(default.statusmsg, chars),
+            "EXCEPTS" => self.excepts = value.map(|v| mode_char(v, 'e')),
+            "INVEX" => self.invex = value.map(|v| mode_char(v, 'I')),
+            _ => ()
+        }
+    }
+
+    /// Returns the value of the given token, or None if the server didn't advertise it.
+    /// Tokens without a value return an empty slice.
+    ///
+    /// This covers every token, including ones without a typed accessor.
+    pub fn token<'a>(&'a self, key: &str) -> Option<&'a [u8]> {
+        self.tokens.iter().find(|&&(ref k, _)| key == k.as_slice()).map(|&(_, ref v)| {
+            v.as_slice()
+        })
+    }
+
+    /// Returns every advertised token along with its value
+    pub fn tokens<'a>(&'a self) -> &'a [(String, Vec<u8>)] {
+        self.tokens.as_slice()
+    }
+
+    /// Returns the channel membership prefixes as (mode, symbol) pairs,
+    /// from highest to lowest rank (PREFIX)
+    pub fn prefixes<'a>(&'a self) -> &'a [(char, char)] {
+        self.prefixes.as_slice()
+    }
+
+    /// Returns the characters that can start a channel name (CHANTYPES).
+    /// Before the server sends CHANTYPES, these are the RFC 2812 types `#&+!`.
+    pub fn chantypes<'a>(&'a self) -> &'a [char] {
+        self.chantypes.as_slice()
+    }
+
+    /// Returns the channel modes grouped by argument type (CHANMODES)
+    pub fn chanmodes<'a>(&'a self) -> &'a ChanModes {
+        &self.chanmodes
+    }
+
+    /// Returns the casemapping used to compare nicknames and channel names
+    /// (CASEMAPPING). Unrecognized casemappings are treated as Ascii; the name
+    /// is still available with `token("CASEMAPPING")`.
+    pub fn casemapping(&self) -> CaseMapping {
+        self.casemapping
+    }
+
+    /// Returns the maximum nickname length, if known (NICKLEN)
+    pub fn nicklen(&self) -> Option<uint> {
+        self.nicklen
+    }
+
+    /// Returns the maximum channel name length, if known (CHANNELLEN)
+    pub fn channellen(&self) -> Option<uint> {
+        self.channellen
+    }
+
+    /// Returns the maximum topic length, if known (TOPICLEN)
+    pub fn topiclen(&self) -> Option<uint> {
+        self.topiclen
+    }
+
+    /// Returns the maximum number of parameterized modes per MODE command,
+    /// or None if there's no limit (MODES)
+    pub fn modes(&self) -> Option<uint> {
+        self.modes
+    }
+
+    /// Returns the maximum number of targets for `cmd`, or None if there's no
+    /// known limit. TARGMAX is consulted first, then MAXTARGETS.
+    pub fn max_targets(&self, cmd: &str) -> Option<uint> {
+        match self.targmax.iter().find(|&&(ref c, _)| c.as_slice().eq_ignore_ascii_case(cmd)) {
+            Some(&(_, max)) => max,
+            None => self.maxtargets
+        }
+    }
+
+    /// Returns the network name, if advertised (NETWORK)
+    pub fn network<'a>(&'a self) -> Option<&'a [u8]> {
+        self.network.as_ref().map(|v| v.as_slice())
+    }
+
+    /// Returns the prefix symbols that can be put before a channel name to
+    /// message only members with that status (STATUSMSG)
+    pub fn statusmsg<'a>(&'a self) -> &'a [char] {
+        self.statusmsg.as_slice()
+    }
+
+    /// Returns the ban exception mode, if supported (EXCEPTS)
+    pub fn excepts(&self) -> Option<char> {
+        self.excepts
+    }
+
+    /// Returns the invite exception mode, if supported (INVEX)
+    pub fn invex(&self) -> Option<char> {
+        self.invex
+    }
+
+    /// Returns `true` if `name` starts with one of the server's channel types
+    pub fn is_channel(&self, name: &[u8]) -> bool {
+        match name.head() {
+            Some(&b) => self.chantypes.contains(&(b as char)),
+            None => false
+        }
+    }
+}
+
+/// Parses a PREFIX value such as `(ov)@+`. An empty value means no prefixes.
+fn parse_prefix(v: &[u8]) -> Option<Vec<(char, char)>> {
+    if v.is_empty() {
+        return Some(Vec::new());
+    }
+    if v[0] != '(' as u8 {
+        return None;
+    }
+    let close = match v.iter().position(|&b| b == ')' as u8) {
+        Some(idx) => idx,
+        None => return None
+    };
+    let (modes, symbols) = (v.slice(1, close), v.slice_from(close+1));
+    if modes.len() != symbols.len() {
+        return None;
+    }
+    Some(modes.iter().zip(symbols.iter()).map(|(&m, &s)| (m as char, s as char)).collect())
+}
+
+fn parse_uint(v: &[u8]) -> Option<uint> {
+    str::from_utf8(v).and_then(from_str::<uint>)
+}
+
+fn chars(v: &[u8]) -> Vec<char> {
+    v.iter().map(|&b| b as char).collect()
+}
+
+fn mode_char(v: &[u8], default: char) -> char {
+    v.head().map_or(default, |&b| b as char)
+}
+
+/// Decodes the `\xHH` escapes allowed in ISUPPORT values
+fn unescape(v: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(v.len());
+    let mut i = 0;
+    while i < v.len() {
+        if v[i] == '\\' as u8 && i + 4 <= v.len() && v[i+1] == 'x' as u8 {
+            let hex = str::from_utf8(v.slice(i+2, i+4));
+            match hex.and_then(|h| num::from_str_radix::<u8>(h, 16)) {
+                Some(b) => {
+                    result.push(b);
+                    i += 4;
+                    continue;
+                }
+                None => ()
+            }
+        }
+        result.push(v[i]);
+        i += 1;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServerSupport;
+    use casemap::{Ascii, Rfc1459};
+
+    macro_rules! b(
+        ($args:tt) => (
+            { static b: &'static [u8] = bytes!($args); b }
+        )
+    )
+
+    #[test]
+    fn test_defaults() {
+        let support = ServerSupport::new();
+        assert_eq!(support.prefixes(), [('o', '@'), ('v', '+')].as_slice());
+        assert!(support.is_channel(b!("#rust")));
+        assert!(support.is_channel(b!("&local")));
+        assert!(support.is_channel(b!("+modeless")));
+        assert!(support.is_channel(b!("!12345safe")));
+        assert!(!support.is_channel(b!("bob")));
+        assert!(!support.is_channel(b!("")));
+        assert_eq!(support.chantypes(), ['#', '&', '+', '!'].as_slice());
+        assert_eq!(support.casemapping(), Rfc1459);
+        assert_eq!(support.modes(), Some(3));
+        assert_eq!(support.token("PREFIX"), None);
+    }
+
+    #[test]
+    fn test_parse_tokens() {
+        let mut support = ServerSupport::new();
+        support.parse_tokens([b!("PREFIX=(qaohv)~&@%+"), b!("CHANTYPES=#"),
+                              b!("CHANMODES=beI,k,l,imnpst,XYZ"), b!("CASEMAPPING=ascii"),
+                              b!("NICKLEN=30"), b!("CHANNELLEN=50"), b!("TOPICLEN=390"),
+                              b!("MODES"), b!("MAXTARGETS=4"),
+                              b!("TARGMAX=PRIVMSG:3,NOTICE:,JOIN:"),
+                              b!("NETWORK=Example\\x20Net"), b!("STATUSMSG=@+"),
+                              b!("EXCEPTS"), b!("INVEX=J"), b!("WHOX"), b!("ELIST=CMNTU")]);
+
+        assert_eq!(support.prefixes(),
+                   [('q', '~'), ('a', '&'), ('o', '@'), ('h', '%'), ('v', '+')].as_slice());
+        assert!(!support.is_channel(b!("&local")));
+        assert!(!support.is_channel(b!("+modeless")));
+        assert_eq!(support.chanmodes().param, vec!('k'));
+        assert_eq!(support.chanmodes().flag, vec!('i', 'm', 'n', 'p', 's', 't'));
+        assert_eq!(support.casemapping(), Ascii);
+        assert_eq!(support.nicklen(), Some(30));
+        assert_eq!(support.channellen(), Some(50));
+        assert_eq!(support.topiclen(), Some(390));
+        assert_eq!(support.modes(), None);
+        assert_eq!(support.max_targets("privmsg"), Some(3));
+        assert_eq!(support.max_targets("NOTICE"), None);
+        assert_eq!(support.max_targets("KICK"), Some(4));
+        assert_eq!(support.network(), Some(b!("Example Net")));
+        assert_eq!(support.statusmsg(), ['@', '+'].as_slice());
+        assert_eq!(support.excepts(), Some('e'));
+        assert_eq!(support.invex(), Some('J'));
+        assert_eq!(support.token("WHOX"), Some(b!("")));
+        assert_eq!(support.token("ELIST"), Some(b!("CMNTU")));
+        assert_eq!(support.token("NETWORK"), Some(b!("Example Net")));
+
+        // negation removes the token and restores the default
+        support.parse_tokens([b!("-PREFIX"), b!("-EXCEPTS"), b!("-WHOX"), b!("-MODES")]);
+        assert_eq!(support.prefixes(), [('o', '@'), ('v', '+')].as_slice());
+        assert_eq!(support.excepts(), None);
+        assert_eq!(support.token("WHOX"), None);
+        assert_eq!(support.modes(), Some(3));
+    }
+}
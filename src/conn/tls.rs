@@ -0,0 +1,509 @@
+//! TLS transport support
+
+use std::{fmt, ptr, slice};
+use std::io;
+use std::io::{IoResult, TcpStream};
+use std::io::timer;
+use std::io::net::ip::{IpAddr, Ipv4Addr, Ipv6Addr};
+use libc::c_void;
+use sync::{Arc, Mutex};
+use openssl::ssl::{Ssl, SslContext, SslStream, Sslv23, SslVerifyPeer, SslVerifyNone};
+use openssl::ssl::error::SslError;
+use openssl::crypto::hash::SHA256;
+use openssl::x509::{PEM, X509};
+use openssl::nid;
+
+/// How long a TLS read may block before releasing the stream to the writer task
+static READ_POLL_MS: u64 = 50;
+
+/// How long the reader waits after a timed out read, so the writer can take the stream
+static READ_BACKOFF_MS: u64 = 5;
+
+/// TLS settings for a connection
+pub struct Config<'a> {
+    /// Verify the server's certificate chain and hostname.
+    /// Setting this to `false` accepts any certificate and is insecure.
+    pub verify: bool,
+    /// A PEM bundle of CA certificates to verify against.
+    /// If None, the system default CA paths are used.
+    pub ca_file: Option<&'a Path>,
+    /// The hostname to send via SNI and to verify the certificate against.
+    /// If None, the host being connected to is used. SNI isn't sent for IP addresses.
+    pub sni: Option<&'a str>,
+    /// A PEM client certificate to present to the server, for CertFP.
+    /// Pair this with SASL EXTERNAL to log in without a password.
+    pub cert_file: Option<&'a Path>,
+    /// The PEM private key for `cert_file`.
+    /// If None, the key is expected to be in `cert_file` as well.
+    pub key_file: Option<&'a Path>,
+}
+
+impl<'a> Config<'a> {
+    /// Returns a new Config that verifies the server against the system CAs
+    pub fn new() -> Config<'a> {
+        #![inline]
+        Config {
+            verify: true,
+            ca_file: None,
+            sni: None,
+            cert_file: None,
+            key_file: None
+        }
+    }
+}
+
+/// Errors that can occur while establishing a TLS session
+pub enum TlsError {
+    /// OpenSSL failed to configure the context or complete the handshake
+    SslFailure(SslError),
+    /// The server's certificate does not match the expected hostname.
+    /// The arg is the first name found in the certificate, if any.
+    HostnameMismatch(Option<String>)
+}
+
+impl fmt::Show for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SslFailure(ref err) => write!(f, "TLS error: {}", *err),
+            HostnameMismatch(None) => write!(f, "TLS error: certificate has no hostname"),
+            HostnameMismatch(Some(ref name)) => {
+                write!(f, "TLS error: certificate is for {}", *name)
+            }
+        }
+    }
+}
+
+/// The transport underlying a connection.
+///
+/// Cloning a NetStream yields another handle to the same connection, so one handle
+/// can be read from while another is written to.
+#[deriving(Clone)]
+pub enum NetStream {
+    /// An unencrypted TCP stream
+    PlainStream(TcpStream),
+    /// A TLS session over TCP, along with a handle to the raw socket
+    TlsStream(Arc<Mutex<SslStream<TcpStream>>>, TcpStream)
+}
+
+impl NetStream {
+    /// Returns the SHA-256 fingerprint of the server's certificate,
+    /// or None if the stream isn't encrypted.
+    pub fn peer_fingerprint(&self) -> Option<Vec<u8>> {
+        match *self {
+            PlainStream(_) => None,
+            TlsStream(ref s, _) => {
+                s.lock().get_peer_certificate().and_then(|cert| cert.fingerprint(SHA256))
+            }
+        }
+    }
+
+    /// Shuts down the underlying socket, waking up any task blocked reading from it
+    pub fn shutdown(&mut self) {
+        let sock = match *self {
+            PlainStream(ref mut s) => s,
+            TlsStream(_, ref mut s) => s
+        };
+        let _ = sock.close_read();
+        let _ = sock.close_write();
+    }
+}
+
+impl Reader for NetStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+        match *self {
+            PlainStream(ref mut s) => s.read(buf),
+            TlsStream(ref s, _) => {
+                // The reader and writer share the session, so reads time out to give
+                // the writer a chance to send. Socket timeouts are deadlines rather
+                // than limits on each read, so every read sets a fresh one. Timing
+                // out in the middle of a record is fine, as the session keeps what
+                // it has read so far.
+                loop {
+                    {
+                        let mut ssl = s.lock();
+                        ssl.get_inner().set_read_timeout(Some(READ_POLL_MS));
+                        match ssl.read(buf) {
+                            Err(ref e) if e.kind == io::TimedOut => (),
+                            res => return res
+                        }
+                    }
+                    timer::sleep(READ_BACKOFF_MS);
+                }
+            }
+        }
+    }
+}
+
+impl Writer for NetStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+        match *self {
+            PlainStream(ref mut s) => s.write(buf),
+            TlsStream(ref s, _) => s.lock().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match *self {
+            PlainStream(ref mut s) => s.flush(),
+            TlsStream(ref s, _) => s.lock().flush()
+        }
+    }
+}
+
+/// Performs a TLS handshake over `stream`, verifying the server as `host`
+/// according to `cfg`.
+pub fn wrap(stream: TcpStream, host: &str, cfg: &Config) -> Result<NetStream, TlsError> {
+    let name = cfg.sni.unwrap_or(host);
+
+    let mut ctx = try!(SslContext::new(Sslv23).map_err(SslFailure));
+    if cfg.verify {
+        ctx.set_verify(SslVerifyPeer, None);
+        let err = match cfg.ca_file {
+            None => ctx.set_default_verify_paths(),
+            Some(path) => ctx.set_CA_file(path)
+        };
+        match err {
+            None => (),
+            Some(err) => return Err(SslFailure(err))
+        }
+    } else {
+        ctx.set_verify(SslVerifyNone, None);
+    }
+    match cfg.cert_file {
+        None => (),
+        Some(cert) => {
+            let key = cfg.key_file.unwrap_or(cert);
+            let err = ctx.set_certificate_file(cert, PEM)
+                         .or_else(|| ctx.set_private_key_file(key, PEM));
+            match err {
+                None => (),
+                Some(err) => return Err(SslFailure(err))
+            }
+        }
+    }
+
+    let ssl = try!(Ssl::new(&ctx).map_err(SslFailure));
+    // SNI only carries hostnames
+    if from_str::<IpAddr>(name).is_none() {
+        match ssl.set_hostname(name) {
+            None => (),
+            Some(err) => return Err(SslFailure(err))
+        }
+    }
+    // keep a handle to the raw socket so it can be shut down independently
+    let sock = stream.clone();
+    let ssl = try!(SslStream::new_from(ssl, stream).map_err(SslFailure));
+
+    if cfg.verify {
+        let names = match ssl.get_peer_certificate() {
+            None => Vec::new(),
+            Some(cert) => cert_names(&cert)
+        };
+        if !names_match(names.as_slice(), name) {
+            return Err(HostnameMismatch(names.as_slice().head().map(|n| format!("{}", *n))));
+        }
+    }
+
+    Ok(TlsStream(Arc::new(Mutex::new(ssl)), sock))
+}
+
+/// A name that a certificate is valid for
+enum CertName {
+    DnsName(String),
+    IpAddress(IpAddr)
+}
+
+impl fmt::Show for CertName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DnsName(ref name) => name.fmt(f),
+            IpAddress(ref addr) => addr.fmt(f)
+        }
+    }
+}
+
+/// Returns the names a certificate is valid for, as in RFC 6125.
+/// These are the subjectAltName dNSName and iPAddress entries if there are any,
+/// and the subject CN only if the certificate has no subjectAltName.
+fn cert_names(cert: &X509) -> Vec<CertName> {
+    match subject_alt_names(cert) {
+        Some(names) => names,
+        None => cert.subject_name().text_by_nid(nid::CN).move_iter().map(DnsName).collect()
+    }
+}
+
+/// Returns `true` if any of the names is valid for `host`.
+/// IP addresses only match iPAddress entries, and hostnames only match DNS names.
+fn names_match(names: &[CertName], host: &str) -> bool {
+    let ip = from_str::<IpAddr>(host);
+    names.iter().any(|n| {
+        match (n, ip) {
+            (&DnsName(ref pattern), None) => hostname_matches(pattern.as_slice(), host),
+            (&IpAddress(addr), Some(ip)) => addr == ip,
+            _ => false
+        }
+    })
+}
+
+/// Reads the subjectAltName extension, which rust-openssl doesn't expose.
+/// Returns None if the certificate doesn't have one.
+fn subject_alt_names(cert: &X509) -> Option<Vec<CertName>> {
+    unsafe {
+        let names = ffi::X509_get_ext_d2i(cert.get_handle() as *mut c_void,
+                                          ffi::NID_subject_alt_name, ptr::mut_null(),
+                                          ptr::mut_null());
+        if names.is_null() {
+            return None;
+        }
+        let mut res = Vec::new();
+        for i in range(0, ffi::sk_num(names)) {
+            let name = ffi::sk_value(names, i) as *mut ffi::GENERAL_NAME;
+            let len = ffi::ASN1_STRING_length((*name).d) as uint;
+            let data = ffi::ASN1_STRING_data((*name).d) as *u8;
+            let kind = (*name).kind;
+            slice::raw::buf_as_slice(data, len, |v| {
+                if kind == ffi::GEN_DNS {
+                    // dNSName is an IA5String, so anything else is bogus
+                    if v.iter().all(|&b| b < 0x80) {
+                        res.push(DnsName(String::from_utf8_lossy(v).into_owned()));
+                    }
+                } else if kind == ffi::GEN_IPADD {
+                    match ip_from_bytes(v) {
+                        Some(addr) => res.push(IpAddress(addr)),
+                        None => ()
+                    }
+                }
+            });
+        }
+        ffi::GENERAL_NAMES_free(names);
+        Some(res)
+    }
+}
+
+/// Converts an iPAddress entry, which is 4 or 16 bytes in network order
+fn ip_from_bytes(v: &[u8]) -> Option<IpAddr> {
+    match v.len() {
+        4 => Some(Ipv4Addr(v[0], v[1], v[2], v[3])),
+        16 => {
+            let w = |i: uint| (v[i*2] as u16 << 8) | v[i*2+1] as u16;
+            Some(Ipv6Addr(w(0), w(1), w(2), w(3), w(4), w(5), w(6), w(7)))
+        }
+        _ => None
+    }
+}
+
+#[allow(non_camel_case_types)]
+mod ffi {
+    use libc::{c_int, c_uchar, c_void};
+
+    pub static NID_subject_alt_name: c_int = 85;
+    pub static GEN_DNS: c_int = 2;
+    pub static GEN_IPADD: c_int = 7;
+
+    /// The type and value of a GENERAL_NAME. The value is a union of pointers,
+    /// and is an ASN1_STRING for the types we read.
+    pub struct GENERAL_NAME {
+        pub kind: c_int,
+        pub d: *mut c_void
+    }
+
+    #[link(name = "crypto")]
+    extern "C" {
+        pub fn X509_get_ext_d2i(x: *mut c_void, nid: c_int, crit: *mut c_int, idx: *mut c_int)
+                               -> *mut c_void;
+        pub fn sk_num(st: *mut c_void) -> c_int;
+        pub fn sk_value(st: *mut c_void, i: c_int) -> *mut c_void;
+        pub fn GENERAL_NAMES_free(names: *mut c_void);
+        pub fn ASN1_STRING_length(s: *mut c_void) -> c_int;
+        pub fn ASN1_STRING_data(s: *mut c_void) -> *mut c_uchar;
+    }
+}
+
+/// Matches a certificate name against a hostname, case-insensitively.
+/// A wildcard is only honored as the entire leftmost label, e.g. `*.example.com`.
+fn hostname_matches(pattern: &str, host: &str) -> bool {
+    let pattern = pattern.to_ascii_lower();
+    let host = host.to_ascii_lower();
+    if pattern.as_slice().starts_with("*.") {
+        let suffix = pattern.as_slice().slice_from(1);
+        match host.as_slice().find('.') {
+            Some(idx) if idx > 0 => host.as_slice().slice_from(idx) == suffix,
+            _ => false
+        }
+    } else {
+        pattern == host
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, wrap, hostname_matches, names_match, DnsName, IpAddress,
+                HostnameMismatch};
+    use std::io::{Acceptor, Listener, BufferedStream, TcpListener, TcpStream};
+    use std::io::net::ip::{Ipv4Addr, Ipv6Addr};
+    use openssl::ssl::{SslContext, SslStream, Sslv23, SslVerifyPeer};
+    use openssl::x509::PEM;
+    use serialize::hex::ToHex;
+
+    /// Spawns a TLS listener with a self-signed certificate for `localhost`.
+    /// It greets each of the next `count` clients with a PING line.
+    /// If `client_certs` is true, the greeting says whether the client presented
+    /// a certificate signed by the same self-signed cert.
+    fn serve(count: uint, client_certs: bool) -> u16 {
+        serve_cert(count, client_certs, "test/cert.pem", "test/key.pem")
+    }
+
+    /// Like serve(), with the given certificate and key
+    fn serve_cert(count: uint, client_certs: bool, cert: &'static str, key: &'static str)
+                 -> u16 {
+        let listener = TcpListener::bind("127.0.0.1", 0).unwrap();
+        let port = listener.socket_name().unwrap().port;
+        let mut acceptor = listener.listen().unwrap();
+        spawn(proc() {
+            let mut ctx = SslContext::new(Sslv23).unwrap();
+            assert!(ctx.set_certificate_file(&Path::new(cert), PEM).is_none());
+            assert!(ctx.set_private_key_file(&Path::new(key), PEM).is_none());
+            if client_certs {
+                ctx.set_verify(SslVerifyPeer, None);
+                assert!(ctx.set_CA_file(&Path::new(cert)).is_none());
+            }
+            for _ in range(0, count) {
+                let stream = match acceptor.accept() {
+                    Ok(s) => s,
+                    Err(_) => break
+                };
+                match SslStream::new_server(&ctx, stream) {
+                    Ok(mut s) => {
+                        let msg = if !client_certs {
+                            bytes!("PING :hello\r\n")
+                        } else if s.get_peer_certificate().is_some() {
+                            bytes!("PING :cert\r\n")
+                        } else {
+                            bytes!("PING :nocert\r\n")
+                        };
+                        let _ = s.write(msg).and_then(|_| s.flush());
+                    }
+                    Err(_) => ()
+                }
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn test_self_signed() {
+        let port = serve(4, false);
+        let ca = Path::new("test/cert.pem");
+
+        // insecure mode accepts anything
+        let mut cfg = Config::new();
+        cfg.verify = false;
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        let stream = wrap(stream, "127.0.0.1", &cfg).ok().expect("insecure handshake failed");
+        let mut stream = BufferedStream::new(stream);
+        assert_eq!(stream.read_line().unwrap().as_slice(), "PING :hello\r\n");
+
+        // the self-signed cert isn't trusted by default
+        let cfg = Config::new();
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        assert!(wrap(stream, "localhost", &cfg).is_err());
+
+        // but it is when we provide it as the CA
+        let mut cfg = Config::new();
+        cfg.ca_file = Some(&ca);
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        let stream = wrap(stream, "localhost", &cfg).ok().expect("verified handshake failed");
+        let mut stream = BufferedStream::new(stream);
+        assert_eq!(stream.read_line().unwrap().as_slice(), "PING :hello\r\n");
+
+        // and the hostname still has to match
+        cfg.sni = Some("irc.example.com");
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        match wrap(stream, "localhost", &cfg) {
+            Err(HostnameMismatch(Some(ref cn))) => assert_eq!(cn.as_slice(), "localhost"),
+            _ => fail!("expected hostname mismatch")
+        }
+    }
+
+    #[test]
+    fn test_subject_alt_name() {
+        // this cert has no CN, only DNS:localhost, IP:127.0.0.1 and IP:::1
+        let port = serve_cert(4, false, "test/san-cert.pem", "test/san-key.pem");
+        let ca = Path::new("test/san-cert.pem");
+        let mut cfg = Config::new();
+        cfg.ca_file = Some(&ca);
+
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        let stream = wrap(stream, "localhost", &cfg).ok().expect("DNS name didn't match");
+        let mut stream = BufferedStream::new(stream);
+        assert_eq!(stream.read_line().unwrap().as_slice(), "PING :hello\r\n");
+
+        // no SNI is sent for an IP address, and it's checked against the iPAddress entries
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        let stream = wrap(stream, "127.0.0.1", &cfg).ok().expect("IP address didn't match");
+        let mut stream = BufferedStream::new(stream);
+        assert_eq!(stream.read_line().unwrap().as_slice(), "PING :hello\r\n");
+
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        assert!(wrap(stream, "127.0.0.2", &cfg).is_err());
+
+        cfg.sni = Some("irc.example.com");
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        match wrap(stream, "localhost", &cfg) {
+            Err(HostnameMismatch(Some(ref name))) => assert_eq!(name.as_slice(), "localhost"),
+            _ => fail!("expected hostname mismatch")
+        }
+    }
+
+    #[test]
+    fn test_names_match() {
+        let names = [DnsName("irc.example.com".to_owned()), DnsName("*.example.net".to_owned()),
+                     IpAddress(Ipv4Addr(192, 0, 2, 1)),
+                     IpAddress(Ipv6Addr(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1))];
+        assert!(names_match(names.as_slice(), "irc.example.com"));
+        assert!(names_match(names.as_slice(), "IRC.example.NET"));
+        assert!(names_match(names.as_slice(), "192.0.2.1"));
+        assert!(names_match(names.as_slice(), "2001:db8::1"));
+        assert!(!names_match(names.as_slice(), "example.com"));
+        assert!(!names_match(names.as_slice(), "192.0.2.2"));
+        assert!(!names_match([], "irc.example.com"));
+
+        // IP addresses have to be in an iPAddress entry, not a DNS name
+        assert!(!names_match([DnsName("192.0.2.1".to_owned())], "192.0.2.1"));
+        assert!(!names_match([IpAddress(Ipv4Addr(192, 0, 2, 1))], "irc.example.com"));
+    }
+
+    #[test]
+    fn test_client_cert() {
+        let port = serve(2, true);
+        let path = Path::new("test/cert.pem");
+        let key = Path::new("test/key.pem");
+
+        let mut cfg = Config::new();
+        cfg.ca_file = Some(&path);
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        let stream = wrap(stream, "localhost", &cfg).ok().expect("handshake failed");
+        let mut stream = BufferedStream::new(stream);
+        assert_eq!(stream.read_line().unwrap().as_slice(), "PING :nocert\r\n");
+
+        cfg.cert_file = Some(&path);
+        cfg.key_file = Some(&key);
+        let stream = TcpStream::connect("127.0.0.1", port).unwrap();
+        let stream = wrap(stream, "localhost", &cfg).ok().expect("handshake failed");
+        let fingerprint = stream.peer_fingerprint().map(|v| v.as_slice().to_hex());
+        let expected = "5bacf58bb71803b1f7c44a831ff9b3405f0bd646268f7ee79449b47722064739";
+        assert_eq!(fingerprint, Some(expected.to_owned()));
+        let mut stream = BufferedStream::new(stream);
+        assert_eq!(stream.read_line().unwrap().as_slice(), "PING :cert\r\n");
+    }
+
+    #[test]
+    fn test_hostname_matches() {
+        assert!(hostname_matches("irc.example.com", "irc.example.com"));
+        assert!(hostname_matches("IRC.Example.com", "irc.example.COM"));
+        assert!(hostname_matches("*.example.com", "irc.example.com"));
+        assert!(!hostname_matches("*.example.com", "example.com"));
+        assert!(!hostname_matches("*.example.com", "a.b.example.com"));
+        assert!(!hostname_matches("irc.example.com", "irc.example.net"));
+    }
+}
@@ -8,11 +8,214 @@
 
 #[phase(syntax, link)]
 extern crate log;
+extern crate collections;
+extern crate serialize;
+extern crate rand;
+extern crate sync;
+extern crate time;
+extern crate openssl;
+extern crate libc;
+#[cfg(test)]
+extern crate test;
 
 use std::{fmt, str};
+use casemap::CaseMapping;
 
+pub mod casemap;
 pub mod conn;
 
+/// The source of a line: either a server or a user
+#[deriving(Eq,Clone)]
+pub enum Prefix {
+    /// A server name, such as `irc.example.net`
+    ServerPrefix(Vec<u8>),
+    /// A user, as `nick[!user][@host]`
+    UserPrefix(User)
+}
+
+impl Prefix {
+    /// Parse a byte-vector into a Prefix.
+    ///
+    /// Anything with a `!` or `@` is a user. Otherwise a name containing a `.` is a
+    /// server, since nicknames can't contain dots, and anything else is a bare nick.
+    pub fn parse(v: &[u8]) -> Prefix {
+        if is_server_name(v) {
+            ServerPrefix(Vec::from_slice(v))
+        } else {
+            UserPrefix(User::parse(v))
+        }
+    }
+
+    /// Returns the raw byte-vector that represents the Prefix
+    pub fn raw<'a>(&'a self) -> &'a [u8] {
+        match *self {
+            ServerPrefix(ref name) => name.as_slice(),
+            UserPrefix(ref user) => user.raw()
+        }
+    }
+
+    /// Returns the server name or the user's nickname
+    pub fn name<'a>(&'a self) -> &'a [u8] {
+        match *self {
+            ServerPrefix(ref name) => name.as_slice(),
+            UserPrefix(ref user) => user.nick()
+        }
+    }
+
+    /// Returns the nickname if the Prefix is a user
+    pub fn nick<'a>(&'a self) -> Option<&'a [u8]> {
+        self.user().map(|u| u.nick())
+    }
+
+    /// Returns the User if the Prefix is a user
+    pub fn user<'a>(&'a self) -> Option<&'a User> {
+        match *self {
+            ServerPrefix(_) => None,
+            UserPrefix(ref user) => Some(user)
+        }
+    }
+
+    /// Returns the server name if the Prefix is a server
+    pub fn server<'a>(&'a self) -> Option<&'a [u8]> {
+        match *self {
+            ServerPrefix(ref name) => Some(name.as_slice()),
+            UserPrefix(_) => None
+        }
+    }
+
+    /// Returns `true` if the Prefix is a server
+    pub fn is_server(&self) -> bool {
+        self.server().is_some()
+    }
+
+    /// Returns `true` if the Prefix is a well-formed server name or user.
+    /// See `is_valid_server()` and `User::is_valid()`.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            ServerPrefix(ref name) => Prefix::is_valid_server(name.as_slice()),
+            UserPrefix(ref user) => user.is_valid()
+        }
+    }
+
+    /// Returns `true` if `name` is a well-formed server name: letters, digits,
+    /// `-`, `_`, `.` and `:`, plus `*` for masked names
+    pub fn is_valid_server(name: &[u8]) -> bool {
+        !name.is_empty() && name.iter().all(|&b| {
+            ((b as char).is_alphanumeric() && b < 0x80) || "-_.:*".contains_char(b as char)
+        })
+    }
+}
+
+impl fmt::Show for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = str::from_utf8_lossy(self.raw());
+        f.pad(s.as_slice())
+    }
+}
+
+/// Returns `true` if a prefix names a server rather than a user
+fn is_server_name(v: &[u8]) -> bool {
+    let is_user = v.iter().any(|&b| b == '!' as u8 || b == '@' as u8);
+    !is_user && v.contains(&('.' as u8))
+}
+
+/// A Prefix that borrows the line it came from, as returned by `Line::prefix()`
+#[deriving(Eq,Clone)]
+pub enum PrefixRef<'a> {
+    /// A server name, such as `irc.example.net`
+    ServerPrefixRef(&'a [u8]),
+    /// A user, as `nick[!user][@host]`
+    UserPrefixRef(UserRef<'a>)
+}
+
+impl<'a> PrefixRef<'a> {
+    /// Parse a byte-vector into a PrefixRef without copying it.
+    /// See `Prefix::parse()`.
+    pub fn parse(v: &'a [u8]) -> PrefixRef<'a> {
+        if is_server_name(v) {
+            ServerPrefixRef(v)
+        } else {
+            UserPrefixRef(UserRef::parse(v))
+        }
+    }
+
+    /// Returns the raw byte-vector that represents the Prefix
+    pub fn raw(&self) -> &'a [u8] {
+        match *self {
+            ServerPrefixRef(name) => name,
+            UserPrefixRef(ref user) => user.raw()
+        }
+    }
+
+    /// Returns the server name or the user's nickname
+    pub fn name(&self) -> &'a [u8] {
+        match *self {
+            ServerPrefixRef(name) => name,
+            UserPrefixRef(ref user) => user.nick()
+        }
+    }
+
+    /// Returns the nickname if the Prefix is a user
+    pub fn nick(&self) -> Option<&'a [u8]> {
+        self.user().map(|u| u.nick())
+    }
+
+    /// Returns the user if the Prefix is a user
+    pub fn user(&self) -> Option<UserRef<'a>> {
+        match *self {
+            ServerPrefixRef(_) => None,
+            UserPrefixRef(ref user) => Some(user.clone())
+        }
+    }
+
+    /// Returns the server name if the Prefix is a server
+    pub fn server(&self) -> Option<&'a [u8]> {
+        match *self {
+            ServerPrefixRef(name) => Some(name),
+            UserPrefixRef(_) => None
+        }
+    }
+
+    /// Returns `true` if the Prefix is a server
+    pub fn is_server(&self) -> bool {
+        self.server().is_some()
+    }
+
+    /// Copies the Prefix
+    pub fn to_prefix(&self) -> Prefix {
+        match *self {
+            ServerPrefixRef(name) => ServerPrefix(Vec::from_slice(name)),
+            UserPrefixRef(ref user) => UserPrefix(user.to_user())
+        }
+    }
+}
+
+impl<'a> fmt::Show for PrefixRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = str::from_utf8_lossy(self.raw());
+        f.pad(s.as_slice())
+    }
+}
+
+/// The styles of ban mask that can be built with `User::ban_mask()`
+#[deriving(Eq,Clone,Show)]
+pub enum BanStyle {
+    /// `nick!*@*`
+    BanNick,
+    /// `*!*@host`
+    BanHost,
+    /// `*!user@host`
+    BanUserHost,
+    /// `*!*@*.domain`, where the domain is the host without its first label.
+    /// IPv4 addresses become `1.2.3.*` instead. Hosts with fewer than three labels,
+    /// cloaks and IPv6 addresses are used whole, as in BanHost.
+    BanDomain,
+    /// `*!user@*.domain`, with the domain as in BanDomain
+    BanUserDomain,
+    /// `nick!user@host`
+    BanFull
+}
+
 /// Representation of an IRC user
 #[deriving(Clone)]
 pub struct User {
@@ -29,22 +232,9 @@ impl User {
     ///     nickname[!username][@host]
     // TODO: make v into IntoVec<u8> once it lands
     pub fn parse(v: &[u8]) -> User {
-        let v = v.into_owned();
-
-        let (mut bangloc, mut atloc) = (None, None);
-        for (i, &b) in v.iter().enumerate() {
-            if bangloc.is_none() && b == '!' as u8 {
-                bangloc = Some(i);
-            } else if b == '@' as u8 {
-                atloc = Some(i);
-                break;
-            }
-        }
-        let nicklen = bangloc.or(atloc).unwrap_or(v.len());
-        let user = bangloc.map(|i| (i+1, atloc.unwrap_or(v.len())));
-        let host = atloc.map(|i| (i+1, v.len()));
+        let (nicklen, user, host) = user_ranges(v);
         User{
-            raw: v,
+            raw: v.into_owned(),
             nicklen: nicklen,
             user: user,
             host: host
@@ -90,6 +280,102 @@ impl User {
         self.host.map(|(a,b)| self.raw.slice(a,b))
     }
 
+    /// Returns `true` if the User has a valid nickname, and a valid username and
+    /// host if they're present. See `is_valid_nick()`, `is_valid_user()` and
+    /// `is_valid_host()`.
+    pub fn is_valid(&self) -> bool {
+        User::is_valid_nick(self.nick()) &&
+        self.user().map_or(true, User::is_valid_user) &&
+        self.host().map_or(true, User::is_valid_host)
+    }
+
+    /// Returns `true` if `nick` is a valid nickname per RFC 2812: a letter or one of
+    /// ``[]\`_^{|}``, followed by letters, digits, those specials and `-`.
+    /// The length isn't checked, as servers advertise their own NICKLEN.
+    pub fn is_valid_nick(nick: &[u8]) -> bool {
+        let special = |b: u8| "[]\\`_^{|}".contains_char(b as char);
+        let letter = |b: u8| (b as char).is_alphabetic() && b < 0x80;
+        match nick.head() {
+            Some(&b) if letter(b) || special(b) => (),
+            _ => return false
+        }
+        nick.slice_from(1).iter().all(|&b| {
+            letter(b) || special(b) || b == '-' as u8 || (b as char).is_digit()
+        })
+    }
+
+    /// Returns `true` if `user` is a valid username: non-empty, without spaces,
+    /// `@`, NUL, CR or LF
+    pub fn is_valid_user(user: &[u8]) -> bool {
+        !user.is_empty() && user.iter().all(|&b| !" @\0\r\n".contains_char(b as char))
+    }
+
+    /// Returns `true` if `host` is a valid hostname: non-empty, without spaces,
+    /// `!`, `@`, NUL, CR or LF. Cloaks such as `user/bob` are allowed.
+    pub fn is_valid_host(host: &[u8]) -> bool {
+        !host.is_empty() && host.iter().all(|&b| !" !@\0\r\n".contains_char(b as char))
+    }
+
+    /// Returns `true` if the User's nickname is equivalent to `nick` under the
+    /// given casemapping
+    pub fn is_nick(&self, nick: &[u8], casemap: CaseMapping) -> bool {
+        casemap.equiv(self.nick(), nick)
+    }
+
+    /// Returns `true` if both Users have equivalent nicknames under the given
+    /// casemapping, regardless of username and hostname
+    pub fn nick_eq(&self, other: &User, casemap: CaseMapping) -> bool {
+        self.is_nick(other.nick(), casemap)
+    }
+
+    /// Returns `true` if both Users are equivalent under the given casemapping,
+    /// including username and hostname
+    pub fn eq_ignore_case(&self, other: &User, casemap: CaseMapping) -> bool {
+        casemap.equiv(self.raw(), other.raw())
+    }
+
+    /// Returns `true` if the User's `nick!user@host` matches the wildcard `mask`,
+    /// such as `*!*@*.example.com`. See `CaseMapping::matches()` for the syntax.
+    pub fn matches(&self, mask: &[u8], casemap: CaseMapping) -> bool {
+        casemap.matches(mask, self.raw())
+    }
+
+    /// Builds a ban mask for the User in the given style.
+    ///
+    /// A missing username or host becomes `*`. An unverified username such as
+    /// `~bob` becomes `*bob`, since the server adds the `~`.
+    pub fn ban_mask(&self, style: BanStyle) -> Vec<u8> {
+        let user = match self.user() {
+            Some(user) if !user.is_empty() && user[0] == '~' as u8 => {
+                Vec::from_slice(bytes!("*")).append(user.slice_from(1))
+            }
+            Some(user) if !user.is_empty() => Vec::from_slice(user),
+            _ => Vec::from_slice(bytes!("*"))
+        };
+        let host = match self.host() {
+            Some(host) if !host.is_empty() => host,
+            _ => bytes!("*")
+        };
+
+        let mut mask = Vec::new();
+        match style {
+            BanNick | BanFull => mask.push_all(self.nick()),
+            _ => mask.push('*' as u8)
+        }
+        mask.push('!' as u8);
+        match style {
+            BanUserHost | BanUserDomain | BanFull => mask.push_all(user.as_slice()),
+            _ => mask.push('*' as u8)
+        }
+        mask.push('@' as u8);
+        match style {
+            BanNick => mask.push('*' as u8),
+            BanDomain | BanUserDomain => mask.push_all(domain_mask(host).as_slice()),
+            BanHost | BanUserHost | BanFull => mask.push_all(host)
+        }
+        mask
+    }
+
     /// Constructs a new User with the given nick and the username/hostname
     /// of the receiver.
     pub fn with_nick(&self, nick: &[u8]) -> User {
@@ -97,6 +383,46 @@ impl User {
     }
 }
 
+/// Finds the nick length and the username and host ranges of `nick[!user][@host]`
+fn user_ranges(v: &[u8]) -> (uint, Option<(uint, uint)>, Option<(uint, uint)>) {
+    let (mut bangloc, mut atloc) = (None, None);
+    for (i, &b) in v.iter().enumerate() {
+        if bangloc.is_none() && b == '!' as u8 {
+            bangloc = Some(i);
+        } else if b == '@' as u8 {
+            atloc = Some(i);
+            break;
+        }
+    }
+    let nicklen = bangloc.or(atloc).unwrap_or(v.len());
+    let user = bangloc.map(|i| (i+1, atloc.unwrap_or(v.len())));
+    let host = atloc.map(|i| (i+1, v.len()));
+    (nicklen, user, host)
+}
+
+/// Wildcards the most specific part of a host: the first label of a hostname,
+/// or the last octet of an IPv4 address. Other hosts are returned as is: IPv6
+/// addresses, cloaks such as `user/bob`, and hostnames of fewer than three labels,
+/// which would otherwise widen to a whole top-level domain.
+fn domain_mask(host: &[u8]) -> Vec<u8> {
+    if host.contains(&(':' as u8)) || host.contains(&('/' as u8)) {
+        return Vec::from_slice(host);
+    }
+    let is_ipv4 = host.split(|&b| b == '.' as u8).count() == 4 &&
+                  host.iter().all(|&b| b == '.' as u8 || (b >= '0' as u8 && b <= '9' as u8));
+    if is_ipv4 {
+        let idx = host.rposition_elem(&('.' as u8)).unwrap();
+        return Vec::from_slice(host.slice_to(idx+1)).append(bytes!("*"));
+    }
+    let labels = host.split(|&b| b == '.' as u8).filter(|l| !l.is_empty()).count();
+    match host.position_elem(&('.' as u8)) {
+        Some(idx) if idx > 0 && labels >= 3 => {
+            Vec::from_slice(bytes!("*")).append(host.slice_from(idx))
+        }
+        _ => Vec::from_slice(host)
+    }
+}
+
 impl Eq for User {
     fn eq(&self, other: &User) -> bool {
         self.raw == other.raw
@@ -110,9 +436,86 @@ impl fmt::Show for User {
     }
 }
 
+/// A User that borrows the line it came from. See `User`.
+#[deriving(Clone)]
+pub struct UserRef<'a> {
+    raw: &'a [u8],
+    nicklen: uint,
+    user: Option<(uint, uint)>,
+    host: Option<(uint, uint)>
+}
+
+impl<'a> UserRef<'a> {
+    /// Parse a byte-vector into a UserRef without copying it.
+    /// See `User::parse()`.
+    pub fn parse(v: &'a [u8]) -> UserRef<'a> {
+        let (nicklen, user, host) = user_ranges(v);
+        UserRef{
+            raw: v,
+            nicklen: nicklen,
+            user: user,
+            host: host
+        }
+    }
+
+    /// Returns the raw byte-vector that represents the User
+    pub fn raw(&self) -> &'a [u8] {
+        self.raw
+    }
+
+    /// Returns the nickname of the User
+    pub fn nick(&self) -> &'a [u8] {
+        self.raw.slice_to(self.nicklen)
+    }
+
+    /// Returns the username of the User, if any
+    pub fn user(&self) -> Option<&'a [u8]> {
+        let raw = self.raw;
+        self.user.map(|(a,b)| raw.slice(a, b))
+    }
+
+    /// Returns the hostname of the User, if any
+    pub fn host(&self) -> Option<&'a [u8]> {
+        let raw = self.raw;
+        self.host.map(|(a,b)| raw.slice(a, b))
+    }
+
+    /// Returns `true` if the User's nickname is equivalent to `nick` under the
+    /// given casemapping
+    pub fn is_nick(&self, nick: &[u8], casemap: CaseMapping) -> bool {
+        casemap.equiv(self.nick(), nick)
+    }
+
+    /// Copies the User
+    pub fn to_user(&self) -> User {
+        User{
+            raw: self.raw.to_owned(),
+            nicklen: self.nicklen,
+            user: self.user,
+            host: self.host
+        }
+    }
+}
+
+impl<'a> Eq for UserRef<'a> {
+    fn eq(&self, other: &UserRef<'a>) -> bool {
+        self.raw == other.raw
+    }
+}
+
+impl<'a> fmt::Show for UserRef<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = str::from_utf8_lossy(self.raw);
+        f.pad(s.as_slice())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use super::User;
+    use super::{User, Prefix, ServerPrefix, UserPrefix};
+    use super::{UserRef, PrefixRef, ServerPrefixRef, UserPrefixRef};
+    use super::{BanNick, BanHost, BanUserHost, BanDomain, BanUserDomain, BanFull};
+    use casemap::{Ascii, Rfc1459};
 
     macro_rules! b(
         ($args:tt) => (
@@ -174,4 +577,127 @@ mod tests {
         assert_eq!(user.user(), None);
         assert_eq!(user.host(), None);
     }
+
+    #[test]
+    fn test_user_casemapping() {
+        let user = User::parse(b!("[Bob]!bob@Host.com"));
+        assert!(user.is_nick(b!("{bob}"), Rfc1459));
+        assert!(!user.is_nick(b!("{bob}"), Ascii));
+        assert!(user.is_nick(b!("[BOB]"), Ascii));
+
+        let other = User::parse(b!("{bob}!BOB@host.COM"));
+        assert!(user.nick_eq(&other, Rfc1459));
+        assert!(user.eq_ignore_case(&other, Rfc1459));
+        assert!(!user.eq_ignore_case(&User::parse(b!("{bob}!bob@elsewhere")), Rfc1459));
+        assert!(user != other);
+    }
+
+    #[test]
+    fn test_user_matches() {
+        let user = User::parse(b!("[Bob]!~bob@irc.Example.com"));
+        assert!(user.matches(b!("*!*@*.example.com"), Rfc1459));
+        assert!(user.matches(b!("{bob}!*"), Rfc1459));
+        assert!(!user.matches(b!("{bob}!*"), Ascii));
+        assert!(user.matches(b!("*!?bob@*"), Rfc1459));
+        assert!(!user.matches(b!("*!bob@*"), Rfc1459));
+        assert!(!user.matches(b!("alice!*@*"), Rfc1459));
+    }
+
+    #[test]
+    fn test_ban_mask() {
+        let user = User::parse(b!("bob!~bob@irc.example.com"));
+        assert_eq!(user.ban_mask(BanNick).as_slice(), b!("bob!*@*"));
+        assert_eq!(user.ban_mask(BanHost).as_slice(), b!("*!*@irc.example.com"));
+        assert_eq!(user.ban_mask(BanUserHost).as_slice(), b!("*!*bob@irc.example.com"));
+        assert_eq!(user.ban_mask(BanDomain).as_slice(), b!("*!*@*.example.com"));
+        assert_eq!(user.ban_mask(BanUserDomain).as_slice(), b!("*!*bob@*.example.com"));
+        assert_eq!(user.ban_mask(BanFull).as_slice(), b!("bob!*bob@irc.example.com"));
+        for style in [BanNick, BanHost, BanUserHost, BanDomain, BanUserDomain, BanFull].iter() {
+            assert!(user.matches(user.ban_mask(*style).as_slice(), Rfc1459));
+        }
+
+        let user = User::parse(b!("bob!bob@192.168.0.12"));
+        assert_eq!(user.ban_mask(BanDomain).as_slice(), b!("*!*@192.168.0.*"));
+        let user = User::parse(b!("bob!bob@2001:db8::1"));
+        assert_eq!(user.ban_mask(BanDomain).as_slice(), b!("*!*@2001:db8::1"));
+        let user = User::parse(b!("bob!bob@localhost"));
+        assert_eq!(user.ban_mask(BanUserDomain).as_slice(), b!("*!bob@localhost"));
+        let user = User::parse(b!("bob"));
+        assert_eq!(user.ban_mask(BanUserHost).as_slice(), b!("*!*@*"));
+
+        // a two-label host isn't widened to its top-level domain
+        let user = User::parse(b!("bob!b@example.com"));
+        assert_eq!(user.ban_mask(BanDomain).as_slice(), b!("*!*@example.com"));
+        assert_eq!(user.ban_mask(BanUserDomain).as_slice(), b!("*!b@example.com"));
+        // nor is a cloak
+        let user = User::parse(b!("bob!uid1@gateway/web/irccloud.com/x-abc"));
+        assert_eq!(user.ban_mask(BanDomain).as_slice(), b!("*!*@gateway/web/irccloud.com/x-abc"));
+        assert_eq!(user.ban_mask(BanUserDomain).as_slice(),
+                   b!("*!uid1@gateway/web/irccloud.com/x-abc"));
+    }
+
+    #[test]
+    fn test_prefix_parse() {
+        let prefix = Prefix::parse(b!("irc.example.net"));
+        assert_eq!(prefix, ServerPrefix(Vec::from_slice(b!("irc.example.net"))));
+        assert!(prefix.is_server());
+        assert_eq!(prefix.name(), b!("irc.example.net"));
+        assert_eq!(prefix.nick(), None);
+
+        let prefix = Prefix::parse(b!("bob!fred@joe.com"));
+        assert_eq!(prefix, UserPrefix(User::parse(b!("bob!fred@joe.com"))));
+        assert_eq!(prefix.nick(), Some(b!("bob")));
+        assert_eq!(prefix.raw(), b!("bob!fred@joe.com"));
+
+        // a bare nick has no dot
+        let prefix = Prefix::parse(b!("frobnitz"));
+        assert_eq!(prefix, UserPrefix(User::parse(b!("frobnitz"))));
+        assert_eq!(prefix.server(), None);
+    }
+
+    #[test]
+    fn test_prefix_ref() {
+        let prefix = PrefixRef::parse(b!("irc.example.net"));
+        assert_eq!(prefix, ServerPrefixRef(b!("irc.example.net")));
+        assert!(prefix.is_server());
+        assert_eq!(prefix.name(), b!("irc.example.net"));
+        assert_eq!(prefix.to_prefix(), Prefix::parse(b!("irc.example.net")));
+
+        let prefix = PrefixRef::parse(b!("bob!fred@joe.com"));
+        assert_eq!(prefix, UserPrefixRef(UserRef::parse(b!("bob!fred@joe.com"))));
+        assert_eq!(prefix.nick(), Some(b!("bob")));
+        assert_eq!(prefix.server(), None);
+        let user = prefix.user().unwrap();
+        assert_eq!(user.user(), Some(b!("fred")));
+        assert_eq!(user.host(), Some(b!("joe.com")));
+        assert_eq!(prefix.to_prefix(), Prefix::parse(b!("bob!fred@joe.com")));
+
+        for &raw in [b!("frob@whatever"), b!("foo!baz"), b!("frobnitz"), b!("a!b!c@d@e")].iter() {
+            let (user, owned) = (UserRef::parse(raw), User::parse(raw));
+            assert_eq!(user.nick(), owned.nick());
+            assert_eq!(user.user(), owned.user());
+            assert_eq!(user.host(), owned.host());
+            assert_eq!(user.to_user(), owned);
+        }
+    }
+
+    #[test]
+    fn test_validation() {
+        assert!(User::is_valid_nick(b!("bob")));
+        assert!(User::is_valid_nick(b!("[Bob]`-2")));
+        assert!(!User::is_valid_nick(b!("2bob")));
+        assert!(!User::is_valid_nick(b!("-bob")));
+        assert!(!User::is_valid_nick(b!("bob.net")));
+        assert!(!User::is_valid_nick(b!("")));
+
+        assert!(User::parse(b!("bob!~fred@user/bob")).is_valid());
+        assert!(User::parse(b!("bob")).is_valid());
+        assert!(!User::parse(b!("bob!@host")).is_valid());
+        assert!(!User::parse(b!("bob!fred@")).is_valid());
+        assert!(!User::parse(b!("bob!fred@host name")).is_valid());
+
+        assert!(Prefix::parse(b!("irc.example.net")).is_valid());
+        assert!(Prefix::parse(b!("*.freenode.net")).is_valid());
+        assert!(!Prefix::parse(b!("irc.example.net/x")).is_valid());
+    }
 }